@@ -1,6 +1,6 @@
 use std::io::stdin;
 
-use crossbeam::sync::MsQueue;
+use minnehack_check_in::util::Queue;
 use termion::event::{Event as TermEvent, Key};
 use termion::input::TermRead;
 
@@ -9,7 +9,7 @@ pub enum Event {
     Tick,
 }
 
-pub fn thread(queue: &MsQueue<Event>) -> ! {
+pub fn thread(queue: &Queue<Event>) -> ! {
     loop {
         for event in stdin().events() {
             let ev = match event.unwrap() {
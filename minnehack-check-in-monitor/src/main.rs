@@ -1,4 +1,3 @@
-extern crate crossbeam;
 extern crate minnehack_check_in;
 extern crate termion;
 extern crate tui;
@@ -7,11 +6,10 @@ mod events;
 mod render;
 
 use std::sync::Arc;
-use std::thread::sleep;
-use std::time::Duration;
+use std::thread::spawn;
 
-use crossbeam::sync::MsQueue;
 use minnehack_check_in::{Client, Config, Result, ResultExt};
+use minnehack_check_in::util::Queue;
 use tui::Terminal;
 use tui::backend::RawBackend;
 
@@ -32,15 +30,20 @@ fn run() -> Result<()> {
         Config::load_from("minnehack-check-in.toml").unwrap_or_default(),
     )?);
 
-    let event_queue = Arc::new(MsQueue::new());
-    client.clone().run_with(move |scope, _| {
-        let input_event_queue = event_queue.clone();
-        scope.spawn(move || events::thread(&input_event_queue));
+    let event_queue = Arc::new(Queue::new());
+    let client_events = client.subscribe();
+    // Reading stdin blocks, so the input thread isn't run by the client,
+    // which waits for its threads to return before shutting down.
+    let input_event_queue = event_queue.clone();
+    spawn(move || events::thread(&input_event_queue));
 
-        let timer_event_queue = event_queue.clone();
-        scope.spawn(move || loop {
-            timer_event_queue.push(events::Event::Tick);
-            sleep(Duration::from_secs(1));
+    client.clone().run_with(move |scope, _| {
+        // Redraw whenever the chain or peers change.
+        let client_event_queue = event_queue.clone();
+        scope.spawn(move || {
+            for _ in client_events {
+                client_event_queue.push(events::Event::Tick);
+            }
         });
 
         scope.spawn(move || {
@@ -1,8 +1,6 @@
-use std::process::exit;
-
-use crossbeam::sync::MsQueue;
 use minnehack_check_in::{Client, Result, ResultExt};
-use minnehack_check_in::util::log_err;
+use minnehack_check_in::cards::CheckIn;
+use minnehack_check_in::util::{log_err, Queue};
 use tui::Terminal;
 use tui::backend::RawBackend;
 use tui::layout::{Direction, Group, Rect, Size};
@@ -10,11 +8,13 @@ use tui::widgets::{Block, Borders, Item, List, Widget};
 
 use events::Event;
 
+/// Redraws the terminal on each event, until told to quit, when the client is
+/// shut down.
 pub fn thread(
     client: &Client,
-    event_queue: &MsQueue<Event>,
+    event_queue: &Queue<Event>,
     terminal: &mut Terminal<RawBackend>,
-) -> ! {
+) {
     let mut size = terminal.size().unwrap();
     loop {
         if log_err(render(&client, terminal, &mut size)) {
@@ -24,7 +24,8 @@ pub fn thread(
         match event_queue.pop() {
             Event::Quit => {
                 terminal.show_cursor().unwrap();
-                exit(0);
+                client.shutdown();
+                return;
             }
             Event::Tick => continue,
         }
@@ -50,7 +51,15 @@ fn render(
             let hashes = client.with_chain(|chain| {
                 chain
                     .into_iter()
-                    .map(|block| block.hash.to_string())
+                    .map(|block| {
+                        let hash = block.hash.to_string();
+                        match CheckIn::from_block(block) {
+                            Some(check_in) => {
+                                format!("{} {}", &hash[..16], check_in)
+                            }
+                            None => hash,
+                        }
+                    })
                     .map(Item::Data)
                     .collect::<Vec<_>>()
             });
@@ -0,0 +1,144 @@
+#[macro_use]
+extern crate clap;
+#[macro_use]
+extern crate log;
+extern crate minnehack_check_in;
+extern crate pretty_env_logger;
+extern crate rand;
+extern crate tokio_core;
+
+mod network;
+mod sim;
+#[cfg(test)]
+mod tests;
+
+use std::process::exit;
+
+use clap::ArgMatches;
+
+use sim::{Scenario, Simulation};
+
+fn main() {
+    pretty_env_logger::init().unwrap();
+
+    let matches = clap_app!((crate_name!()) =>
+        (about: crate_description!())
+        (author: crate_authors!())
+        (version: crate_version!())
+        (@arg NODES: -n --nodes +takes_value "The number of nodes")
+        (@arg CHECK_INS: -c --("check-ins") +takes_value
+            "The number of check-ins each node records")
+        (@arg DURATION: -d --duration +takes_value
+            "The seconds over which check-ins are recorded")
+        (@arg SETTLE: --settle +takes_value
+            "The seconds to keep running after the last check-in")
+        (@arg PARTITION: -p --partition +takes_value
+            "The seconds from and to which the network is split in half, as \
+             START-END, or \"none\"")
+        (@arg LOSS: --loss +takes_value
+            "The probability that a message is dropped")
+        (@arg DUPLICATE: --duplicate +takes_value
+            "The probability that a message is delivered twice")
+        (@arg MIN_LATENCY: --("min-latency") +takes_value
+            "The shortest time a message takes to arrive, in milliseconds")
+        (@arg MAX_LATENCY: --("max-latency") +takes_value
+            "The longest time a message takes to arrive, in milliseconds")
+        (@arg SEED: -s --seed +takes_value "The seed of the first run")
+        (@arg RUNS: -r --runs +takes_value
+            "The number of runs, each with the next seed")
+    ).get_matches();
+
+    let scenario = scenario_from_matches(&matches);
+    let seed = value_or_exit(&matches, "SEED", 0);
+    let runs = value_or_exit(&matches, "RUNS", 1);
+
+    let mut failures = 0;
+    for seed in seed..seed + runs {
+        let mut sim = Simulation::new(&scenario, seed);
+        sim.run();
+        let stats = sim.stats();
+        let blocks = sim.clients()[0].with_chain(|chain| chain.len());
+        let violations = sim.check();
+        println!(
+            "Seed {}: {} events, {} messages ({} dropped, {} duplicated), \
+             {} blocks: {}",
+            seed,
+            stats.events,
+            stats.sent,
+            stats.dropped,
+            stats.duplicated,
+            blocks,
+            if violations.is_empty() { "ok" } else { "FAILED" }
+        );
+        for violation in &violations {
+            println!("    {}", violation);
+        }
+        if !violations.is_empty() {
+            failures += 1;
+        }
+    }
+
+    if failures > 0 {
+        println!("{} of {} runs failed", failures, runs);
+        exit(1);
+    }
+}
+
+fn scenario_from_matches(matches: &ArgMatches) -> Scenario {
+    let mut scenario = Scenario::default();
+    let c = &mut scenario.conditions;
+    c.loss = value_or_exit(matches, "LOSS", c.loss);
+    c.duplicate = value_or_exit(matches, "DUPLICATE", c.duplicate);
+    c.min_latency = value_or_exit(matches, "MIN_LATENCY", c.min_latency);
+    c.max_latency = value_or_exit(matches, "MAX_LATENCY", c.max_latency);
+    if c.min_latency > c.max_latency {
+        clap::Error::with_description(
+            "The minimum latency is more than the maximum latency",
+            clap::ErrorKind::ValueValidation,
+        ).exit();
+    }
+
+    let s = &mut scenario;
+    s.nodes = value_or_exit(matches, "NODES", s.nodes);
+    s.check_ins = value_or_exit(matches, "CHECK_INS", s.check_ins);
+    s.duration = value_or_exit(matches, "DURATION", s.duration / 1000) * 1000;
+    s.settle = value_or_exit(matches, "SETTLE", s.settle / 1000) * 1000;
+    if s.nodes == 0 || s.duration == 0 {
+        clap::Error::with_description(
+            "There must be at least one node, and a nonzero duration",
+            clap::ErrorKind::ValueValidation,
+        ).exit();
+    }
+
+    match matches.value_of("PARTITION") {
+        Some("none") => s.partition = None,
+        Some(partition) => {
+            let times = partition
+                .split('-')
+                .map(|t| t.parse::<u64>().map(|t| t * 1000))
+                .collect::<Vec<_>>();
+            s.partition = match times.as_slice() {
+                [Ok(start), Ok(end)] if start <= end => Some((*start, *end)),
+                _ => clap::Error::with_description(
+                    "The partition should be given as START-END",
+                    clap::ErrorKind::ValueValidation,
+                ).exit(),
+            };
+        }
+        None => {}
+    }
+    scenario
+}
+
+/// Gets the value of an argument, or the default if it wasn't given. Exits if
+/// the value is invalid.
+fn value_or_exit<T>(matches: &ArgMatches, name: &str, default: T) -> T
+where
+    T: ::std::str::FromStr,
+{
+    if matches.is_present(name) {
+        value_t!(matches, name, T).unwrap_or_else(|err| err.exit())
+    } else {
+        default
+    }
+}
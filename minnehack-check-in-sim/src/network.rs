@@ -0,0 +1,97 @@
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use minnehack_check_in::Result;
+use minnehack_check_in::p2p::{Incoming, Message, Transport};
+use tokio_core::reactor::Handle;
+
+/// The conditions messages are sent under.
+#[derive(Clone, Debug)]
+pub struct Conditions {
+    /// The probability that a message is dropped.
+    pub loss: f64,
+
+    /// The probability that a message is delivered twice.
+    pub duplicate: f64,
+
+    /// The shortest time a message takes to be delivered, in milliseconds.
+    pub min_latency: u64,
+
+    /// The longest time a message takes to be delivered, in milliseconds.
+    /// Messages sent less than `max_latency - min_latency` apart may be
+    /// reordered.
+    pub max_latency: u64,
+}
+
+impl Default for Conditions {
+    fn default() -> Conditions {
+        Conditions {
+            loss: 0.1,
+            duplicate: 0.05,
+            min_latency: 5,
+            max_latency: 200,
+        }
+    }
+}
+
+/// A message sent by a node, waiting to be picked up by the simulator.
+#[derive(Debug)]
+pub enum Outgoing {
+    /// A discovery ping, sent to every other node.
+    Discovery,
+
+    /// A message sent to a single node.
+    To(SocketAddr, Message),
+}
+
+/// A transport that only queues the messages sent with it. The simulator
+/// delivers them, by calling `Client::handle_message` on the recipient.
+#[derive(Debug, Default)]
+pub struct SimTransport {
+    outbox: Mutex<Vec<Outgoing>>,
+}
+
+impl SimTransport {
+    /// Takes the messages sent since the last call. Messages to the same node
+    /// stay in order, but are otherwise sorted by recipient, so the order
+    /// doesn't depend on the order a `Client` iterates over its peers in.
+    pub fn take_sent(&self) -> Vec<Outgoing> {
+        let mut sent = self.outbox.lock().unwrap().drain(..).collect::<Vec<_>>();
+        sent.sort_by_key(|out| match *out {
+            Outgoing::Discovery => None,
+            Outgoing::To(addr, _) => Some(addr),
+        });
+        sent
+    }
+}
+
+impl Transport for SimTransport {
+    fn incoming<'a>(&'a self, _handle: &Handle) -> Result<Incoming<'a>> {
+        Err("Simulated messages are delivered by the simulator".into())
+    }
+
+    fn recv(&self) -> Result<(SocketAddr, Message)> {
+        Err("Simulated messages are delivered by the simulator".into())
+    }
+
+    fn recv_timeout(
+        &self,
+        _timeout: Duration,
+    ) -> Result<Option<(SocketAddr, Message)>> {
+        self.recv().map(Some)
+    }
+
+    fn send_discovery(&self) -> Result<()> {
+        self.outbox.lock().unwrap().push(Outgoing::Discovery);
+        Ok(())
+    }
+
+    fn send(&self, addr: SocketAddr, msg: &Message) -> Result<()> {
+        self.outbox
+            .lock()
+            .unwrap()
+            .push(Outgoing::To(addr, msg.clone()));
+        Ok(())
+    }
+}
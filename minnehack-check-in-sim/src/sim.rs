@@ -0,0 +1,337 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use minnehack_check_in::Client;
+use minnehack_check_in::blockchain::Chain;
+use minnehack_check_in::cards::{CheckIn, EventKind};
+use minnehack_check_in::keys::Keypair;
+use minnehack_check_in::p2p::Message;
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use network::{Conditions, Outgoing, SimTransport};
+
+/// The Unix timestamp the virtual clock starts at.
+const EPOCH: u64 = 1_517_000_000;
+
+/// A scenario to simulate. All times are in milliseconds of virtual time.
+#[derive(Clone, Debug)]
+pub struct Scenario {
+    /// The number of nodes.
+    pub nodes: usize,
+
+    /// The number of check-ins each node records.
+    pub check_ins: usize,
+
+    /// The time over which check-ins are recorded.
+    pub duration: u64,
+
+    /// The time to keep running after the last check-in is recorded.
+    pub settle: u64,
+
+    /// The start and end of a partition between the first half of the nodes
+    /// and the second half.
+    pub partition: Option<(u64, u64)>,
+
+    /// The conditions messages are sent under.
+    pub conditions: Conditions,
+
+    /// The time nodes wait between sending discovery pings.
+    pub discovery_ping_interval: u64,
+
+    /// The time nodes wait between asking peers for status updates.
+    pub status_check_interval: u64,
+
+    /// The maximum karma value a peer can reach before it is ignored.
+    pub max_karma: usize,
+}
+
+impl Default for Scenario {
+    fn default() -> Scenario {
+        Scenario {
+            nodes: 5,
+            check_ins: 10,
+            duration: 300_000,
+            settle: 1_800_000,
+            partition: Some((60_000, 180_000)),
+            conditions: Conditions::default(),
+            discovery_ping_interval: 60_000,
+            status_check_interval: 30_000,
+            max_karma: 10,
+        }
+    }
+}
+
+/// Counts of what happened during a simulation.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// The number of events processed.
+    pub events: usize,
+
+    /// The number of messages sent.
+    pub sent: usize,
+
+    /// The number of messages dropped, either by chance or by a partition.
+    pub dropped: usize,
+
+    /// The number of messages delivered twice.
+    pub duplicated: usize,
+}
+
+/// Something scheduled to happen at a point in virtual time.
+#[derive(Debug)]
+enum Event {
+    Deliver { from: usize, to: usize, msg: Message },
+    Discovery(usize),
+    Heal,
+    Mine(usize, CheckIn),
+    Partition,
+    StatusCheck(usize),
+}
+
+/// A simulated network of nodes, driven by a virtual clock. Given the same
+/// scenario and seed, a simulation always plays out the same way.
+pub struct Simulation {
+    addrs: Vec<SocketAddr>,
+    clients: Vec<Client<SimTransport>>,
+    conditions: Conditions,
+    end: u64,
+    events: BTreeMap<(u64, u64), Event>,
+    mined: Vec<CheckIn>,
+    next_seq: u64,
+    now: u64,
+    partitioned: bool,
+    rng: XorShiftRng,
+    stats: Stats,
+}
+
+impl Simulation {
+    /// Creates a simulation of the scenario, with randomness derived from the
+    /// seed.
+    pub fn new(scenario: &Scenario, seed: u64) -> Simulation {
+        let addrs = (0..scenario.nodes)
+            .map(|i| {
+                let ip = [10, 0, (i >> 8) as u8, i as u8];
+                SocketAddr::from((ip, 10101))
+            })
+            .collect::<Vec<_>>();
+        let clients = (0..scenario.nodes)
+            .map(|i| {
+                let mut seed = [0; 32];
+                seed[0] = (i >> 8) as u8;
+                seed[1] = i as u8;
+                Client::with_transport(
+                    SimTransport::default(),
+                    Chain::new(),
+                    Keypair::from_seed(&seed),
+                    Duration::from_millis(scenario.discovery_ping_interval),
+                    Duration::from_millis(scenario.status_check_interval),
+                    scenario.max_karma,
+                )
+            })
+            .collect();
+        let rng = XorShiftRng::from_seed([
+            seed as u32,
+            (seed >> 32) as u32,
+            0x9e37_79b9,
+            0x7f4a_7c15,
+        ]);
+
+        let mut sim = Simulation {
+            addrs,
+            clients,
+            conditions: scenario.conditions.clone(),
+            end: scenario.duration + scenario.settle,
+            events: BTreeMap::new(),
+            mined: Vec::new(),
+            next_seq: 0,
+            now: 0,
+            partitioned: false,
+            rng,
+            stats: Stats::default(),
+        };
+
+        for node in 0..scenario.nodes {
+            let t = sim.rng.gen_range(0, scenario.discovery_ping_interval);
+            sim.schedule(t, Event::Discovery(node));
+            let t = sim.rng.gen_range(0, scenario.status_check_interval);
+            sim.schedule(t, Event::StatusCheck(node));
+
+            for i in 0..scenario.check_ins {
+                let t = sim.rng.gen_range(0, scenario.duration);
+                let check_in = CheckIn {
+                    attendee: format!("attendee {}-{}", node, i),
+                    station: format!("station {}", node),
+                    kind: EventKind::Arrival,
+                    timestamp: EPOCH + t / 1000,
+                };
+                sim.schedule(t, Event::Mine(node, check_in));
+            }
+        }
+        if let Some((start, end)) = scenario.partition {
+            sim.schedule(start, Event::Partition);
+            sim.schedule(end, Event::Heal);
+        }
+        sim
+    }
+
+    /// Checks that the nodes ended up in agreement, returning a description
+    /// of each invariant that doesn't hold.
+    pub fn check(&self) -> Vec<String> {
+        let chains = self.clients
+            .iter()
+            .map(|client| client.with_chain(Chain::clone))
+            .collect::<Vec<_>>();
+        let first = &chains[0];
+
+        let mut violations = Vec::new();
+        for (i, chain) in chains.iter().enumerate() {
+            if chain.genesis() != first.genesis() {
+                violations.push(format!("Node {} has another genesis", i));
+            }
+            if !chain.is_valid() {
+                violations.push(format!("Node {}'s chain is invalid", i));
+            }
+            if chain != first {
+                violations.push(format!(
+                    "Node {} has {} blocks with tip {}, but node 0 has {} \
+                     blocks with tip {}",
+                    i,
+                    chain.len(),
+                    chain.tip().hash,
+                    first.len(),
+                    first.tip().hash
+                ));
+            }
+
+            let check_ins = (1..chain.len())
+                .filter_map(|j| CheckIn::from_block(&chain[j]))
+                .collect::<Vec<_>>();
+            let lost = self.mined
+                .iter()
+                .filter(|check_in| !check_ins.contains(check_in))
+                .count();
+            if lost > 0 {
+                violations.push(format!(
+                    "Node {} is missing {} of {} check-ins",
+                    i,
+                    lost,
+                    self.mined.len()
+                ));
+            }
+        }
+        violations
+    }
+
+    /// Returns the nodes' clients.
+    pub fn clients(&self) -> &[Client<SimTransport>] {
+        &self.clients
+    }
+
+    /// Runs the simulation to its end.
+    pub fn run(&mut self) {
+        loop {
+            let key = match self.events.keys().next() {
+                Some(&key) if key.0 <= self.end => key,
+                _ => break,
+            };
+            let event = self.events.remove(&key).unwrap();
+            self.now = key.0;
+            self.stats.events += 1;
+            self.handle(event);
+        }
+    }
+
+    /// Returns counts of what has happened so far.
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Sends the messages the node has queued.
+    fn flush(&mut self, from: usize) {
+        self.clients[from].send_pending();
+        for out in self.clients[from].transport().take_sent() {
+            match out {
+                Outgoing::Discovery => for to in 0..self.clients.len() {
+                    if to != from {
+                        self.transmit(from, to, Message::Ping);
+                    }
+                },
+                Outgoing::To(addr, msg) => {
+                    match self.addrs.iter().position(|&a| a == addr) {
+                        Some(to) => self.transmit(from, to, msg),
+                        None => self.stats.dropped += 1,
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::Deliver { from, to, msg } => {
+                self.clients[to].handle_message(self.addrs[from], msg);
+                self.flush(to);
+            }
+            Event::Discovery(node) => {
+                self.clients[node].send_discovery();
+                self.flush(node);
+                let interval = self.clients[node].discovery_ping_interval;
+                self.schedule(self.now + millis(interval), event);
+            }
+            Event::Heal => {
+                debug!("Healing partition at {}ms", self.now);
+                self.partitioned = false;
+            }
+            Event::Mine(node, check_in) => {
+                let data = check_in.to_data().expect("Check-in too large");
+                self.clients[node].mine_at(check_in.timestamp, data);
+                self.mined.push(check_in);
+                self.flush(node);
+            }
+            Event::Partition => {
+                debug!("Partitioning network at {}ms", self.now);
+                self.partitioned = true;
+            }
+            Event::StatusCheck(node) => {
+                self.clients[node].request_status();
+                self.flush(node);
+                let interval = self.clients[node].status_check_interval;
+                self.schedule(self.now + millis(interval), event);
+            }
+        }
+    }
+
+    fn schedule(&mut self, time: u64, event: Event) {
+        self.events.insert((time, self.next_seq), event);
+        self.next_seq += 1;
+    }
+
+    /// Sends a message subject to the network conditions.
+    fn transmit(&mut self, from: usize, to: usize, msg: Message) {
+        self.stats.sent += 1;
+        let half = self.clients.len() / 2;
+        let across = (from < half) != (to < half);
+        if (self.partitioned && across)
+            || self.rng.gen::<f64>() < self.conditions.loss
+        {
+            self.stats.dropped += 1;
+            return;
+        }
+
+        let (min, max) =
+            (self.conditions.min_latency, self.conditions.max_latency);
+        if self.rng.gen::<f64>() < self.conditions.duplicate {
+            self.stats.duplicated += 1;
+            let t = self.now + self.rng.gen_range(min, max + 1);
+            let msg = msg.clone();
+            self.schedule(t, Event::Deliver { from, to, msg });
+        }
+        let t = self.now + self.rng.gen_range(min, max + 1);
+        self.schedule(t, Event::Deliver { from, to, msg });
+    }
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_millis())
+}
@@ -0,0 +1,54 @@
+use network::Conditions;
+use sim::{Scenario, Simulation};
+
+/// A scenario small enough to run quickly in debug builds.
+fn small() -> Scenario {
+    Scenario {
+        nodes: 4,
+        check_ins: 5,
+        ..Scenario::default()
+    }
+}
+
+fn run(scenario: &Scenario, seed: u64) -> Simulation {
+    let mut sim = Simulation::new(scenario, seed);
+    sim.run();
+    assert_eq!(sim.check(), Vec::<String>::new(), "with seed {}", seed);
+    sim
+}
+
+#[test]
+fn converges() {
+    for seed in 0..2 {
+        run(&small(), seed);
+    }
+}
+
+#[test]
+fn converges_under_harsh_conditions() {
+    let scenario = Scenario {
+        nodes: 6,
+        check_ins: 3,
+        settle: 3_600_000,
+        partition: Some((30_000, 280_000)),
+        conditions: Conditions {
+            loss: 0.3,
+            duplicate: 0.2,
+            min_latency: 5,
+            max_latency: 2000,
+        },
+        ..Scenario::default()
+    };
+    run(&scenario, 0);
+}
+
+#[test]
+fn deterministic() {
+    let a = run(&small(), 42);
+    let b = run(&small(), 42);
+    assert_eq!(a.stats(), b.stats());
+    assert_eq!(
+        a.clients()[0].with_chain(|chain| chain.tip().hash),
+        b.clients()[0].with_chain(|chain| chain.tip().hash)
+    );
+}
@@ -0,0 +1,365 @@
+//! A live feed of blocks over a WebSocket, served on an address of its own.
+//!
+//! A client connects to `/feed`, and can pass `?since=<index>` to be sent the
+//! blocks from that index onward, so one that reconnects catches up on those
+//! it missed. Otherwise, only blocks appended after it connects are sent.
+//!
+//! Each message is a JSON object. `{"type": "block", "block": ...}` carries a
+//! block, in the same form as `GET /blocks/<index>`, and blocks are sent in
+//! order of index. `{"type": "fork", "index": ...}` means the chain was
+//! replaced from that index onward, so blocks already received from there on
+//! should be discarded; the new blocks follow.
+//!
+//! Pings and close frames from the client are answered. Anything else it
+//! sends is ignored.
+
+use std::cmp::min;
+use std::io::{
+    BufRead, BufReader, Error as IoError, ErrorKind as IoErrorKind, Read,
+    Result as IoResult, Write,
+};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::TryRecvError;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use base64;
+use byteorder::{BigEndian, ReadBytesExt};
+use crossbeam::scope;
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use serde_json::Value;
+
+use api::{block_json, param, split_url};
+use blockchain::Chain;
+use errors::{ErrorKind, Result, ResultExt};
+use events::Event;
+use p2p::Transport;
+use {Client, POLL_INTERVAL};
+
+/// How long to wait without sending anything before sending a ping, so
+/// closed connections are noticed.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a client has to send its handshake. This is short, since
+/// shutting down waits for it.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The GUID the WebSocket handshake is computed with, from RFC 6455.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The longest handshake read from the client.
+const MAX_HANDSHAKE_LEN: u64 = 8192;
+
+/// The longest payload read from the client. Control frames, which are all
+/// the feed expects, are at most 125 bytes.
+const MAX_PAYLOAD_LEN: u64 = 4096;
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// The position of a feed in the chain.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Feed {
+    /// The index of the next block to send.
+    next: u64,
+
+    /// The index the chain was replaced from, if it has been since the last
+    /// blocks were sent.
+    fork: Option<u64>,
+}
+
+impl Feed {
+    /// Creates a feed that next sends the block at the given index.
+    pub fn new(next: u64) -> Feed {
+        Feed { next, fork: None }
+    }
+
+    /// Notes an event, returning whether there may be messages to send.
+    pub fn handle(&mut self, event: &Event) -> bool {
+        match *event {
+            Event::BlockAppended(_) => true,
+            Event::ForkResolved { fork, .. } => {
+                if fork < self.next {
+                    self.fork = Some(min(fork, self.fork.unwrap_or(fork)));
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the messages for the blocks that haven't been sent yet.
+    pub fn messages<T: Transport>(
+        &mut self,
+        client: &Client<T>,
+    ) -> Vec<Value> {
+        let mut msgs = Vec::new();
+        if let Some(fork) = self.fork.take() {
+            msgs.push(json!({ "type": "fork", "index": fork }));
+            self.next = fork;
+        }
+        client.with_chain(|chain| {
+            for i in self.next..chain.len() {
+                let block = block_json(&chain[i]);
+                msgs.push(json!({ "type": "block", "block": block }));
+            }
+            self.next = chain.len();
+        });
+        msgs
+    }
+}
+
+/// Serves the feed for the client on the given address, until the client is
+/// shut down.
+pub fn serve<T: Transport>(client: &Client<T>, addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        })
+        .chain_err(|| ErrorKind::CouldNotStartFeed(addr))?;
+    info!("Serving the feed on {}", addr);
+
+    scope(|scope| {
+        while !client.is_shutting_down() {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    scope.spawn(move || {
+                        if let Err(err) = connection(client, &stream) {
+                            debug!("Closing WebSocket: {}", err);
+                        }
+                    });
+                }
+                Err(ref err) if err.kind() == IoErrorKind::WouldBlock => {
+                    sleep(POLL_INTERVAL)
+                }
+                Err(err) => warn!("Could not accept a WebSocket: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Upgrades a connection to a WebSocket, and sends the feed over it until
+/// it's closed.
+fn connection<T: Transport>(
+    client: &Client<T>,
+    stream: &TcpStream,
+) -> IoResult<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let mut writer = stream;
+    let (key, since) = match read_handshake(&mut reader)? {
+        Some(handshake) => handshake,
+        None => {
+            let response = "HTTP/1.1 400 Bad Request\r\n\
+                            Content-Length: 0\r\n\
+                            Connection: close\r\n\r\n";
+            return writer.write_all(response.as_bytes());
+        }
+    };
+
+    // Subscribing before looking at the chain ensures no blocks are missed.
+    let events = client.subscribe();
+    let mut feed =
+        Feed::new(since.unwrap_or_else(|| client.with_chain(Chain::len)));
+
+    write!(
+        writer,
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&key)
+    )?;
+    send(client, &mut feed, &mut writer)?;
+
+    // Reads time out, so the client's frames are answered between sends.
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    let mut received = Vec::new();
+    let mut buf = [0; 1024];
+    let mut last_sent = Instant::now();
+    while !client.is_shutting_down() {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => received.extend_from_slice(&buf[..n]),
+            Err(ref err)
+                if err.kind() == IoErrorKind::WouldBlock
+                    || err.kind() == IoErrorKind::TimedOut => {}
+            Err(err) => return Err(err),
+        }
+        if !answer(&mut received, &mut writer)? {
+            return Ok(());
+        }
+
+        let mut pending = false;
+        loop {
+            match events.try_recv() {
+                Ok(event) => pending |= feed.handle(&event),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+        if pending {
+            last_sent = Instant::now();
+            send(client, &mut feed, &mut writer)?;
+        } else if last_sent.elapsed() >= PING_INTERVAL {
+            last_sent = Instant::now();
+            write_frame(&mut writer, OPCODE_PING, &[])?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads a WebSocket handshake for `/feed`, returning its key and the index
+/// it asks for blocks since, if any. Returns `None` if it isn't a valid one.
+pub fn read_handshake<R: BufRead>(
+    r: &mut R,
+) -> IoResult<Option<(String, Option<u64>)>> {
+    let mut lines = Vec::new();
+    let mut r = r.take(MAX_HANDSHAKE_LEN);
+    loop {
+        let mut line = String::new();
+        if r.read_line(&mut line)? == 0 {
+            return Err(IoError::new(
+                IoErrorKind::UnexpectedEof,
+                "incomplete handshake",
+            ));
+        }
+        let line = line.trim_end().to_string();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+
+    let target = match lines.first().map(|l| l.split(' ').collect::<Vec<_>>())
+    {
+        Some(ref parts) if parts.len() == 3 && parts[0] == "GET" => {
+            parts[1].to_string()
+        }
+        _ => return Ok(None),
+    };
+    let (path, query) = split_url(&target);
+    let mut upgrade = false;
+    let mut key = None;
+    for line in &lines[1..] {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let value = parts.next().unwrap_or("").trim();
+        if name.eq_ignore_ascii_case("Upgrade") {
+            upgrade = value.eq_ignore_ascii_case("websocket");
+        } else if name.eq_ignore_ascii_case("Sec-WebSocket-Key") {
+            key = Some(value.to_string());
+        }
+    }
+    let since = param(query, "since").map(str::parse::<u64>);
+    Ok(match (path, upgrade, key, since) {
+        ("/feed", true, Some(key), None) => Some((key, None)),
+        ("/feed", true, Some(key), Some(Ok(since))) => Some((key, Some(since))),
+        _ => None,
+    })
+}
+
+/// Answers the complete frames in the data received from the client, and
+/// removes them from it. Returns whether the connection is still open.
+pub fn answer<W: Write>(received: &mut Vec<u8>, w: &mut W) -> IoResult<bool> {
+    loop {
+        let (frame, len) = {
+            let mut rest = &received[..];
+            match read_frame(&mut rest) {
+                Ok(frame) => (frame, received.len() - rest.len()),
+                // The rest of the frame hasn't been received yet.
+                Err(ref err) if err.kind() == IoErrorKind::UnexpectedEof => {
+                    return Ok(true)
+                }
+                Err(err) => return Err(err),
+            }
+        };
+        received.drain(..len);
+        match frame {
+            (OPCODE_PING, payload) => write_frame(w, OPCODE_PONG, &payload)?,
+            (OPCODE_CLOSE, payload) => {
+                // The reply echoes the status code, if there is one.
+                let code = &payload[..min(payload.len(), 2)];
+                write_frame(w, OPCODE_CLOSE, code)?;
+                return Ok(false);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Sends the feed's pending messages over the WebSocket.
+fn send<T: Transport, W: Write>(
+    client: &Client<T>,
+    feed: &mut Feed,
+    stream: &mut W,
+) -> IoResult<()> {
+    for msg in feed.messages(client) {
+        write_frame(stream, OPCODE_TEXT, msg.to_string().as_bytes())?;
+    }
+    stream.flush()
+}
+
+/// Computes the `Sec-WebSocket-Accept` header for a `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.input_str(key);
+    sha1.input_str(WEBSOCKET_GUID);
+    let mut digest = [0; 20];
+    sha1.result(&mut digest);
+    base64::encode(&digest)
+}
+
+/// Reads a masked WebSocket frame, as clients send, returning its opcode and
+/// unmasked payload. Fragmented messages aren't reassembled.
+pub fn read_frame<R: Read>(r: &mut R) -> IoResult<(u8, Vec<u8>)> {
+    let opcode = r.read_u8()? & 0x0f;
+    let len = r.read_u8()?;
+    if len & 0x80 == 0 {
+        return Err(IoError::new(IoErrorKind::InvalidData, "unmasked frame"));
+    }
+    let len = match len & 0x7f {
+        126 => u64::from(r.read_u16::<BigEndian>()?),
+        127 => r.read_u64::<BigEndian>()?,
+        len => u64::from(len),
+    };
+    if len > MAX_PAYLOAD_LEN {
+        return Err(IoError::new(IoErrorKind::InvalidData, "frame too long"));
+    }
+
+    let mut mask = [0; 4];
+    r.read_exact(&mut mask)?;
+    let mut payload = vec![0; len as usize];
+    r.read_exact(&mut payload)?;
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+    Ok((opcode, payload))
+}
+
+/// Writes an unmasked WebSocket frame, as servers send.
+pub fn write_frame<W: Write>(
+    w: &mut W,
+    opcode: u8,
+    payload: &[u8],
+) -> IoResult<()> {
+    let len = payload.len();
+    let mut frame = vec![0x80 | opcode];
+    if len < 126 {
+        frame.push(len as u8);
+    } else if len <= 0xffff {
+        frame.push(126);
+        frame.extend_from_slice(&[(len >> 8) as u8, len as u8]);
+    } else {
+        frame.push(127);
+        frame.extend((0..8).rev().map(|i| (len as u64 >> (i * 8)) as u8));
+    }
+    frame.extend_from_slice(payload);
+    w.write_all(&frame)
+}
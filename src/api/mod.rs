@@ -0,0 +1,424 @@
+//! An HTTP API for inspecting a station, which serves JSON.
+//!
+//! The endpoints are:
+//!
+//! - `GET /chain`: the length, genesis and tip of the chain.
+//! - `GET /blocks/<index or hash>`: a block, with its check-in, if any.
+//! - `GET /check-ins?since=<index>`: the check-ins in blocks from the given
+//!   index (by default, 1) onward.
+//! - `GET /peers`: the known peers, with their state and karma.
+//! - `GET /health`: whether the station is working, and what it knows of.
+//! - `GET /attendees?q=<query>`: the registered attendees whose U Card ID is
+//!   the query, or whose name or email contains it.
+//! - `GET /attendees/<U Card ID>`: a registered attendee, with the check-ins
+//!   made with their card.
+//! - `POST /check-ins`: records a check-in at this station. The body is a
+//!   JSON object with an `attendee` and optionally a `kind`, which defaults
+//!   to the station's. Check-ins that break a rule for their kind, like
+//!   claiming a meal twice, are refused with a 409.
+//!
+//! Requests to `/attendees` and `POST` requests must have an
+//! `Authorization: Bearer <token>` header with the station's API token.
+
+pub mod feed;
+
+#[cfg(test)]
+mod tests;
+
+use std::io::Read;
+use std::net::SocketAddr;
+use std::str::from_utf8;
+
+use crypto::util::fixed_time_eq;
+use serde_json::{from_slice as json_from_slice, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use blockchain::{Block, Hash};
+use cards::{CheckIn, EventKind};
+use errors::{Error, ErrorKind, Result, ResultExt};
+use p2p::{PeerState, Transport, PROTOCOL_VERSION};
+use {Client, POLL_INTERVAL};
+
+/// The largest request body that is read.
+const MAX_BODY_LEN: u64 = 4096;
+
+/// A check-in submitted over the API.
+#[derive(Debug, Deserialize)]
+struct CheckInRequest {
+    attendee: String,
+    kind: Option<EventKind>,
+}
+
+/// Serves the API for the client on the given address, until the client is
+/// shut down.
+pub fn serve<T: Transport>(client: &Client<T>, addr: SocketAddr) -> Result<()> {
+    let server = Server::http(addr)
+        .map_err(|err| Error::from(err.to_string()))
+        .chain_err(|| ErrorKind::CouldNotStartApi(addr))?;
+    info!("Serving the API on {}", addr);
+
+    while !client.is_shutting_down() {
+        match server.recv_timeout(POLL_INTERVAL) {
+            Ok(Some(request)) => respond(client, request),
+            Ok(None) => {}
+            Err(err) => warn!("Could not receive an API request: {}", err),
+        }
+    }
+    Ok(())
+}
+
+/// Responds to a request.
+fn respond<T: Transport>(client: &Client<T>, mut request: Request) {
+    let authorization = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Authorization"))
+        .map(|h| h.value.as_str().to_string());
+    let (status, body) = match *request.method() {
+        Method::Get => get(client, request.url(), authorization.as_deref()),
+        Method::Post => {
+            let mut body = Vec::new();
+            match request
+                .as_reader()
+                .take(MAX_BODY_LEN)
+                .read_to_end(&mut body)
+            {
+                Ok(_) => post(
+                    client,
+                    request.url(),
+                    authorization.as_deref(),
+                    &body,
+                ),
+                Err(_) => error(400, "Could not read the request body"),
+            }
+        }
+        _ => error(405, "Only GET and POST requests are supported"),
+    };
+    let response = Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+        .with_header(header("Access-Control-Allow-Origin", "*"));
+    if let Err(err) = request.respond(response) {
+        warn!("Could not respond to an API request: {}", err);
+    }
+}
+
+/// Responds to a `GET` request for the given URL, with the given
+/// `Authorization` header, returning the status code and body.
+pub fn get<T: Transport>(
+    client: &Client<T>,
+    url: &str,
+    authorization: Option<&str>,
+) -> (u16, Value) {
+    let (path, query) = split_url(url);
+    let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+    if segments[0] == "attendees" {
+        if let Some(response) = unauthorized(client, authorization) {
+            return response;
+        }
+    }
+    match segments.as_slice() {
+        ["attendees"] => attendees(client, query),
+        ["attendees", id] => attendee(client, id),
+        ["chain"] => chain(client),
+        ["blocks", id] => block(client, id),
+        ["check-ins"] => check_ins(client, query),
+        ["peers"] => peers(client),
+        ["health"] => health(client),
+        _ => error(404, "No such endpoint"),
+    }
+}
+
+/// Responds to a `POST` request for the given URL, with the given
+/// `Authorization` header and body, returning the status code and body.
+pub fn post<T: Transport>(
+    client: &Client<T>,
+    url: &str,
+    authorization: Option<&str>,
+    body: &[u8],
+) -> (u16, Value) {
+    if url.trim_matches('/') != "check-ins" {
+        return error(404, "No such endpoint");
+    }
+    if let Some(response) = unauthorized(client, authorization) {
+        return response;
+    }
+
+    let request: CheckInRequest = match json_from_slice(body) {
+        Ok(request) => request,
+        Err(err) => return error(400, &format!("Invalid check-in: {}", err)),
+    };
+    let attendee = request.attendee.trim();
+    if attendee.is_empty() {
+        return error(400, "Expected an attendee");
+    }
+    let kind = request.kind.unwrap_or_else(|| client.event_kind.clone());
+
+    match client.check_in(attendee, kind) {
+        Ok(block) => {
+            let json = json!({
+                "index": block.index,
+                "hash": block.hash.to_string(),
+                "check_in": CheckIn::from_block(&block),
+            });
+            (201, json)
+        }
+        Err(err) => {
+            let status = match *err.kind() {
+                ErrorKind::CheckInRefused(_) => 409,
+                ErrorKind::CheckInTooLarge => 400,
+                ErrorKind::NotAuthorized => 403,
+                _ => 400,
+            };
+            error(status, &err.to_string())
+        }
+    }
+}
+
+/// Returns the response to send if the `Authorization` header doesn't have
+/// the station's API token.
+fn unauthorized<T: Transport>(
+    client: &Client<T>,
+    authorization: Option<&str>,
+) -> Option<(u16, Value)> {
+    let token = match client.api_token {
+        Some(ref token) => token,
+        None => return Some(error(403, "This station has no API token")),
+    };
+    let given = authorization
+        .and_then(|a| a.trim().split_once(' '))
+        .filter(|&(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+        .map(|(_, token)| token.trim())
+        .unwrap_or("");
+    if fixed_time_eq(given.as_bytes(), token.as_bytes()) {
+        None
+    } else {
+        Some(error(401, "Expected a valid API token"))
+    }
+}
+
+fn attendee<T: Transport>(client: &Client<T>, id: &str) -> (u16, Value) {
+    let registry = match client.registry {
+        Some(ref registry) => registry,
+        None => return error(404, "This station has no attendee registry"),
+    };
+    let attendee = match registry.get(id) {
+        Some(attendee) => attendee,
+        None => return error(404, "No such attendee"),
+    };
+    let check_ins = client.with_chain(|chain| {
+        chain
+            .into_iter()
+            .filter_map(|block| {
+                CheckIn::from_block(block)
+                    .filter(|c| registry.lookup_check_in(c) == Some(attendee))
+                    .map(|check_in| {
+                        json!({
+                            "index": block.index,
+                            "hash": block.hash.to_string(),
+                            "check_in": check_in,
+                        })
+                    })
+            })
+            .collect::<Vec<_>>()
+    });
+    let mut json = json!(attendee);
+    json["check_ins"] = Value::Array(check_ins);
+    (200, json)
+}
+
+fn attendees<T: Transport>(client: &Client<T>, query: &str) -> (u16, Value) {
+    match client.registry {
+        Some(ref registry) => {
+            let q = decode(param(query, "q").unwrap_or(""));
+            (200, json!(registry.search(&q)))
+        }
+        None => error(404, "This station has no attendee registry"),
+    }
+}
+
+fn block<T: Transport>(client: &Client<T>, id: &str) -> (u16, Value) {
+    client.with_chain(|chain| {
+        // Hashes are checked first, since some of them are valid indices.
+        let block = if let Ok(hash) = id.parse::<Hash>() {
+            chain.into_iter().find(|block| block.hash == hash)
+        } else if let Ok(index) = id.parse::<u64>() {
+            if index < chain.len() {
+                Some(&chain[index])
+            } else {
+                None
+            }
+        } else {
+            return error(400, "Expected a block index or hash");
+        };
+        match block {
+            Some(block) => (200, block_json(block)),
+            None => error(404, "No such block"),
+        }
+    })
+}
+
+fn chain<T: Transport>(client: &Client<T>) -> (u16, Value) {
+    client.with_chain(|chain| {
+        let json = json!({
+            "length": chain.len(),
+            "genesis_hash": chain.genesis().hash.to_string(),
+            "tip": block_json(chain.tip()),
+        });
+        (200, json)
+    })
+}
+
+fn check_ins<T: Transport>(client: &Client<T>, query: &str) -> (u16, Value) {
+    let since = match param(query, "since").map(str::parse::<u64>) {
+        Some(Ok(since)) => since,
+        Some(Err(_)) => return error(400, "Expected an index for since"),
+        None => 1,
+    };
+    client.with_chain(|chain| {
+        let check_ins = chain
+            .into_iter()
+            .skip(since as usize)
+            .filter_map(|block| {
+                CheckIn::from_block(block).map(|check_in| {
+                    json!({
+                        "index": block.index,
+                        "hash": block.hash.to_string(),
+                        "check_in": check_in,
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        (200, Value::Array(check_ins))
+    })
+}
+
+fn health<T: Transport>(client: &Client<T>) -> (u16, Value) {
+    let (length, tip_hash, authorized) = client.with_chain(|chain| {
+        let authorized = chain.is_authorized(&client.public_key());
+        (chain.len(), chain.tip().hash, authorized)
+    });
+    let (peers, confirmed) = client.with_peers(|peers| {
+        let confirmed = peers.values().filter(|p| p.same_blockchain()).count();
+        (peers.len(), confirmed)
+    });
+    let json = json!({
+        "status": "ok",
+        "public_key": client.public_key().to_string(),
+        "protocol_version": PROTOCOL_VERSION,
+        "authorized": authorized,
+        "chain_length": length,
+        "tip_hash": tip_hash.to_string(),
+        "peers": peers,
+        "confirmed_peers": confirmed,
+    });
+    (200, json)
+}
+
+fn peers<T: Transport>(client: &Client<T>) -> (u16, Value) {
+    client.with_peers(|peers| {
+        let mut peers = peers.values().collect::<Vec<_>>();
+        peers.sort_by_key(|peer| peer.addr);
+        let peers = peers
+            .into_iter()
+            .map(|peer| {
+                let mut json = json!({
+                    "addr": peer.addr.to_string(),
+                    "karma": peer.karma,
+                    "hello": peer.hello.map(|hello| json!({
+                        "version": hello.version,
+                        "node_id": hello.node_id.to_string(),
+                        "capabilities": hello.capabilities.0,
+                    })),
+                });
+                let state = match peer.state {
+                    PeerState::Speculative => json!({"state": "speculative"}),
+                    PeerState::Existent => json!({"state": "existent"}),
+                    PeerState::Confirmed(index, hash) => json!({
+                        "state": "confirmed",
+                        "tip_index": index,
+                        "tip_hash": hash.to_string(),
+                    }),
+                    PeerState::Ignore(reason) => json!({
+                        "state": "ignored",
+                        "reason": reason.to_string(),
+                    }),
+                };
+                json["state"] = state;
+                json
+            })
+            .collect();
+        (200, Value::Array(peers))
+    })
+}
+
+fn block_json(block: &Block) -> Value {
+    let data = block
+        .data
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    json!({
+        "index": block.index,
+        "hash": block.hash.to_string(),
+        "prev_hash": block.prev_hash.to_string(),
+        "timestamp": block.timestamp,
+        "signer": block.signer.to_string(),
+        "signature": block.signature.to_string(),
+        "data": data,
+        "check_in": CheckIn::from_block(block),
+    })
+}
+
+fn error(status: u16, msg: &str) -> (u16, Value) {
+    (status, json!({ "error": msg }))
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+/// Splits a URL into its path and query string.
+fn split_url(url: &str) -> (&str, &str) {
+    match url.find('?') {
+        Some(i) => (&url[..i], &url[i + 1..]),
+        None => (url, ""),
+    }
+}
+
+/// Decodes a percent-encoded query string value, in which spaces may be
+/// encoded as `+`. Invalid escapes are left as they are.
+fn decode(value: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut rest = value.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let escaped = tail
+            .get(..2)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (b, escaped) {
+            (b'%', Some(byte)) => {
+                bytes.push(byte);
+                rest = &tail[2..];
+                continue;
+            }
+            (b'+', _) => bytes.push(b' '),
+            (b, _) => bytes.push(b),
+        }
+        rest = tail;
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Returns the value of a parameter in a query string.
+fn param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query.split('&').find_map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+        if parts.next() == Some(name) {
+            Some(parts.next().unwrap_or(""))
+        } else {
+            None
+        }
+    })
+}
@@ -0,0 +1,289 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use api::feed::{
+    accept_key, answer, read_frame, read_handshake, write_frame, Feed,
+};
+use api::{get, post};
+use blockchain::Chain;
+use cards::{CheckIn, EventKind};
+use events::Event;
+use keys::Keypair;
+use p2p::{MemoryNetwork, MemoryTransport};
+use registry::Registry;
+use util::str_to_arrayvec;
+use Client;
+
+fn client() -> Client<MemoryTransport> {
+    let network = MemoryNetwork::new();
+    let client = Client::with_transport(
+        network.transport(SocketAddr::from(([10, 0, 0, 0], 10101))),
+        Chain::new(),
+        Keypair::from_seed(&[0; 32]),
+        Duration::from_secs(60),
+        Duration::from_secs(30),
+        10,
+    );
+    let check_in = CheckIn {
+        attendee: "attendee".to_string(),
+        station: "station".to_string(),
+        kind: EventKind::Arrival,
+        timestamp: 1_517_000_000,
+    };
+    client.mine_at(check_in.timestamp, check_in.to_data().unwrap());
+    client.add_peer(SocketAddr::from(([10, 0, 0, 1], 10101)));
+    client
+}
+
+#[test]
+fn chain() {
+    let client = client();
+    let (status, json) = get(&client, "/chain", None);
+    assert_eq!(status, 200);
+    assert_eq!(json["length"], 2);
+    assert_eq!(json["tip"]["index"], 1);
+    assert_eq!(json["tip"]["check_in"]["attendee"], "attendee");
+    assert_eq!(
+        json["genesis_hash"],
+        Chain::new().genesis().hash.to_string()
+    );
+}
+
+#[test]
+fn blocks() {
+    let client = client();
+    let hash = client.with_chain(|chain| chain[1].hash);
+
+    let (status, by_index) = get(&client, "/blocks/1", None);
+    assert_eq!(status, 200);
+    assert_eq!(by_index["hash"], hash.to_string());
+    assert_eq!(by_index["signer"], client.public_key().to_string());
+
+    let (status, by_hash) = get(&client, &format!("/blocks/{}", hash), None);
+    assert_eq!(status, 200);
+    assert_eq!(by_hash, by_index);
+
+    let (status, genesis) = get(&client, "/blocks/0", None);
+    assert_eq!(status, 200);
+    assert!(genesis["check_in"].is_null());
+
+    assert_eq!(get(&client, "/blocks/2", None).0, 404);
+    let unknown = format!("/blocks/{}", "0".repeat(64));
+    assert_eq!(get(&client, &unknown, None).0, 404);
+    assert_eq!(get(&client, "/blocks/tip", None).0, 400);
+}
+
+#[test]
+fn check_ins() {
+    let client = client();
+    let (status, json) = get(&client, "/check-ins", None);
+    assert_eq!(status, 200);
+    assert_eq!(json.as_array().unwrap().len(), 1);
+    assert_eq!(json[0]["index"], 1);
+    assert_eq!(json[0]["check_in"]["kind"], "arrival");
+
+    assert_eq!(get(&client, "/check-ins?since=2", None).1, json!([]));
+    assert_eq!(get(&client, "/check-ins?since=two", None).0, 400);
+}
+
+#[test]
+fn peers_and_health() {
+    let client = client();
+    let (status, peers) = get(&client, "/peers", None);
+    assert_eq!(status, 200);
+    assert_eq!(peers[0]["addr"], "10.0.0.1:10101");
+    assert_eq!(peers[0]["state"]["state"], "speculative");
+    assert!(peers[0]["hello"].is_null());
+
+    let (status, health) = get(&client, "/health", None);
+    assert_eq!(status, 200);
+    assert_eq!(health["status"], "ok");
+    assert_eq!(health["chain_length"], 2);
+    assert_eq!(health["peers"], 1);
+    assert_eq!(health["confirmed_peers"], 0);
+    assert_eq!(health["authorized"], true);
+
+    assert_eq!(get(&client, "/nope", None).0, 404);
+}
+
+#[test]
+fn submit_check_in() {
+    let mut client = client();
+    let body = br#"{"attendee": "walk-in", "kind": "Departure"}"#;
+    assert_eq!(post(&client, "/check-ins", None, body).0, 403);
+
+    client.api_token = Some("secret".to_string());
+    assert_eq!(post(&client, "/check-ins", None, body).0, 401);
+    let wrong = Some("Bearer guess");
+    assert_eq!(post(&client, "/check-ins", wrong, body).0, 401);
+
+    let auth = Some("Bearer secret");
+    let (status, json) = post(&client, "/check-ins", auth, body);
+    assert_eq!(status, 201);
+    assert_eq!(json["index"], 2);
+    assert_eq!(json["check_in"]["attendee"], "walk-in");
+    assert_eq!(json["check_in"]["kind"], "departure");
+    let (_, block) = get(&client, "/blocks/2", None);
+    assert_eq!(block["hash"], json["hash"]);
+    assert_eq!(block["check_in"], json["check_in"]);
+
+    // Swiping again straight away is refused.
+    let (status, json) = post(&client, "/check-ins", auth, body);
+    assert_eq!(status, 409);
+    assert!(json["error"].as_str().unwrap().contains("already checked in"));
+
+    let invalid: &[&[u8]] = &[
+        br#"{"attendee": "  "}"#,
+        br#"{"kind": "Arrival"}"#,
+        b"not json",
+    ];
+    for body in invalid {
+        assert_eq!(post(&client, "/check-ins", auth, body).0, 400);
+    }
+    for len in &[250, 1000] {
+        let body = json!({ "attendee": "x".repeat(*len) }).to_string();
+        let (status, json) = post(&client, "/check-ins", auth, body.as_bytes());
+        assert_eq!(status, 400);
+        assert!(json["error"].as_str().unwrap().contains("too large"));
+    }
+    assert_eq!(client.with_chain(|chain| chain.len()), 3);
+    assert_eq!(post(&client, "/chain", auth, body).0, 404);
+
+    client.event_kind = EventKind::Meal(1);
+    let body = br#"{"attendee": "walk-in"}"#;
+    let (status, json) = post(&client, "/check-ins", auth, body);
+    assert_eq!(status, 201);
+    assert_eq!(json["check_in"]["kind"], "meal:1");
+    assert_eq!(post(&client, "/check-ins", auth, body).0, 409);
+    let body = br#"{"attendee": "walk-in", "kind": "meal:2"}"#;
+    assert_eq!(post(&client, "/check-ins", auth, body).0, 201);
+}
+
+#[test]
+fn attendees() {
+    let mut client = client();
+    let csv = "name,ucard id,email\nAda Lovelace,attendee,ada@example.com\n";
+    let auth = Some("Bearer secret");
+    assert_eq!(get(&client, "/attendees", auth).0, 403);
+    client.api_token = Some("secret".to_string());
+    assert_eq!(get(&client, "/attendees", None).0, 401);
+    assert_eq!(get(&client, "/attendees", auth).0, 404);
+
+    client.registry = Some(Registry::from_csv(csv).unwrap());
+    let (status, json) = get(&client, "/attendees?q=ada+LOVELACE", auth);
+    assert_eq!(status, 200);
+    assert_eq!(json[0]["email"], "ada@example.com");
+    assert_eq!(get(&client, "/attendees?q=grace", auth).1, json!([]));
+
+    let (status, json) = get(&client, "/attendees/attendee", auth);
+    assert_eq!(status, 200);
+    assert_eq!(json["name"], "Ada Lovelace");
+    assert_eq!(json["check_ins"][0]["index"], 1);
+    assert_eq!(json["check_ins"].as_array().unwrap().len(), 1);
+    assert_eq!(get(&client, "/attendees/nobody", auth).0, 404);
+}
+
+#[test]
+fn feed() {
+    let client = client();
+    let mut feed = Feed::new(1);
+    let msgs = feed.messages(&client);
+    assert_eq!(msgs.len(), 1);
+    assert_eq!(msgs[0]["type"], "block");
+    assert_eq!(msgs[0]["block"]["check_in"]["attendee"], "attendee");
+    assert!(feed.messages(&client).is_empty());
+
+    let block = client.mine(str_to_arrayvec("foo").unwrap()).unwrap();
+    assert!(feed.handle(&Event::BlockAppended(block.clone())));
+    let msgs = feed.messages(&client);
+    assert_eq!(msgs.len(), 1);
+    assert_eq!(msgs[0]["block"]["hash"], block.hash.to_string());
+
+    let addr = SocketAddr::from(([10, 0, 0, 1], 10101));
+    assert!(!feed.handle(&Event::PeerDiscovered(addr)));
+    let fork = Event::ForkResolved {
+        addr,
+        fork: 1,
+        len: 3,
+        tip: block.hash,
+    };
+    assert!(feed.handle(&fork));
+    let msgs = feed.messages(&client);
+    assert_eq!(msgs[0], json!({ "type": "fork", "index": 1 }));
+    assert_eq!(msgs[1]["block"]["index"], 1);
+    assert_eq!(msgs[2]["block"]["index"], 2);
+    assert_eq!(msgs.len(), 3);
+}
+
+#[test]
+fn websocket_framing() {
+    // The example handshake from RFC 6455.
+    assert_eq!(
+        accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+        "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+    );
+
+    let mut frame = Vec::new();
+    write_frame(&mut frame, 0x1, b"Hello").unwrap();
+    assert_eq!(frame, b"\x81\x05Hello");
+
+    for &(len, header_len) in &[(125, 2), (126, 4), (65535, 4), (65536, 10)] {
+        let mut frame = Vec::new();
+        write_frame(&mut frame, 0x1, &vec![0; len]).unwrap();
+        assert_eq!(frame.len(), header_len + len);
+    }
+
+    // The example masked frame from RFC 6455.
+    let mut frame = &b"\x81\x85\x37\xfa\x21\x3d\x7f\x9f\x4d\x51\x58"[..];
+    assert_eq!(read_frame(&mut frame).unwrap(), (0x1, b"Hello".to_vec()));
+    assert!(read_frame(&mut &b"\x81\x05Hello"[..]).is_err());
+}
+
+#[test]
+fn websocket_handshake() {
+    let handshake = |request: &str| read_handshake(&mut request.as_bytes());
+    let key = "dGhlIHNhbXBsZSBub25jZQ==";
+    let request = format!(
+        "GET /feed?since=3 HTTP/1.1\r\nHost: station\r\n\
+         Upgrade: WebSocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\n\r\n",
+        key
+    );
+    assert_eq!(
+        handshake(&request).unwrap(),
+        Some((key.to_string(), Some(3)))
+    );
+    let request = request.replace("?since=3", "");
+    assert_eq!(handshake(&request).unwrap(), Some((key.to_string(), None)));
+
+    let invalid = [
+        request.replace("/feed", "/chain"),
+        request.replace("GET", "POST"),
+        request.replace("/feed", "/feed?since=two"),
+        request.replace("Upgrade: WebSocket\r\n", ""),
+        request.replace("Sec-WebSocket-Key", "Sec-WebSocket-Nope"),
+    ];
+    for request in &invalid {
+        assert_eq!(handshake(request).unwrap(), None, "{}", request);
+    }
+    assert!(handshake("GET /feed HTTP/1.1\r\n").is_err());
+}
+
+#[test]
+fn websocket_answers() {
+    // A masked ping, the first half of a masked close with code 1000, and
+    // then its second half.
+    let mut received = b"\x89\x82\x00\x00\x00\x00hi\x88\x82".to_vec();
+    let mut sent = Vec::new();
+    assert!(answer(&mut received, &mut sent).unwrap());
+    assert_eq!(sent, b"\x8a\x02hi");
+    assert_eq!(received, b"\x88\x82");
+
+    received.extend_from_slice(b"\x00\x00\x00\x00\x03\xe8");
+    sent.clear();
+    assert!(!answer(&mut received, &mut sent).unwrap());
+    assert_eq!(sent, b"\x88\x02\x03\xe8");
+
+    let mut unmasked = b"\x89\x02hi".to_vec();
+    assert!(answer(&mut unmasked, &mut sent).is_err());
+}
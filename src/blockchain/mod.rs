@@ -5,9 +5,11 @@ mod serialize;
 #[cfg(test)]
 mod tests;
 
-use std::cmp::{max, Ordering};
+use std::cmp::{max, min, Ordering};
+use std::collections::BTreeSet;
 use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::ops::Index;
+use std::str::FromStr;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use arrayvec::ArrayVec;
@@ -17,6 +19,9 @@ use crypto::sha2::Sha256;
 #[cfg(test)]
 use quickcheck::{Arbitrary, Gen};
 
+use keys::{
+    parse_hex, Keypair, PublicKey, Signature, ZERO_PUBLIC_KEY, ZERO_SIGNATURE,
+};
 use util::str_to_arrayvec;
 
 /// A SHA-256 hash.
@@ -27,6 +32,9 @@ pub struct Hash(pub [u8; 32]);
 /// The zero hash.
 pub const ZERO_HASH: Hash = Hash([0; 32]);
 
+/// The most data a block can hold, since its length is serialized as a byte.
+pub const MAX_DATA_LEN: usize = 255;
+
 #[cfg(test)]
 impl Arbitrary for Hash {
     fn arbitrary<G: Gen>(gen: &mut G) -> Hash {
@@ -45,6 +53,15 @@ impl Display for Hash {
     }
 }
 
+impl FromStr for Hash {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Hash, String> {
+        let mut hash = ZERO_HASH;
+        parse_hex(s, &mut hash.0)?;
+        Ok(hash)
+    }
+}
+
 /// A block on the blockchain.
 #[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
 pub struct Block {
@@ -54,8 +71,8 @@ pub struct Block {
     /// The hash of the previous block in the chain.
     pub prev_hash: Hash,
 
-    /// The time at which the block's hash was computed. This may be updated
-    /// when a fork occurs.
+    /// The time at which the block's data was signed. This is kept when the
+    /// block is moved by a fork being resolved.
     pub timestamp: u64,
 
     /// The hash of this block.
@@ -63,45 +80,106 @@ pub struct Block {
 
     /// The data in the block.
     pub data: ArrayVec<[u8; 256]>,
+
+    /// The public key of the station that signed the block.
+    pub signer: PublicKey,
+
+    /// The signer's signature of the timestamp and data. The index and
+    /// previous hash are not signed, so that the block can be moved when a
+    /// fork is resolved. To stop it being replayed elsewhere instead, a chain
+    /// can't have two blocks with the same signer, timestamp and data.
+    pub signature: Signature,
 }
 
 impl Block {
-    /// Creates a new block appended onto the current one with the given data.
-    pub fn create(&self, data: ArrayVec<[u8; 256]>) -> Block {
-        self.create_at(now(), data)
+    /// Creates a new block appended onto the current one with the given data,
+    /// signed with the given keypair.
+    pub fn create(
+        &self,
+        data: ArrayVec<[u8; 256]>,
+        keypair: &Keypair,
+    ) -> Block {
+        self.create_at(now(), data, keypair)
     }
 
     /// Creates a new block appended onto the current one with the given data
-    /// and timestamp.
+    /// and timestamp, signed with the given keypair.
     pub fn create_at(
         &self,
         timestamp: u64,
         data: ArrayVec<[u8; 256]>,
+        keypair: &Keypair,
     ) -> Block {
-        Block::new(self.index + 1, self.hash, timestamp, data)
+        Block::new(self.index + 1, self.hash, timestamp, data, keypair)
+    }
+
+    /// Creates a new block appended onto the current one with the timestamp,
+    /// data, and signature of another block.
+    pub fn create_from(&self, block: Block) -> Block {
+        Block::with_signature(
+            self.index + 1,
+            self.hash,
+            block.timestamp,
+            block.data,
+            block.signer,
+            block.signature,
+        )
+    }
+
+    /// Returns the block's header.
+    pub fn header(&self) -> Header {
+        Header {
+            index: self.index,
+            prev_hash: self.prev_hash,
+            hash: self.hash,
+        }
     }
 
-    /// Checks if this block's hash is internally consistent.
+    /// Checks if this block's hash is internally consistent and, unless it is
+    /// a genesis block, whether its signature is valid.
     pub fn is_valid(&self) -> bool {
-        hash_block(self.index, &self.prev_hash, self.timestamp, &self.data)
-            == self.hash
+        let hash = hash_block(
+            self.index,
+            &self.prev_hash,
+            self.timestamp,
+            &self.data,
+            &self.signer,
+            &self.signature,
+        );
+        if hash != self.hash {
+            false
+        } else if self.index == 0 {
+            true
+        } else {
+            let msg = signed_message(self.timestamp, &self.data);
+            self.signer.verify(&msg, &self.signature)
+        }
     }
 
-    /// Creates a new block with the given values.
+    /// Creates a new block with the given values, signed with the given
+    /// keypair.
     pub fn new(
         index: u64,
         prev_hash: Hash,
         timestamp: u64,
         data: ArrayVec<[u8; 256]>,
+        keypair: &Keypair,
     ) -> Block {
-        let hash = hash_block(index, &prev_hash, timestamp, &data);
-        Block {
+        let signature = keypair.sign(&signed_message(timestamp, &data));
+        Block::with_signature(
             index,
             prev_hash,
             timestamp,
             data,
-            hash,
-        }
+            keypair.public(),
+            signature,
+        )
+    }
+
+    /// Returns the block's signer, with the timestamp and data it signed.
+    /// These are unique within a valid chain.
+    fn signed(&self) -> (&PublicKey, u64, &[u8]) {
+        (&self.signer, self.timestamp, &self.data)
     }
 
     /// Checks if another block is a valid "next block" relative to this block.
@@ -114,6 +192,29 @@ impl Block {
             next.is_valid()
         }
     }
+
+    /// Creates a new block with the given values and an existing signature.
+    pub fn with_signature(
+        index: u64,
+        prev_hash: Hash,
+        timestamp: u64,
+        data: ArrayVec<[u8; 256]>,
+        signer: PublicKey,
+        signature: Signature,
+    ) -> Block {
+        let hash = hash_block(
+            index, &prev_hash, timestamp, &data, &signer, &signature,
+        );
+        Block {
+            index,
+            prev_hash,
+            timestamp,
+            data,
+            hash,
+            signer,
+            signature,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -125,55 +226,121 @@ impl Arbitrary for Block {
             data.push(gen.gen());
         }
 
+        let mut seed = [0; 32];
+        gen.fill_bytes(&mut seed);
+
         Block::new(
             u64::arbitrary(gen),
             Hash::arbitrary(gen),
             u64::arbitrary(gen),
             data,
+            &Keypair::from_seed(&seed),
         )
     }
 }
 
+/// The parts of a block needed to tell where it is in a chain, without its
+/// data.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Header {
+    /// The index of the block in the chain.
+    pub index: u64,
+
+    /// The hash of the previous block in the chain.
+    pub prev_hash: Hash,
+
+    /// The hash of the block.
+    pub hash: Hash,
+}
+
+impl Header {
+    /// Checks if the given header could come directly after this one.
+    pub fn valid_next(&self, next: &Header) -> bool {
+        self.index + 1 == next.index && self.hash == next.prev_hash
+    }
+}
+
+#[cfg(test)]
+impl Arbitrary for Header {
+    fn arbitrary<G: Gen>(gen: &mut G) -> Header {
+        Header {
+            index: u64::arbitrary(gen),
+            prev_hash: Hash::arbitrary(gen),
+            hash: Hash::arbitrary(gen),
+        }
+    }
+}
+
 /// A blockchain.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Chain {
     genesis: Block,
     blocks: Vec<Block>,
+    authorized_stations: Option<BTreeSet<PublicKey>>,
 }
 
 impl Chain {
     /// Combines the two blockchains deterministically. The point at which they
     /// fork is found, and the greater block (by `Block`'s `Ord` impl) is moved
-    /// to the end. The blockchains must share a genesis block and both be
-    /// valid.
+    /// to the end. The blocks moved keep their timestamps and signatures, and
+    /// any whose signer, timestamp and data already appear on the other side
+    /// of the fork are dropped, so combining is idempotent and gives the same
+    /// chain regardless of which node does it. The blockchains
+    /// must share a genesis block and both be valid.
     pub fn combine(mut self, mut other: Chain) -> Chain {
         if let Some(i) = self.find_fork(&other) {
-            let i_usize = i as usize;
-            let l = self.blocks.drain(i_usize..).collect::<Vec<_>>();
-            let r = other.blocks.drain(i_usize..).collect::<Vec<_>>();
-            drop(other);
-
-            // TODO: Rewrite this once NLLs are stable.
-            let l_is_less = if let (Some(l), Some(r)) = (l.get(0), r.get(0)) {
-                l <= r
-            } else {
-                true
-            };
+            let r = other.blocks.drain(i as usize..).collect();
+            self.merge_branch(i as usize, r);
+        }
+        self
+    }
 
-            if l_is_less {
-                self.blocks.extend(l);
-                for block in r {
-                    self.mine(block.data);
-                }
-            } else {
-                self.blocks.extend(r);
-                for block in l {
-                    self.mine(block.data);
-                }
+    /// Combines the chain with a branch, as `combine` would combine it with
+    /// the chain whose blocks from index `fork` on are the branch, and
+    /// returns whether the chain changed. Neither is validated, so this takes
+    /// time in proportion to the branch rather than the chain; the branch
+    /// should be checked with `valid_branch` first.
+    pub fn combine_branch(
+        &mut self,
+        fork: u64,
+        mut branch: Vec<Block>,
+    ) -> bool {
+        assert!(fork > 0, "A branch can't replace the genesis block");
+        let start = min(fork as usize - 1, self.blocks.len());
+        let l = self.blocks[start..].iter().map(|block| block.hash);
+        let r = branch.iter().map(|block| block.hash);
+        match first_difference(l, r) {
+            Some(i) => {
+                let tip = self.tip().hash;
+                branch.drain(..i);
+                self.merge_branch(start + i, branch);
+                self.tip().hash != tip
             }
-            self
+            None => false,
+        }
+    }
+
+    /// Replaces the blocks from `i` on, in the order `combine` puts them in,
+    /// with them and the blocks of another branch from the same block.
+    fn merge_branch(&mut self, i: usize, r: Vec<Block>) {
+        let l = self.blocks.drain(i..).collect::<Vec<_>>();
+
+        // TODO: Rewrite this once NLLs are stable.
+        let l_is_less = if let (Some(l), Some(r)) = (l.first(), r.first()) {
+            l <= r
         } else {
-            self
+            true
+        };
+
+        let (kept, moved) = if l_is_less { (l, r) } else { (r, l) };
+        let moved = moved
+            .into_iter()
+            .filter(|block| !kept.iter().any(|b| b.signed() == block.signed()))
+            .collect::<Vec<_>>();
+        self.blocks.extend(kept);
+        for block in moved {
+            let block = self.tip().create_from(block);
+            self.blocks.push(block);
         }
     }
 
@@ -184,19 +351,23 @@ impl Chain {
         assert!(self.is_valid());
         assert!(other.is_valid());
 
-        for i in 0..max(self.blocks.len(), other.blocks.len()) {
-            match (self.blocks.get(i), other.blocks.get(i)) {
-                (Some(l), Some(r)) => if l == r {
-                    continue;
-                } else {
-                    return Some(i as u64);
-                },
-                (Some(_), None) => return Some(i as u64),
-                (None, Some(_)) => return Some(i as u64),
-                (None, None) => unreachable!(),
-            }
-        }
-        None
+        let l = self.blocks.iter().map(|block| block.hash);
+        let r = other.blocks.iter().map(|block| block.hash);
+        first_difference(l, r).map(|i| i as u64)
+    }
+
+    /// Finds the index of the first of the headers that isn't in this chain,
+    /// if there is one. The headers must be consecutive, starting with one
+    /// that is in this chain.
+    pub fn find_fork_in_headers(&self, headers: &[Header]) -> Option<u64> {
+        let start = headers[0].index;
+        assert_eq!(self[start].hash, headers[0].hash);
+
+        let l = (start..self.len()).map(|i| self[i].hash);
+        let r = headers.iter().map(|header| header.hash);
+        first_difference(l, r)
+            .filter(|&i| i < headers.len())
+            .map(|i| start + i as u64)
     }
 
     /// Returns a reference to the first block in the chain.
@@ -204,11 +375,45 @@ impl Chain {
         &self.genesis
     }
 
+    /// Returns the headers of up to `count` blocks, starting at the given
+    /// index.
+    pub fn headers(&self, start: u64, count: u64) -> Vec<Header> {
+        let end = min(start.saturating_add(count), self.len());
+        (start..end).map(|i| self[i].header()).collect()
+    }
+
+    /// Returns whether the given station may sign blocks on this chain. Until
+    /// the authorized stations are set, any station may.
+    pub fn is_authorized(&self, station: &PublicKey) -> bool {
+        match self.authorized_stations {
+            Some(ref stations) => stations.contains(station),
+            None => true,
+        }
+    }
+
+    /// Finds the block in the chain signed by the given station with the
+    /// given timestamp and data, if any. No other block can be added with
+    /// them, since it would be a replay of that one.
+    pub fn find_signed(
+        &self,
+        signer: &PublicKey,
+        timestamp: u64,
+        data: &[u8],
+    ) -> Option<&Block> {
+        self.blocks
+            .iter()
+            .find(|block| block.signed() == (signer, timestamp, data))
+    }
+
     /// Returns whether the chain is valid.
     pub fn is_valid(&self) -> bool {
+        let mut signed = BTreeSet::new();
         let mut prev = &self.genesis;
         for block in &self.blocks {
-            if prev.valid_next(block) {
+            if prev.valid_next(block)
+                && self.is_authorized(&block.signer)
+                && signed.insert(block.signed())
+            {
                 prev = block;
             } else {
                 return false;
@@ -222,18 +427,55 @@ impl Chain {
         (self.blocks.len() as u64) + 1
     }
 
-    /// Mines a new block with the given data.
-    pub fn mine(&mut self, data: ArrayVec<[u8; 256]>) -> &Block {
-        self.mine_at(now(), data)
+    /// Finds the index of the last block in the chain whose hash is in the
+    /// locator, if any is.
+    pub fn locate(&self, locator: &[Hash]) -> Option<u64> {
+        let locator = locator.iter().collect::<BTreeSet<_>>();
+        (0..self.len()).rev().find(|&i| locator.contains(&self[i].hash))
+    }
+
+    /// Returns a block locator for the chain: the hashes of the last ten
+    /// blocks, then of blocks exponentially further apart, ending with the
+    /// genesis block. A peer can find the last of these on its own chain to
+    /// tell about where the two chains fork.
+    pub fn locator(&self) -> Vec<Hash> {
+        let mut locator = Vec::new();
+        let mut i = self.len() - 1;
+        let mut step = 1;
+        while i > 0 {
+            locator.push(self[i].hash);
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            i = i.saturating_sub(step);
+        }
+        locator.push(self.genesis.hash);
+        locator
+    }
+
+    /// Mines a new block with the given data, signed with the given keypair.
+    /// The keypair's public key should be authorized, and no block should
+    /// have been signed with it at the same time with the same data, or the
+    /// chain will no longer be valid.
+    pub fn mine(
+        &mut self,
+        data: ArrayVec<[u8; 256]>,
+        keypair: &Keypair,
+    ) -> &Block {
+        self.mine_at(now(), data, keypair)
     }
 
-    /// Mines a new block with the given data and timestamp.
+    /// Mines a new block with the given data and timestamp, signed with the
+    /// given keypair. The keypair's public key should be authorized, and no
+    /// block should have been signed with it at the same time with the same
+    /// data, or the chain will no longer be valid.
     pub fn mine_at(
         &mut self,
         timestamp: u64,
         data: ArrayVec<[u8; 256]>,
+        keypair: &Keypair,
     ) -> &Block {
-        let block = self.tip().create_at(timestamp, data);
+        let block = self.tip().create_at(timestamp, data, keypair);
         self.blocks.push(block);
         let block = self.blocks.last().unwrap();
         debug!("Blockchain now has {} blocks", self.len());
@@ -242,11 +484,13 @@ impl Chain {
 
     /// Creates a new Chain with the default genesis block.
     pub fn new() -> Chain {
-        Chain::with_genesis(Block::new(
+        Chain::with_genesis(Block::with_signature(
             0,
             ZERO_HASH,
             1515140055,
             str_to_arrayvec("Hello, world!").unwrap(),
+            ZERO_PUBLIC_KEY,
+            ZERO_SIGNATURE,
         ))
     }
 
@@ -262,10 +506,9 @@ impl Chain {
     }
 
     /// Gets the status of a block with respect to the chain. This chain must
-    /// be valid.
+    /// be valid. That isn't checked here, since it means verifying the
+    /// signature of every block, and this is called for every block received.
     pub fn status(&self, block: &Block) -> BlockStatus {
-        assert!(self.is_valid());
-
         match self.len().cmp(&block.index) {
             Ordering::Greater => {
                 if block == &self[block.index] {
@@ -290,10 +533,55 @@ impl Chain {
         self.blocks.last().unwrap_or(&self.genesis)
     }
 
+    /// Sets the stations that may sign blocks on this chain. If `None`, blocks
+    /// signed by any station are accepted.
+    pub fn set_authorized_stations(
+        &mut self,
+        stations: Option<BTreeSet<PublicKey>>,
+    ) {
+        self.authorized_stations = stations;
+    }
+
+    /// Shortens the chain to the given number of blocks. The genesis block is
+    /// never removed.
+    pub fn truncate(&mut self, len: u64) {
+        let len = max(len, 1) - 1;
+        self.blocks.truncate(len as usize);
+    }
+
+    /// Returns whether the blocks are a valid branch following block
+    /// `fork - 1` of the chain. Only the branch's blocks are verified.
+    pub fn valid_branch(&self, fork: u64, branch: &[Block]) -> bool {
+        if fork == 0 || fork > self.len() {
+            return false;
+        }
+        let signed = branch.iter().map(Block::signed).collect::<BTreeSet<_>>();
+        if signed.len() < branch.len()
+            || self.blocks[..fork as usize - 1]
+                .iter()
+                .any(|block| signed.contains(&block.signed()))
+        {
+            return false;
+        }
+
+        let mut prev = &self[fork - 1];
+        for block in branch {
+            if !(prev.valid_next(block) && self.is_authorized(&block.signer)) {
+                return false;
+            }
+            prev = block;
+        }
+        true
+    }
+
     /// Returns whether the given block is valid as the next block in the
     /// chain.
     pub fn valid_tip(&self, block: &Block) -> bool {
         self.tip().valid_next(block)
+            && self.is_authorized(&block.signer)
+            && self
+                .find_signed(&block.signer, block.timestamp, &block.data)
+                .is_none()
     }
 
     /// Creates a new Chain with the given genesis block.
@@ -301,6 +589,7 @@ impl Chain {
         Chain {
             genesis,
             blocks: Vec::new(),
+            authorized_stations: None,
         }
     }
 }
@@ -368,12 +657,31 @@ impl<'a> Iterator for Iter<'a> {
     }
 }
 
+/// Finds where two sequences of hashes first differ or one ends.
+fn first_difference<L, R>(l: L, r: R) -> Option<usize>
+where
+    L: IntoIterator<Item = Hash>,
+    R: IntoIterator<Item = Hash>,
+{
+    let (mut l, mut r) = (l.into_iter(), r.into_iter());
+    let mut i = 0;
+    loop {
+        match (l.next(), r.next()) {
+            (Some(lh), Some(rh)) if lh == rh => i += 1,
+            (None, None) => return None,
+            _ => return Some(i),
+        }
+    }
+}
+
 /// Hashes the components of a block.
 fn hash_block(
     index: u64,
     prev_hash: &Hash,
     timestamp: u64,
     data: &[u8],
+    signer: &PublicKey,
+    signature: &Signature,
 ) -> Hash {
     let mut buf = [0; 8];
     let mut hasher = Sha256::new();
@@ -388,13 +696,24 @@ fn hash_block(
 
     hasher.input(data);
 
+    hasher.input(&signer.0);
+    hasher.input(&signature.0);
+
     let mut hash = ZERO_HASH;
     hasher.result(&mut hash.0);
     hash
 }
 
+/// Returns the message a block's signature is over.
+fn signed_message(timestamp: u64, data: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0; 8];
+    LE::write_u64(&mut buf, timestamp);
+    buf.extend(data);
+    buf
+}
+
 /// Returns the current Unix timestamp.
-fn now() -> u64 {
+pub(crate) fn now() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
@@ -1,6 +1,7 @@
 use nom::{IResult, le_u64, le_u8};
 
-use blockchain::{Block, Hash};
+use blockchain::{Block, Hash, Header};
+use keys::{PublicKey, Signature};
 use util::slice_to_arrayvec;
 
 impl Block {
@@ -17,12 +18,43 @@ impl Block {
     }
 }
 
+impl Header {
+    /// Attempts to parse a `Header` from a buffer.
+    pub fn parse_from(msg: &[u8]) -> Option<Header> {
+        match header(msg) {
+            IResult::Done(rest, msg) => if rest.is_empty() {
+                Some(msg)
+            } else {
+                None
+            },
+            _ => None,
+        }
+    }
+}
+
 named!(pub block(&[u8]) -> Block, do_parse!(
     index: le_u64 >>
     prev_hash: hash >>
     timestamp: le_u64 >>
     data_len: le_u8 >>
     data: map_opt!(take!(data_len), slice_to_arrayvec) >>
+    signer: public_key >>
+    signature: signature >>
+    hash: hash >>
+    ( Block { index, prev_hash, timestamp, hash, data, signer, signature })));
+named!(pub header(&[u8]) -> Header, do_parse!(
+    index: le_u64 >>
+    prev_hash: hash >>
     hash: hash >>
-    ( Block { index, prev_hash, timestamp, hash, data })));
+    ( Header { index, prev_hash, hash })));
 named!(pub hash(&[u8]) -> Hash, map!(count_fixed!(u8, le_u8, 32), Hash));
+named!(pub public_key(&[u8]) -> PublicKey, map!(take!(32), |bs| {
+    let mut key = [0; 32];
+    key.copy_from_slice(bs);
+    PublicKey(key)
+}));
+named!(pub signature(&[u8]) -> Signature, map!(take!(64), |bs| {
+    let mut sig = [0; 64];
+    sig.copy_from_slice(bs);
+    Signature(sig)
+}));
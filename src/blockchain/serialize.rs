@@ -1,6 +1,6 @@
 use std::io::{Result, Write};
 
-use blockchain::Block;
+use blockchain::{Block, Header};
 use util::write_u64_to;
 
 impl Block {
@@ -15,6 +15,18 @@ impl Block {
         w.write_all(&[l as u8])?;
         w.write_all(&self.data)?;
 
+        w.write_all(&self.signer.0)?;
+        w.write_all(&self.signature.0)?;
+
+        w.write_all(&self.hash.0)
+    }
+}
+
+impl Header {
+    /// Serializes the `Header` to a `Write`.
+    pub fn write_to<W: Write>(&self, mut w: W) -> Result<()> {
+        write_u64_to(self.index, &mut w)?;
+        w.write_all(&self.prev_hash.0)?;
         w.write_all(&self.hash.0)
     }
 }
@@ -1,17 +1,28 @@
-use blockchain::{now, Block, Chain, ZERO_HASH};
+use std::collections::BTreeSet;
+
+use blockchain::{Block, BlockStatus, Chain, Header};
+use keys::Keypair;
 use util::str_to_arrayvec;
 
+fn keypair() -> Keypair {
+    Keypair::from_seed(&[0; 32])
+}
+
+fn keypair_2() -> Keypair {
+    Keypair::from_seed(&[1; 32])
+}
+
 fn example_chain() -> Chain {
     let mut chain = Chain::new();
-    chain.mine_at(1000, str_to_arrayvec("foo").unwrap());
-    chain.mine_at(2500, str_to_arrayvec("bar").unwrap());
+    chain.mine_at(1000, str_to_arrayvec("foo").unwrap(), &keypair());
+    chain.mine_at(2500, str_to_arrayvec("bar").unwrap(), &keypair());
     chain
 }
 
 fn example_chain_2() -> Chain {
     let mut chain = Chain::new();
-    chain.mine_at(1000, str_to_arrayvec("foo").unwrap());
-    chain.mine_at(2000, str_to_arrayvec("baz").unwrap());
+    chain.mine_at(1000, str_to_arrayvec("foo").unwrap(), &keypair());
+    chain.mine_at(2000, str_to_arrayvec("baz").unwrap(), &keypair_2());
     chain
 }
 
@@ -21,9 +32,9 @@ fn combine() {
     let combined_2 = example_chain_2().combine(example_chain());
 
     let mut expected = Chain::new();
-    expected.mine_at(1000, str_to_arrayvec("foo").unwrap());
-    expected.mine_at(2000, str_to_arrayvec("baz").unwrap());
-    expected.mine_at(now(), str_to_arrayvec("bar").unwrap());
+    expected.mine_at(1000, str_to_arrayvec("foo").unwrap(), &keypair());
+    expected.mine_at(2000, str_to_arrayvec("baz").unwrap(), &keypair_2());
+    expected.mine_at(2500, str_to_arrayvec("bar").unwrap(), &keypair());
 
     assert_eq!(combined_1, expected);
     assert_eq!(combined_2, expected);
@@ -33,19 +44,65 @@ fn combine() {
     assert!(expected.is_valid());
 }
 
+#[test]
+fn combine_is_idempotent() {
+    let combined = example_chain().combine(example_chain_2());
+
+    assert_eq!(combined.clone().combine(example_chain()), combined);
+    assert_eq!(example_chain().combine(combined.clone()), combined);
+    assert_eq!(combined.clone().combine(example_chain_2()), combined);
+    assert_eq!(combined.clone().combine(combined.clone()), combined);
+}
+
+#[test]
+fn combine_branch() {
+    let branch = |chain: Chain| {
+        chain.into_iter().skip(1).cloned().collect::<Vec<_>>()
+    };
+    let expected = example_chain().combine(example_chain_2());
+
+    let mut chain = example_chain();
+    assert!(chain.valid_branch(1, &branch(example_chain_2())));
+    assert!(chain.combine_branch(1, branch(example_chain_2())));
+    assert_eq!(chain, expected);
+    assert!(!chain.combine_branch(1, branch(example_chain())));
+    assert_eq!(chain, expected);
+
+    let mut chain = example_chain_2();
+    assert!(chain.combine_branch(2, branch(example_chain()).split_off(1)));
+    assert_eq!(chain, expected);
+
+    let mut invalid = branch(example_chain());
+    invalid.remove(0);
+    assert!(!chain.valid_branch(1, &invalid));
+    assert!(!chain.valid_branch(0, &[]));
+}
+
+#[test]
+fn truncate() {
+    let mut chain = example_chain();
+    chain.truncate(2);
+    assert_eq!(chain.len(), 2);
+    assert_eq!(chain.tip().index, 1);
+
+    chain.truncate(0);
+    assert_eq!(chain, Chain::new());
+}
+
 #[test]
 fn iter() {
-    let mut expected = vec![
-        Block::new(
-            0,
-            ZERO_HASH,
-            1515140055,
-            str_to_arrayvec("Hello, world!").unwrap(),
-        ),
-    ];
-    let mut next = expected[0].create_at(1000, str_to_arrayvec("foo").unwrap());
+    let mut expected = vec![Chain::new().genesis().clone()];
+    let mut next = expected[0].create_at(
+        1000,
+        str_to_arrayvec("foo").unwrap(),
+        &keypair(),
+    );
     expected.push(next);
-    next = expected[1].create_at(2500, str_to_arrayvec("bar").unwrap());
+    next = expected[1].create_at(
+        2500,
+        str_to_arrayvec("bar").unwrap(),
+        &keypair(),
+    );
     expected.push(next);
 
     for (i, block) in example_chain().into_iter().enumerate() {
@@ -65,6 +122,106 @@ fn find_fork() {
     assert_eq!(r.find_fork(&r), None);
 }
 
+#[test]
+fn locator() {
+    let mut chain = Chain::new();
+    for i in 0..100 {
+        let data = str_to_arrayvec(&i.to_string()).unwrap();
+        chain.mine_at(1000 + i, data, &keypair());
+    }
+
+    let locator = chain.locator();
+    assert_eq!(locator.len(), 16);
+    assert_eq!(locator[0], chain.tip().hash);
+    assert_eq!(locator[9], chain[91].hash);
+    assert_eq!(locator[10], chain[89].hash);
+    assert_eq!(locator[15], chain.genesis().hash);
+    assert_eq!(Chain::new().locator(), vec![Chain::new().genesis().hash]);
+
+    assert_eq!(chain.locate(&locator), Some(100));
+    assert_eq!(example_chain().locate(&locator), Some(0));
+    assert_eq!(Chain::new().locate(&example_chain().locator()), Some(0));
+    assert_eq!(chain.locate(&[]), None);
+}
+
+#[test]
+fn find_fork_in_headers() {
+    let l = example_chain();
+    let r = example_chain_2();
+
+    assert_eq!(l.find_fork_in_headers(&r.headers(0, 3)), Some(2));
+    assert_eq!(l.find_fork_in_headers(&r.headers(1, 3)), Some(2));
+    assert_eq!(l.find_fork_in_headers(&l.headers(0, 3)), None);
+    assert_eq!(l.find_fork_in_headers(&l.headers(0, 2)), None);
+
+    let mut longer = l.clone();
+    longer.mine_at(3000, str_to_arrayvec("qux").unwrap(), &keypair());
+    assert_eq!(l.find_fork_in_headers(&longer.headers(0, 4)), Some(3));
+    assert_eq!(longer.find_fork_in_headers(&l.headers(1, 2)), None);
+}
+
+#[test]
+fn signatures() {
+    let mut block = example_chain().tip().clone();
+    assert!(block.is_valid());
+
+    block.signer = keypair_2().public();
+    assert!(!block.is_valid());
+}
+
+#[test]
+fn replayed_blocks() {
+    let mut chain = example_chain();
+    let found = chain.find_signed(&keypair().public(), 1000, b"foo");
+    assert_eq!(found, Some(&chain[1]));
+    assert_eq!(chain.find_signed(&keypair_2().public(), 1000, b"foo"), None);
+
+    let replay = chain.tip().create_from(chain[1].clone());
+    assert!(replay.is_valid());
+    assert_eq!(chain.status(&replay), BlockStatus::Invalid);
+    assert!(!chain.push(replay.clone()));
+    assert!(!chain.valid_branch(3, &[replay.clone()]));
+
+    let data = str_to_arrayvec("baz").unwrap();
+    let block = chain[0].create_at(3000, data, &keypair());
+    let branch = [block.clone(), block.create_from(block.clone())];
+    assert!(chain.valid_branch(1, &branch[..1]));
+    assert!(!chain.valid_branch(1, &branch));
+
+    chain.blocks.push(replay);
+    assert!(!chain.is_valid());
+}
+
+#[test]
+fn unauthorized_stations() {
+    let mut chain = Chain::new();
+    let mut stations = BTreeSet::new();
+    stations.insert(keypair().public());
+    chain.set_authorized_stations(Some(stations));
+
+    let block = chain.tip().create_at(
+        1000,
+        str_to_arrayvec("foo").unwrap(),
+        &keypair_2(),
+    );
+    assert!(block.is_valid());
+    assert_eq!(chain.status(&block), BlockStatus::Invalid);
+    assert!(!chain.push(block));
+
+    let block = chain.tip().create_at(
+        1000,
+        str_to_arrayvec("foo").unwrap(),
+        &keypair(),
+    );
+    assert!(chain.push(block));
+    assert!(chain.is_valid());
+
+    assert!(example_chain_2().is_authorized(&keypair_2().public()));
+    let mut chain = example_chain_2();
+    chain.set_authorized_stations(Some(BTreeSet::new()));
+    assert!(!chain.is_valid());
+}
+
 quickcheck! {
     fn serialize_parse_is_identity(block: Block) -> () {
         let mut buf = Vec::new();
@@ -72,4 +229,11 @@ quickcheck! {
         let block2 = Block::parse_from(&buf).expect("Failed to parse");
         assert_eq!(block, block2);
     }
+
+    fn serialize_parse_header_is_identity(header: Header) -> () {
+        let mut buf = Vec::new();
+        header.write_to(&mut buf).expect("Failed to serialize");
+        let header2 = Header::parse_from(&buf).expect("Failed to parse");
+        assert_eq!(header, header2);
+    }
 }
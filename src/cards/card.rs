@@ -0,0 +1,356 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// The most characters each track can hold, including its sentinels and LRC.
+const MAX_TRACK_LENS: [usize; 3] = [79, 40, 107];
+
+/// The number of digits in a University of Minnesota student ID.
+const STUDENT_ID_LEN: usize = 7;
+
+/// The tracks read from a magnetic stripe card. Tracks the reader couldn't
+/// read are `None`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Card {
+    /// Track 1, which holds the account number and the cardholder's name.
+    pub track1: Option<Track1>,
+
+    /// Track 2, which holds the account number.
+    pub track2: Option<Track2>,
+
+    /// The data on track 3, which has no standard layout.
+    pub track3: Option<String>,
+}
+
+impl Card {
+    /// Returns the card's account number, from track 2 if it was read, or
+    /// else track 1.
+    pub fn account_number(&self) -> Option<&str> {
+        let track1 = self.track1.as_ref().map(|t| t.account_number.as_str());
+        self.track2
+            .as_ref()
+            .map(|t| t.account_number.as_str())
+            .or(track1)
+    }
+
+    /// Returns the student ID on a U Card, which is its account number.
+    /// Returns `None` if the card isn't a U Card.
+    pub fn student_id(&self) -> Option<&str> {
+        self.account_number().filter(|n| n.len() == STUDENT_ID_LEN)
+    }
+}
+
+/// Track 1 of a card, in the layout of ISO/IEC 7813.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Track1 {
+    /// The format code, which is `B` for bank cards.
+    pub format_code: char,
+
+    /// The primary account number.
+    pub account_number: String,
+
+    /// The cardholder's name, as `SURNAME/GIVEN NAMES`.
+    pub name: String,
+
+    /// The expiration date, as `YYMM`, if present.
+    pub expiry: Option<String>,
+
+    /// The service code, if present.
+    pub service_code: Option<String>,
+
+    /// The rest of the track, whose meaning is up to the issuer.
+    pub discretionary: String,
+}
+
+/// Track 2 of a card, in the layout of ISO/IEC 7813.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Track2 {
+    /// The primary account number.
+    pub account_number: String,
+
+    /// The expiration date, as `YYMM`, if present.
+    pub expiry: Option<String>,
+
+    /// The service code, if present.
+    pub service_code: Option<String>,
+
+    /// The rest of the track, whose meaning is up to the issuer.
+    pub discretionary: String,
+}
+
+/// Why a card couldn't be parsed. Tracks are numbered from 1.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CardError {
+    /// The reader couldn't read any track of the card.
+    BadRead,
+
+    /// A track's longitudinal redundancy check failed.
+    BadLrc {
+        /// The track.
+        track: u8,
+
+        /// The LRC computed from the track.
+        expected: char,
+
+        /// The LRC that was read.
+        found: char,
+    },
+
+    /// A track was read more than once.
+    DuplicateTrack(u8),
+
+    /// A track has a character that can't be encoded on it.
+    InvalidCharacter {
+        /// The track.
+        track: u8,
+
+        /// The character.
+        found: char,
+    },
+
+    /// A field of a track is malformed.
+    InvalidField {
+        /// The track.
+        track: u8,
+
+        /// The name of the field.
+        field: &'static str,
+    },
+
+    /// A track has no end sentinel.
+    MissingEndSentinel(u8),
+
+    /// A track ends before one of its fields.
+    MissingField {
+        /// The track.
+        track: u8,
+
+        /// The name of the field.
+        field: &'static str,
+    },
+
+    /// The input contains no tracks.
+    NoTracks,
+
+    /// A track is longer than it can be.
+    TooLong(u8),
+
+    /// A character was found outside of a track, where a start sentinel was
+    /// expected.
+    UnexpectedCharacter(char),
+}
+
+impl Display for CardError {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            CardError::BadRead => write!(fmt, "The card couldn't be read"),
+            CardError::BadLrc {
+                track,
+                expected,
+                found,
+            } => write!(
+                fmt,
+                "Track {} has LRC {:?}, but should have {:?}",
+                track, found, expected
+            ),
+            CardError::DuplicateTrack(track) => {
+                write!(fmt, "Track {} was read twice", track)
+            }
+            CardError::InvalidCharacter { track, found } => {
+                write!(fmt, "Track {} can't contain {:?}", track, found)
+            }
+            CardError::InvalidField { track, field } => {
+                write!(fmt, "Track {} has an invalid {}", track, field)
+            }
+            CardError::MissingEndSentinel(track) => {
+                write!(fmt, "Track {} has no end sentinel", track)
+            }
+            CardError::MissingField { track, field } => {
+                write!(fmt, "Track {} has no {}", track, field)
+            }
+            CardError::NoTracks => write!(fmt, "No tracks were read"),
+            CardError::TooLong(track) => {
+                write!(fmt, "Track {} is too long", track)
+            }
+            CardError::UnexpectedCharacter(c) => {
+                write!(fmt, "Expected a start sentinel, found {:?}", c)
+            }
+        }
+    }
+}
+
+/// Parses a line from a card reader. Each track starts with its start
+/// sentinel (`%` for track 1, `;` for track 2, and `+` or a second `;` for
+/// track 3) and ends with `?`, optionally followed by its LRC. A track the
+/// reader couldn't read is sent as `E`.
+pub fn parse_card(input: &str) -> Result<Card, CardError> {
+    let mut card = Card::default();
+    let mut seen = [false; 3];
+    let mut rest = input.trim_end_matches(&['\r', '\n'][..]);
+    while let Some(c) = rest.chars().next() {
+        let track = match c {
+            '%' => 1,
+            ';' if seen[1] => 3,
+            ';' => 2,
+            '+' => 3,
+            c => return Err(CardError::UnexpectedCharacter(c)),
+        };
+        if seen[track as usize - 1] {
+            return Err(CardError::DuplicateTrack(track));
+        }
+        seen[track as usize - 1] = true;
+
+        let end = rest.find('?')
+            .ok_or(CardError::MissingEndSentinel(track))?;
+        let data = &rest[1..end];
+        rest = &rest[end + 1..];
+
+        // An LRC equal to a start sentinel can't be told apart from the start
+        // of the next track, but readers rarely send the LRC at all.
+        let lrc = match rest.chars().next() {
+            Some(c) if !"%;+".contains(c) => {
+                rest = &rest[c.len_utf8()..];
+                Some(c)
+            }
+            _ => None,
+        };
+
+        if data == "E" {
+            continue;
+        }
+        check_track(track, data, lrc)?;
+        match track {
+            1 => card.track1 = Some(parse_track1(data)?),
+            2 => card.track2 = Some(parse_track2(data)?),
+            _ => card.track3 = Some(data.to_string()),
+        }
+    }
+
+    if seen == [false; 3] {
+        Err(CardError::NoTracks)
+    } else if card == Card::default() {
+        Err(CardError::BadRead)
+    } else {
+        Ok(card)
+    }
+}
+
+/// Checks a track's length, its characters, and its LRC if it was read.
+fn check_track(
+    track: u8,
+    data: &str,
+    lrc: Option<char>,
+) -> Result<(), CardError> {
+    if data.chars().count() + 3 > MAX_TRACK_LENS[track as usize - 1] {
+        return Err(CardError::TooLong(track));
+    }
+    let valid = |c: char| match track {
+        1 => (' '..='_').contains(&c) && c != '%',
+        _ => c.is_ascii_digit() || c == '=',
+    };
+    if let Some(c) = data.chars().find(|&c| !valid(c)) {
+        return Err(CardError::InvalidCharacter { track, found: c });
+    }
+
+    if let Some(found) = lrc {
+        // Track 1 uses 6-bit characters starting at space, and tracks 2 and 3
+        // use 4-bit characters starting at `0`. Readers send track 3's start
+        // sentinel as `+`, but it's encoded as `;`.
+        let (base, mask, start) = match track {
+            1 => (b' ', 0x3f, '%'),
+            _ => (b'0', 0x0f, ';'),
+        };
+        let code = |c: char| (c as u8).wrapping_sub(base) & mask;
+        let lrc = data.chars().fold(code(start) ^ code('?'), |lrc, c| {
+            lrc ^ code(c)
+        });
+        let expected = (lrc + base) as char;
+        if found != expected {
+            return Err(CardError::BadLrc {
+                track,
+                expected,
+                found,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Parses the data of track 1, between its sentinels.
+fn parse_track1(data: &str) -> Result<Track1, CardError> {
+    let mut chars = data.chars();
+    let format_code = match chars.next() {
+        Some(c) if c.is_ascii_uppercase() => c,
+        Some(_) => return Err(invalid(1, "format code")),
+        None => return Err(missing(1, "format code")),
+    };
+    let mut fields = chars.as_str().splitn(3, '^');
+    let account_number = account_number(1, fields.next().unwrap_or(""))?;
+    let name = fields.next().ok_or_else(|| missing(1, "name"))?;
+    let rest = fields.next().ok_or_else(|| missing(1, "expiry"))?;
+    let (expiry, rest) = optional_field(1, rest, '^', 4, "expiry")?;
+    let (service_code, rest) =
+        optional_field(1, rest, '^', 3, "service code")?;
+    Ok(Track1 {
+        format_code,
+        account_number,
+        name: name.trim_end().to_string(),
+        expiry,
+        service_code,
+        discretionary: rest.to_string(),
+    })
+}
+
+/// Parses the data of track 2, between its sentinels.
+fn parse_track2(data: &str) -> Result<Track2, CardError> {
+    let mut fields = data.splitn(2, '=');
+    let account_number = account_number(2, fields.next().unwrap_or(""))?;
+    let rest = fields.next().ok_or_else(|| missing(2, "expiry"))?;
+    let (expiry, rest) = optional_field(2, rest, '=', 4, "expiry")?;
+    let (service_code, rest) =
+        optional_field(2, rest, '=', 3, "service code")?;
+    Ok(Track2 {
+        account_number,
+        expiry,
+        service_code,
+        discretionary: rest.to_string(),
+    })
+}
+
+/// Checks that an account number is 1 to 19 digits.
+fn account_number(track: u8, s: &str) -> Result<String, CardError> {
+    if s.is_empty() {
+        Err(missing(track, "account number"))
+    } else if s.len() > 19 || !s.bytes().all(|b| b.is_ascii_digit()) {
+        Err(invalid(track, "account number"))
+    } else {
+        Ok(s.to_string())
+    }
+}
+
+/// Takes a numeric field of the given length from the start of `s`, or the
+/// separator that stands in for it when it's absent.
+fn optional_field<'a>(
+    track: u8,
+    s: &'a str,
+    separator: char,
+    len: usize,
+    field: &'static str,
+) -> Result<(Option<String>, &'a str), CardError> {
+    if s.starts_with(separator) {
+        return Ok((None, &s[1..]));
+    }
+    match s.get(..len) {
+        Some(f) if f.bytes().all(|b| b.is_ascii_digit()) => {
+            Ok((Some(f.to_string()), &s[len..]))
+        }
+        _ if s.is_empty() => Err(missing(track, field)),
+        _ => Err(invalid(track, field)),
+    }
+}
+
+fn invalid(track: u8, field: &'static str) -> CardError {
+    CardError::InvalidField { track, field }
+}
+
+fn missing(track: u8, field: &'static str) -> CardError {
+    CardError::MissingField { track, field }
+}
@@ -0,0 +1,210 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::result::Result as StdResult;
+use std::str::FromStr;
+
+use arrayvec::ArrayVec;
+#[cfg(test)]
+use quickcheck::{Arbitrary, Gen};
+use serde::de::Error as DeError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use blockchain::{now, Block, MAX_DATA_LEN};
+
+/// The current version of the check-in encoding.
+pub const CHECK_IN_VERSION: u8 = 1;
+
+/// A check-in record, as stored in the data of a `Block`.
+#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct CheckIn {
+    /// An identifier for the attendee who checked in.
+    pub attendee: String,
+
+    /// The ID of the station the check-in occurred at.
+    pub station: String,
+
+    /// The kind of event being recorded.
+    pub kind: EventKind,
+
+    /// The time at which the card was read, as a Unix timestamp.
+    pub timestamp: u64,
+}
+
+impl CheckIn {
+    /// Decodes the check-in stored in a block, if there is one.
+    pub fn from_block(block: &Block) -> Option<CheckIn> {
+        CheckIn::parse_from(&block.data)
+    }
+
+    /// Creates a new check-in at the current time.
+    pub fn new(attendee: String, station: String, kind: EventKind) -> CheckIn {
+        CheckIn {
+            attendee,
+            station,
+            kind,
+            timestamp: now(),
+        }
+    }
+
+    /// Encodes the check-in as block data. Returns `None` if it is too large
+    /// to fit in a block.
+    pub fn to_data(&self) -> Option<ArrayVec<[u8; 256]>> {
+        if self.attendee.len() > 255 || self.station.len() > 255 {
+            return None;
+        }
+        if let EventKind::Workshop(ref id) = self.kind {
+            if id.len() > 255 {
+                return None;
+            }
+        }
+
+        let mut buf = Vec::new();
+        self.write_to(&mut buf).unwrap();
+
+        if buf.len() > MAX_DATA_LEN {
+            None
+        } else {
+            Some(buf.into_iter().collect())
+        }
+    }
+}
+
+#[cfg(test)]
+impl Arbitrary for CheckIn {
+    fn arbitrary<G: Gen>(gen: &mut G) -> CheckIn {
+        let mut attendee = String::arbitrary(gen);
+        let mut station = String::arbitrary(gen);
+        while attendee.len() > 100 {
+            attendee.pop();
+        }
+        while station.len() > 100 {
+            station.pop();
+        }
+
+        let mut check_in = CheckIn {
+            attendee,
+            station,
+            kind: EventKind::arbitrary(gen),
+            timestamp: u64::arbitrary(gen),
+        };
+        // Multi-byte characters can still make it too large for a block.
+        while check_in.to_data().is_none() {
+            if check_in.attendee.pop().is_none() {
+                check_in.station.pop();
+            }
+        }
+        check_in
+    }
+}
+
+impl Display for CheckIn {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(
+            fmt,
+            "{} of {} at {}",
+            self.kind, self.attendee, self.station
+        )
+    }
+}
+
+/// The kind of event a check-in records.
+///
+/// As a string, this is `arrival`, `departure`, `meal:<number>`,
+/// `workshop:<ID>` or `swag`.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum EventKind {
+    /// The attendee arrived at the event.
+    Arrival,
+
+    /// The attendee left the event.
+    Departure,
+
+    /// The attendee got the meal with the given number.
+    Meal(u8),
+
+    /// The attendee went to the workshop with the given ID.
+    Workshop(String),
+
+    /// The attendee got their swag.
+    Swag,
+}
+
+impl EventKind {
+    /// Returns whether each attendee can only check in for the event once,
+    /// as for meals, workshops and swag.
+    pub fn is_once_per_attendee(&self) -> bool {
+        match *self {
+            EventKind::Arrival | EventKind::Departure => false,
+            EventKind::Meal(_) | EventKind::Workshop(_) | EventKind::Swag => {
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl Arbitrary for EventKind {
+    fn arbitrary<G: Gen>(gen: &mut G) -> EventKind {
+        match gen.gen_range(0, 5) {
+            0 => EventKind::Arrival,
+            1 => EventKind::Departure,
+            2 => EventKind::Meal(gen.gen()),
+            3 => {
+                let mut id = String::arbitrary(gen);
+                while id.len() > 20 {
+                    id.pop();
+                }
+                EventKind::Workshop(id)
+            }
+            _ => EventKind::Swag,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for EventKind {
+    fn deserialize<D: Deserializer<'de>>(
+        d: D,
+    ) -> StdResult<EventKind, D::Error> {
+        let s = String::deserialize(d)?;
+        s.parse().map_err(D::Error::custom)
+    }
+}
+
+impl Display for EventKind {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            EventKind::Arrival => fmt.write_str("arrival"),
+            EventKind::Departure => fmt.write_str("departure"),
+            EventKind::Meal(n) => write!(fmt, "meal:{}", n),
+            EventKind::Workshop(ref id) => write!(fmt, "workshop:{}", id),
+            EventKind::Swag => fmt.write_str("swag"),
+        }
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = String;
+    fn from_str(s: &str) -> StdResult<EventKind, String> {
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (s, None),
+        };
+        match (name.to_lowercase().as_str(), arg) {
+            ("arrival", None) => Ok(EventKind::Arrival),
+            ("departure", None) => Ok(EventKind::Departure),
+            ("meal", Some(n)) => n.parse()
+                .map(EventKind::Meal)
+                .map_err(|_| format!("Invalid meal number: {}", n)),
+            ("workshop", Some(id)) if !id.is_empty() => {
+                Ok(EventKind::Workshop(id.to_string()))
+            }
+            ("swag", None) => Ok(EventKind::Swag),
+            _ => Err(format!("Invalid event kind: {}", s)),
+        }
+    }
+}
+
+impl Serialize for EventKind {
+    fn serialize<S: Serializer>(&self, s: S) -> StdResult<S::Ok, S::Error> {
+        s.serialize_str(&self.to_string())
+    }
+}
@@ -0,0 +1,28 @@
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+
+/// The number of bytes of each field's HMAC that are kept.
+const HASH_LEN: usize = 16;
+
+/// Reduces the fields read from a card to an identifier that can be recorded
+/// in check-ins without revealing them. Each field is replaced by its
+/// HMAC-SHA256, keyed with the event's secret and truncated, so a field can
+/// still be matched against a U Card ID hashed the same way.
+pub fn hash_card(secret: &str, fields: &[&str]) -> String {
+    fields
+        .iter()
+        .map(|field| hash_card_field(secret, field))
+        .collect::<Vec<_>>()
+        .join("^")
+}
+
+/// Hashes a single field read from a card, as `hash_card` does.
+pub fn hash_card_field(secret: &str, field: &str) -> String {
+    let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
+    hmac.input(field.trim().as_bytes());
+    hmac.result().code()[..HASH_LEN]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
@@ -1,30 +1,13 @@
-//! Functions for the U Cards.
+//! Functions for the U Cards, and the check-in records made from them.
 
+mod card;
+mod checkin;
+mod hash;
 pub(crate) mod parse;
+mod serialize;
 #[cfg(test)]
 mod tests;
 
-/// The result of reading a card.
-#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
-pub enum CardParse<'a> {
-    /// A successful read.
-    Card(Vec<&'a str>),
-
-    /// A read failed.
-    BadRead,
-
-    /// An invalid input was attempted to be parsed.
-    BadParse,
-}
-
-/// Parse a card. Returns `Card` on a successful card read, `BadRead` on a bad
-/// card read, and `BadParse` if the input was invalid.
-pub fn parse_card(input: &str) -> CardParse {
-    use nom::IResult;
-
-    match parse::card_result(input) {
-        IResult::Done("", Some(card)) => CardParse::Card(card),
-        IResult::Done("", None) => CardParse::BadRead,
-        _ => CardParse::BadParse,
-    }
-}
+pub use self::card::{parse_card, Card, CardError, Track1, Track2};
+pub use self::checkin::{CheckIn, EventKind, CHECK_IN_VERSION};
+pub use self::hash::{hash_card, hash_card_field};
@@ -1,13 +1,35 @@
-named!(pub card_result(&str) -> Option<Vec<&str>>,
-    alt_complete!(card_result_err | card_result_ok));
-named!(card_result_ok(&str) -> Option<Vec<&str>>,
-    map!(card_stripes, Some));
-named!(card_result_err(&str) -> Option<Vec<&str>>,
-    map!(tag_s!("%E?\n"), |_| None));
-
-named!(card_stripes(&str) -> Vec<&str>, do_parse!(
-    tag_s!("%") >>
-    init: many0!(map!(pair!(take_until_s!("^"), tag_s!("^")), |(s, _)| s)) >>
-    last: take_until_s!("?") >>
-    tag_s!("?\n") >>
-    ({ let mut init = init; init.push(last); init })));
+use std::str::from_utf8;
+
+use nom::{IResult, le_u64, le_u8};
+
+use cards::{CheckIn, EventKind, CHECK_IN_VERSION};
+
+impl CheckIn {
+    /// Attempts to parse a `CheckIn` from a buffer.
+    pub fn parse_from(buf: &[u8]) -> Option<CheckIn> {
+        match check_in(buf) {
+            IResult::Done(rest, check_in) => if rest.is_empty() {
+                Some(check_in)
+            } else {
+                None
+            },
+            _ => None,
+        }
+    }
+}
+
+named!(pub check_in(&[u8]) -> CheckIn, do_parse!(
+    tag!([CHECK_IN_VERSION]) >>
+    kind: event_kind >>
+    timestamp: le_u64 >>
+    station: string >>
+    attendee: string >>
+    ( CheckIn { attendee, station, kind, timestamp } )));
+named!(event_kind(&[u8]) -> EventKind, alt_complete!(
+    map!(tag!([0x00]), |_| EventKind::Arrival) |
+    map!(tag!([0x01]), |_| EventKind::Departure) |
+    map!(preceded!(tag!([0x02]), le_u8), EventKind::Meal) |
+    map!(preceded!(tag!([0x03]), string), EventKind::Workshop) |
+    map!(tag!([0x04]), |_| EventKind::Swag)));
+named!(string(&[u8]) -> String,
+    map!(map_res!(length_bytes!(le_u8), from_utf8), str::to_owned));
@@ -0,0 +1,38 @@
+use std::io::{Result, Write};
+
+use cards::{CheckIn, EventKind, CHECK_IN_VERSION};
+use util::write_u64_to;
+
+impl CheckIn {
+    /// Serializes the `CheckIn` to a `Write`.
+    pub fn write_to<W: Write>(&self, mut w: W) -> Result<()> {
+        w.write_all(&[CHECK_IN_VERSION])?;
+        self.kind.write_to(&mut w)?;
+        write_u64_to(self.timestamp, &mut w)?;
+        write_str_to(&self.station, &mut w)?;
+        write_str_to(&self.attendee, &mut w)
+    }
+}
+
+impl EventKind {
+    /// Serializes the `EventKind` to a `Write`.
+    pub fn write_to<W: Write>(&self, mut w: W) -> Result<()> {
+        match *self {
+            EventKind::Arrival => w.write_all(&[0x00]),
+            EventKind::Departure => w.write_all(&[0x01]),
+            EventKind::Meal(n) => w.write_all(&[0x02, n]),
+            EventKind::Workshop(ref id) => {
+                w.write_all(&[0x03])?;
+                write_str_to(id, &mut w)
+            }
+            EventKind::Swag => w.write_all(&[0x04]),
+        }
+    }
+}
+
+fn write_str_to<W: Write>(s: &str, w: &mut W) -> Result<()> {
+    let l = s.len();
+    assert!(l < 256);
+    w.write_all(&[l as u8])?;
+    w.write_all(s.as_bytes())
+}
@@ -1,14 +1,169 @@
-use nom::IResult;
-
-use cards::{parse_card, CardParse};
-use cards::parse::card_result;
+use cards::{
+    hash_card, hash_card_field, parse_card, Card, CardError, CheckIn,
+    EventKind, Track1, Track2,
+};
 
 // TODO: Find a card I'm okay with having its stripe be public.
 // Old library card? Middle-school ID?
 
+const TRACK_1: &str = "%B1234567^LOVELACE/ADA^2512101DISC?";
+const TRACK_2: &str = ";1234567=2512101000?";
+
+#[test]
+fn parse_tracks() {
+    let read = format!("{}{}+0123=456?\n", TRACK_1, TRACK_2);
+    let card = parse_card(&read).expect("Failed to parse");
+    assert_eq!(
+        card,
+        Card {
+            track1: Some(Track1 {
+                format_code: 'B',
+                account_number: "1234567".to_string(),
+                name: "LOVELACE/ADA".to_string(),
+                expiry: Some("2512".to_string()),
+                service_code: Some("101".to_string()),
+                discretionary: "DISC".to_string(),
+            }),
+            track2: Some(Track2 {
+                account_number: "1234567".to_string(),
+                expiry: Some("2512".to_string()),
+                service_code: Some("101".to_string()),
+                discretionary: "000".to_string(),
+            }),
+            track3: Some("0123=456".to_string()),
+        }
+    );
+    assert_eq!(card.student_id(), Some("1234567"));
+
+    // Missing fields can be replaced by separators, and the LRC may follow
+    // each track.
+    let read = format!("%B4111111111111111^CARD/TEST^^^?P{}=\r\n", TRACK_2);
+    let card = parse_card(&read).expect("Failed to parse");
+    let track1 = card.track1.as_ref().unwrap();
+    assert_eq!(track1.expiry, None);
+    assert_eq!(track1.service_code, None);
+    assert_eq!(track1.discretionary, "");
+    assert_eq!(card.account_number(), Some("1234567"));
+
+    let card = parse_card("%B4111111111111111^CARD/TEST^2512101?\n").unwrap();
+    assert_eq!(card.student_id(), None);
+}
+
 #[test]
 fn parse_err() {
-    let read = "%E?\n";
-    assert_eq!(card_result(read), IResult::Done("", None));
-    assert_eq!(parse_card(read), CardParse::BadRead);
+    assert_eq!(parse_card("%E?\n"), Err(CardError::BadRead));
+    assert_eq!(parse_card("%E?;E?+E?\n"), Err(CardError::BadRead));
+    let card = parse_card(&format!("%E?{}\n", TRACK_2)).unwrap();
+    assert_eq!(card.track1, None);
+    assert_eq!(card.student_id(), Some("1234567"));
+
+    let errors = [
+        ("\n", CardError::NoTracks),
+        ("B1234567^A^?", CardError::UnexpectedCharacter('B')),
+        ("%B1234567^A^", CardError::MissingEndSentinel(1)),
+        ("+1?+2?", CardError::DuplicateTrack(3)),
+        (";12a4=?", CardError::InvalidCharacter {
+            track: 2,
+            found: 'a',
+        }),
+        ("%11234567^A^^^?", CardError::InvalidField {
+            track: 1,
+            field: "format code",
+        }),
+        ("%B12X4567^A^^^?", CardError::InvalidField {
+            track: 1,
+            field: "account number",
+        }),
+        ("%B1234567^A^25?", CardError::InvalidField {
+            track: 1,
+            field: "expiry",
+        }),
+        ("%B1234567^A?", CardError::MissingField {
+            track: 1,
+            field: "expiry",
+        }),
+        (";=?", CardError::MissingField {
+            track: 2,
+            field: "account number",
+        }),
+        (";1234567=2512?", CardError::MissingField {
+            track: 2,
+            field: "service code",
+        }),
+        ("%B1234567^LOVELACE/ADA^2512101DISC?2", CardError::BadLrc {
+            track: 1,
+            expected: '1',
+            found: '2',
+        }),
+    ];
+    for &(read, ref err) in &errors {
+        assert_eq!(parse_card(read).as_ref(), Err(err), "{:?}", read);
+    }
+    let long = format!(";{}=?", "1".repeat(40));
+    assert_eq!(parse_card(&long), Err(CardError::TooLong(2)));
+}
+
+#[test]
+fn check_in_too_large() {
+    let check_in = CheckIn {
+        attendee: "x".repeat(200),
+        station: "y".repeat(100),
+        kind: EventKind::Arrival,
+        timestamp: 0,
+    };
+    assert_eq!(check_in.to_data(), None);
+}
+
+#[test]
+fn check_in_size_limit() {
+    let mut check_in = CheckIn {
+        attendee: "x".repeat(236),
+        station: "station".into(),
+        kind: EventKind::Arrival,
+        timestamp: 0,
+    };
+    assert_eq!(check_in.to_data().map(|data| data.len()), Some(255));
+    check_in.attendee.push('x');
+    assert_eq!(check_in.to_data(), None);
+}
+
+#[test]
+fn event_kind_strings() {
+    let kinds = [
+        ("arrival", EventKind::Arrival),
+        ("departure", EventKind::Departure),
+        ("meal:2", EventKind::Meal(2)),
+        ("workshop:intro-to-rust", EventKind::Workshop("intro-to-rust".into())),
+        ("swag", EventKind::Swag),
+    ];
+    for &(s, ref kind) in &kinds {
+        assert_eq!(s.parse::<EventKind>().as_ref(), Ok(kind));
+        assert_eq!(kind.to_string(), s);
+    }
+    assert_eq!("Arrival".parse(), Ok(EventKind::Arrival));
+    for s in &["meal", "meal:dinner", "workshop:", "swag:1", "lunch"] {
+        assert!(s.parse::<EventKind>().is_err(), "Parsed {}", s);
+    }
+}
+
+quickcheck! {
+    fn check_in_serialize_parse_is_identity(check_in: CheckIn) -> () {
+        let data = check_in.to_data().expect("Failed to serialize");
+        let check_in2 = CheckIn::parse_from(&data).expect("Failed to parse");
+        assert_eq!(check_in, check_in2);
+    }
+}
+
+#[test]
+fn card_hashing() {
+    let fields = ["B1234567", "1234567", "LOVELACE"];
+    let hashed = hash_card("secret", &fields);
+    assert_eq!(hashed, hash_card("secret", &fields));
+    assert_ne!(hashed, hash_card("other secret", &fields));
+    assert!(!hashed.contains("1234567"));
+
+    let hashed = hashed.split('^').collect::<Vec<_>>();
+    assert_eq!(hashed.len(), 3);
+    assert_eq!(hashed[1], hash_card_field("secret", " 1234567 "));
+    assert!(hashed.iter().all(|h| h.len() == 32));
 }
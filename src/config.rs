@@ -3,22 +3,75 @@
 use std::fs::File;
 use std::io::Read;
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use toml::de::from_str as toml_from_str;
 
+use cards::EventKind;
 use errors::{ErrorKind, Result, ResultExt};
+use evdev::InputDevice;
+use keys::PublicKey;
 
 /// A peer's configuration.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(default)]
 pub struct Config {
+    /// The address to serve the HTTP API for inspecting the station on. If
+    /// not present, the API is not served.
+    pub api_addr: Option<SocketAddr>,
+
+    /// The token that must be given, as `Authorization: Bearer <token>`, to
+    /// submit check-ins over the HTTP API. If not present, check-ins can't be
+    /// submitted over it.
+    pub api_token: Option<String>,
+
+    /// The public keys of the stations allowed to sign blocks. If empty, as it
+    /// is by default, blocks signed by any station are accepted, so anything
+    /// that can reach the station can add blocks to its chain.
+    pub authorized_stations: Vec<PublicKey>,
+
+    /// The keyboard-wedge card reader to read swipes from, as the path of its
+    /// `/dev/input/event*` device or as its USB vendor and product IDs in
+    /// hex, like `0801:0002`. The device is grabbed, so its keystrokes don't
+    /// reach anything else. If not present, swipes are read from stdin.
+    pub card_reader: Option<InputDevice>,
+
+    /// A secret shared by all the stations, with which the data read from
+    /// cards is hashed before it's recorded, so the chain doesn't reveal
+    /// U Card IDs. If not present, cards aren't read.
+    pub card_secret: Option<String>,
+
     /// The time to wait, in seconds, between sending discovery pings.
     pub discovery_ping_interval: u64,
 
+    /// The time, in seconds, after an attendee's check-in during which
+    /// another of the same kind is refused as a repeated swipe.
+    pub duplicate_window: u64,
+
+    /// The kind of event recorded by cards read at this station: `arrival`,
+    /// `departure`, `meal:<number>`, `workshop:<ID>` or `swag`.
+    pub event_kind: EventKind,
+
+    /// The address to serve the WebSocket feed of blocks on, at `/feed`. If
+    /// not present, the feed is not served.
+    pub feed_addr: Option<SocketAddr>,
+
+    /// The file the station's keypair is stored in. If it does not exist, a
+    /// new keypair is generated and stored there.
+    pub key_path: PathBuf,
+
     /// The maximum karma value a peer can reach before it is ignored.
     pub max_karma: usize,
 
+    /// The address to serve metrics on, in the Prometheus text format. If not
+    /// present, metrics are not served.
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// A secret shared by all the stations. If present, messages between
+    /// stations are encrypted and authenticated with it, and messages from
+    /// anything without it are dropped.
+    pub network_key: Option<String>,
+
     /// The time to wait, in seconds, between asking peers for status updates.
     pub status_check_interval: u64,
 
@@ -27,6 +80,22 @@ pub struct Config {
 
     /// The port to run on.
     pub port: u16,
+
+    /// The registration CSV to import the attendee registry from. If present,
+    /// cards that don't belong to a registered attendee are flagged when
+    /// they are read. See the `registry` module for the format.
+    pub registry_path: Option<PathBuf>,
+
+    /// The ID of this check-in station, recorded in each check-in.
+    pub station_id: String,
+
+    /// The file to store the blockchain in. If not present, the blockchain is
+    /// only kept in memory.
+    pub storage_path: Option<PathBuf>,
+
+    /// Whether to send messages over TCP. Discovery pings are still broadcast
+    /// over UDP, and the TCP listener uses the same port.
+    pub use_tcp: bool,
 }
 
 impl Config {
@@ -49,11 +118,26 @@ impl Config {
 impl Default for Config {
     fn default() -> Config {
         Config {
+            api_addr: None,
+            api_token: None,
+            authorized_stations: Vec::new(),
+            card_reader: None,
+            card_secret: None,
             discovery_ping_interval: 60,
+            duplicate_window: 60,
+            event_kind: EventKind::Arrival,
+            feed_addr: None,
+            key_path: PathBuf::from("minnehack-check-in.key"),
             max_karma: 10,
+            metrics_addr: None,
+            network_key: None,
             status_check_interval: 30,
             peers: Vec::new(),
             port: 10101,
+            registry_path: None,
+            station_id: "station".to_string(),
+            storage_path: None,
+            use_tcp: false,
         }
     }
 }
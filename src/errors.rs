@@ -3,10 +3,37 @@
 use std::net::SocketAddr;
 use std::path::PathBuf;
 
+use evdev::InputDevice;
 use p2p::Message;
+use presence::Violation;
 
 error_chain!{
     errors {
+        CheckInRefused(violation: Violation) {
+            description("The check-in was refused")
+            display("The check-in was refused: {}", violation)
+        }
+        CheckInTooLarge {
+            description("The check-in is too large to fit in a block")
+            display("The check-in is too large to fit in a block")
+        }
+        CorruptStorage(path: PathBuf) {
+            description("The stored blockchain is corrupt")
+            display("The blockchain stored in {} is corrupt", path.display())
+        }
+        CouldNotGenerateKey {
+            description("Could not generate a keypair")
+            display("Could not generate a keypair")
+        }
+        CouldNotOpenStorage(path: PathBuf) {
+            description("Could not open the blockchain storage")
+            display("Could not open the blockchain storage in {}",
+                    path.display())
+        }
+        CouldNotOpenCardReader(device: InputDevice) {
+            description("Could not open the card reader")
+            display("Could not open the card reader {}", device)
+        }
         CouldNotParseConfig(path: PathBuf) {
             description("Could not parse the config")
             display("Could not parse the config in {}", path.display())
@@ -15,6 +42,15 @@ error_chain!{
             description("Could not read the config")
             display("Could not read the config in {}", path.display())
         }
+        CouldNotReadKey(path: PathBuf) {
+            description("Could not read the keypair")
+            display("Could not read the keypair in {}", path.display())
+        }
+        CouldNotReadRegistry(path: PathBuf) {
+            description("Could not read the attendee registry")
+            display("Could not read the attendee registry in {}",
+                    path.display())
+        }
         CouldNotRecvMessage {
             description("Could not receive a message")
             display("Could not receive a message")
@@ -27,13 +63,59 @@ error_chain!{
             description("Could not serialize a message")
             display("Could not serialize the message {:?}", msg)
         }
+        CouldNotStartApi(addr: SocketAddr) {
+            description("Could not start the API server")
+            display("Could not start the API server on {}", addr)
+        }
+        CouldNotStartFeed(addr: SocketAddr) {
+            description("Could not start the feed server")
+            display("Could not start the feed server on {}", addr)
+        }
         CouldNotStartListener {
             description("Could not start listener")
             display("Could not start listener")
         }
+        CouldNotStartMetrics(addr: SocketAddr) {
+            description("Could not start the metrics server")
+            display("Could not start the metrics server on {}", addr)
+        }
+        CouldNotStartReactor {
+            description("Could not start the reactor")
+            display("Could not start the reactor")
+        }
+        CouldNotWriteKey(path: PathBuf) {
+            description("Could not write the keypair")
+            display("Could not write the keypair to {}", path.display())
+        }
+        CouldNotWriteStorage(path: PathBuf) {
+            description("Could not write to the blockchain storage")
+            display("Could not write to the blockchain storage in {}",
+                    path.display())
+        }
+        GenesisMismatch(path: PathBuf) {
+            description("The stored blockchain has a different genesis block")
+            display("The blockchain stored in {} has a different genesis block",
+                    path.display())
+        }
         InvalidPacket(buf: Vec<u8>) {
             description("Received invalid packet")
             display("Received invalid packet: {:?}", buf)
         }
+        InvalidRegistry(line: usize, reason: String) {
+            description("Invalid attendee registry")
+            display("Invalid attendee registry on line {}: {}", line, reason)
+        }
+        NoCardSecret {
+            description("No card secret is set, so card data can't be recorded")
+            display("No card secret is set, so card data can't be recorded")
+        }
+        NotAuthorized {
+            description("This station is not authorized to mine")
+            display("This station is not authorized to mine")
+        }
+        UnauthenticatedPacket(addr: SocketAddr) {
+            description("Could not authenticate a packet")
+            display("Could not authenticate a packet from {}", addr)
+        }
     }
 }
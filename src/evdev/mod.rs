@@ -0,0 +1,333 @@
+//! Reading swipes from a keyboard-wedge card reader through Linux's evdev
+//! interface, instead of stdin. The reader's device is grabbed, so its
+//! keystrokes go only to the station, whichever window has focus.
+
+#[cfg(test)]
+mod tests;
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs::File;
+use std::io::{ErrorKind as IoErrorKind, Read, Result as IoResult};
+use std::mem::size_of;
+use std::os::raw::c_long;
+use std::path::PathBuf;
+use std::result::Result as StdResult;
+use std::str::FromStr;
+
+use byteorder::{NativeEndian, ReadBytesExt};
+use serde::de::Error as DeError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use errors::{ErrorKind, Result, ResultExt};
+
+/// The size of the `struct timeval` at the start of each event.
+const TIME_LEN: usize = 2 * size_of::<c_long>();
+
+/// The size of a `struct input_event`.
+const EVENT_LEN: usize = TIME_LEN + 8;
+
+/// The event type of key presses and releases.
+const EV_KEY: u16 = 0x01;
+
+const KEY_ENTER: u16 = 28;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_RIGHTSHIFT: u16 = 54;
+const KEY_KPENTER: u16 = 96;
+
+/// The characters typed by each key on a US keyboard layout, without and
+/// with shift.
+const KEYMAP: &[(u16, char, char)] = &[
+    (2, '1', '!'),
+    (3, '2', '@'),
+    (4, '3', '#'),
+    (5, '4', '$'),
+    (6, '5', '%'),
+    (7, '6', '^'),
+    (8, '7', '&'),
+    (9, '8', '*'),
+    (10, '9', '('),
+    (11, '0', ')'),
+    (12, '-', '_'),
+    (13, '=', '+'),
+    (16, 'q', 'Q'),
+    (17, 'w', 'W'),
+    (18, 'e', 'E'),
+    (19, 'r', 'R'),
+    (20, 't', 'T'),
+    (21, 'y', 'Y'),
+    (22, 'u', 'U'),
+    (23, 'i', 'I'),
+    (24, 'o', 'O'),
+    (25, 'p', 'P'),
+    (26, '[', '{'),
+    (27, ']', '}'),
+    (30, 'a', 'A'),
+    (31, 's', 'S'),
+    (32, 'd', 'D'),
+    (33, 'f', 'F'),
+    (34, 'g', 'G'),
+    (35, 'h', 'H'),
+    (36, 'j', 'J'),
+    (37, 'k', 'K'),
+    (38, 'l', 'L'),
+    (39, ';', ':'),
+    (40, '\'', '"'),
+    (41, '`', '~'),
+    (43, '\\', '|'),
+    (44, 'z', 'Z'),
+    (45, 'x', 'X'),
+    (46, 'c', 'C'),
+    (47, 'v', 'V'),
+    (48, 'b', 'B'),
+    (49, 'n', 'N'),
+    (50, 'm', 'M'),
+    (51, ',', '<'),
+    (52, '.', '>'),
+    (53, '/', '?'),
+    (57, ' ', ' '),
+];
+
+/// An input device to read swipes from.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum InputDevice {
+    /// The device at a path, such as `/dev/input/event3`.
+    Path(PathBuf),
+
+    /// The first device with the given USB vendor and product IDs.
+    Id {
+        /// The vendor ID.
+        vendor: u16,
+
+        /// The product ID.
+        product: u16,
+    },
+}
+
+impl<'de> Deserialize<'de> for InputDevice {
+    fn deserialize<D: Deserializer<'de>>(
+        d: D,
+    ) -> StdResult<InputDevice, D::Error> {
+        let s = String::deserialize(d)?;
+        s.parse().map_err(D::Error::custom)
+    }
+}
+
+impl Display for InputDevice {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            InputDevice::Path(ref path) => write!(fmt, "{}", path.display()),
+            InputDevice::Id { vendor, product } => {
+                write!(fmt, "{:04x}:{:04x}", vendor, product)
+            }
+        }
+    }
+}
+
+impl FromStr for InputDevice {
+    type Err = String;
+    fn from_str(s: &str) -> StdResult<InputDevice, String> {
+        if s.contains('/') {
+            return Ok(InputDevice::Path(PathBuf::from(s)));
+        }
+        let id = |id: &str| u16::from_str_radix(id, 16).ok();
+        match s.split_once(':') {
+            Some((vendor, product)) => match (id(vendor), id(product)) {
+                (Some(vendor), Some(product)) => {
+                    Ok(InputDevice::Id { vendor, product })
+                }
+                _ => Err(format!("Invalid vendor and product IDs: {}", s)),
+            },
+            None => Err(format!("Invalid input device: {}", s)),
+        }
+    }
+}
+
+impl Serialize for InputDevice {
+    fn serialize<S: Serializer>(&self, s: S) -> StdResult<S::Ok, S::Error> {
+        s.serialize_str(&self.to_string())
+    }
+}
+
+/// An event read from an input device.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct InputEvent {
+    /// The type of event, such as `EV_KEY`.
+    pub kind: u16,
+
+    /// The key, axis or other code the event is about.
+    pub code: u16,
+
+    /// The event's value. For keys, this is 1 for a press, 0 for a release,
+    /// and 2 for an autorepeat.
+    pub value: i32,
+}
+
+impl InputEvent {
+    /// Reads an event, in the layout of the kernel's `struct input_event`.
+    /// Returns `None` at the end of the input.
+    pub fn read_from<R: Read>(mut r: R) -> IoResult<Option<InputEvent>> {
+        let mut buf = [0; EVENT_LEN];
+        match r.read_exact(&mut buf) {
+            Ok(()) => {}
+            Err(ref err) if err.kind() == IoErrorKind::UnexpectedEof => {
+                return Ok(None)
+            }
+            Err(err) => return Err(err),
+        }
+        let mut buf = &buf[TIME_LEN..];
+        Ok(Some(InputEvent {
+            kind: buf.read_u16::<NativeEndian>()?,
+            code: buf.read_u16::<NativeEndian>()?,
+            value: buf.read_i32::<NativeEndian>()?,
+        }))
+    }
+}
+
+/// Turns key events into the lines they type.
+#[derive(Clone, Debug, Default)]
+pub struct Keyboard {
+    line: String,
+    shift: bool,
+}
+
+impl Keyboard {
+    /// Creates a keyboard with nothing typed, and shift released.
+    pub fn new() -> Keyboard {
+        Keyboard::default()
+    }
+
+    /// Handles an event, returning the line typed if it was ended by Enter.
+    /// Keys that don't type a character are ignored.
+    pub fn handle(&mut self, event: &InputEvent) -> Option<String> {
+        if event.kind != EV_KEY {
+            return None;
+        }
+        match event.code {
+            KEY_LEFTSHIFT | KEY_RIGHTSHIFT => self.shift = event.value != 0,
+            _ if event.value != 1 => {}
+            KEY_ENTER | KEY_KPENTER => return Some(self.line.split_off(0)),
+            code => {
+                let key = KEYMAP.iter().find(|&&(c, _, _)| c == code);
+                if let Some(&(_, c, shifted)) = key {
+                    self.line.push(if self.shift { shifted } else { c });
+                }
+            }
+        }
+        None
+    }
+}
+
+/// An iterator over the lines typed on an input device.
+#[derive(Debug)]
+pub struct Swipes<R> {
+    keyboard: Keyboard,
+    reader: R,
+}
+
+impl<R: Read> Swipes<R> {
+    /// Reads lines typed as the events read from `reader`.
+    pub fn new(reader: R) -> Swipes<R> {
+        Swipes {
+            keyboard: Keyboard::new(),
+            reader,
+        }
+    }
+}
+
+impl<R: Read> Iterator for Swipes<R> {
+    type Item = IoResult<String>;
+
+    fn next(&mut self) -> Option<IoResult<String>> {
+        loop {
+            match InputEvent::read_from(&mut self.reader) {
+                Ok(Some(event)) => if let Some(line) =
+                    self.keyboard.handle(&event)
+                {
+                    return Some(Ok(line));
+                },
+                Ok(None) => return None,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// Opens and grabs an input device.
+#[cfg(target_os = "linux")]
+pub fn open(device: &InputDevice) -> Result<File> {
+    use std::io::Error as IoError;
+    use std::os::unix::io::AsRawFd;
+
+    use libc::{c_int, ioctl};
+
+    /// `EVIOCGRAB`, from `linux/input.h`.
+    const EVIOCGRAB: u32 = 0x4004_4590;
+
+    let err = || ErrorKind::CouldNotOpenCardReader(device.clone());
+    let file = match *device {
+        InputDevice::Path(ref path) => File::open(path).chain_err(err)?,
+        InputDevice::Id { vendor, product } => {
+            find(vendor, product).chain_err(err)?
+        }
+    };
+    let grab: c_int = 1;
+    if unsafe { ioctl(file.as_raw_fd(), EVIOCGRAB as _, grab) } < 0 {
+        return Err(IoError::last_os_error()).chain_err(err);
+    }
+    Ok(file)
+}
+
+/// Opens and grabs an input device.
+#[cfg(not(target_os = "linux"))]
+pub fn open(device: &InputDevice) -> Result<File> {
+    use errors::Error;
+
+    Err(Error::from("Input devices can only be read on Linux"))
+        .chain_err(|| ErrorKind::CouldNotOpenCardReader(device.clone()))
+}
+
+/// Opens the first event device, ordered by path, with the given vendor and
+/// product IDs.
+#[cfg(target_os = "linux")]
+fn find(vendor: u16, product: u16) -> Result<File> {
+    use std::fs::read_dir;
+    use std::os::unix::io::AsRawFd;
+
+    use libc::ioctl;
+
+    /// `EVIOCGID`, from `linux/input.h`.
+    const EVIOCGID: u32 = 0x8008_4502;
+
+    let mut paths = read_dir("/dev/input")
+        .chain_err(|| "Could not list the input devices")?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("event"))
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    for path in paths {
+        // Devices we can't open or query, such as ones we don't have
+        // permission to read, can't be the one we want anyway.
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        // A `struct input_id`: the bus type, vendor, product and version.
+        let mut id = [0u16; 4];
+        if unsafe { ioctl(file.as_raw_fd(), EVIOCGID as _, id.as_mut_ptr()) }
+            < 0
+        {
+            continue;
+        }
+        if id[1] == vendor && id[2] == product {
+            info!("Found the card reader at {}", path.display());
+            return Ok(file);
+        }
+    }
+    Err("No input device has that vendor and product ID".into())
+}
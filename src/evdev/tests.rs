@@ -0,0 +1,81 @@
+use std::path::PathBuf;
+
+use cards::parse_card;
+use evdev::{InputDevice, InputEvent, Keyboard, Swipes};
+
+/// The events read from a card reader's event device as two cards are
+/// swiped, the second of which can't be read, in the layout of a 64-bit
+/// little-endian system.
+#[cfg(all(target_pointer_width = "64", target_endian = "little"))]
+const SWIPES: &[u8] = include_bytes!("fixtures/swipes.bin");
+
+#[test]
+#[cfg(all(target_pointer_width = "64", target_endian = "little"))]
+fn recorded_swipes() {
+    let lines = Swipes::new(SWIPES)
+        .collect::<Result<Vec<_>, _>>()
+        .expect("Failed to read");
+    assert_eq!(
+        lines,
+        [
+            "%B1234567^LOVELACE/ADA^2512101DISC?;1234567=2512101000?",
+            "%E?",
+        ]
+    );
+    let card = parse_card(&lines[0]).expect("Failed to parse");
+    assert_eq!(card.student_id(), Some("1234567"));
+
+    // An unfinished line isn't returned.
+    assert_eq!(Swipes::new(&SWIPES[..1000]).count(), 0);
+}
+
+#[test]
+fn keyboard() {
+    let key = |code, value| InputEvent {
+        kind: 0x01,
+        code,
+        value,
+    };
+    let events = [
+        key(54, 1),
+        key(30, 1),
+        key(30, 0),
+        key(54, 0),
+        key(30, 1),
+        key(30, 2),
+        key(30, 0),
+        key(1, 1),
+        InputEvent {
+            kind: 0x04,
+            code: 0x04,
+            value: 30,
+        },
+        key(42, 1),
+        key(6, 1),
+        key(42, 0),
+        key(96, 1),
+    ];
+    let mut keyboard = Keyboard::new();
+    let lines = events
+        .iter()
+        .filter_map(|event| keyboard.handle(event))
+        .collect::<Vec<_>>();
+    assert_eq!(lines, ["Aa%"]);
+}
+
+#[test]
+fn input_devices() {
+    let path = "/dev/input/event3".parse::<InputDevice>();
+    assert_eq!(path, Ok(InputDevice::Path(PathBuf::from("/dev/input/event3"))));
+    let id = "0801:0002".parse::<InputDevice>();
+    assert_eq!(
+        id,
+        Ok(InputDevice::Id {
+            vendor: 0x0801,
+            product: 0x0002,
+        })
+    );
+    assert_eq!(id.unwrap().to_string(), "0801:0002");
+    assert!("event3".parse::<InputDevice>().is_err());
+    assert!("0801:xyzw".parse::<InputDevice>().is_err());
+}
@@ -0,0 +1,82 @@
+//! Events a `Client` sends to its subscribers as its chain and peers change.
+
+use std::net::SocketAddr;
+
+use blockchain::{Block, Hash};
+use cards::CheckIn;
+use p2p::PeerState;
+use presence::Violation;
+
+/// Something that happened to a `Client`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// A block was appended to the chain, either mined by this station or
+    /// received from a peer.
+    BlockAppended(Block),
+
+    /// A block received from a peer wasn't added to the chain.
+    BlockRejected {
+        /// The peer the block came from.
+        addr: SocketAddr,
+
+        /// The rejected block.
+        block: Block,
+
+        /// Why the block was rejected.
+        reason: RejectReason,
+    },
+
+    /// A check-in at this station was refused, and not mined.
+    CheckInRefused {
+        /// The refused check-in.
+        check_in: CheckIn,
+
+        /// The rule it would have broken.
+        violation: Violation,
+    },
+
+    /// The chain was replaced by its combination with a peer's branch.
+    ForkResolved {
+        /// The peer the branch came from.
+        addr: SocketAddr,
+
+        /// The index at which the peer's branch forked from the chain.
+        fork: u64,
+
+        /// The new length of the chain.
+        len: u64,
+
+        /// The hash of the new tip of the chain.
+        tip: Hash,
+    },
+
+    /// A peer was heard of for the first time.
+    PeerDiscovered(SocketAddr),
+
+    /// A peer's state changed.
+    PeerStateChanged {
+        /// The peer's address.
+        addr: SocketAddr,
+
+        /// The peer's previous state.
+        old: PeerState,
+
+        /// The peer's new state.
+        new: PeerState,
+    },
+
+    /// A peer was sent more messages than its karma allows without
+    /// answering, so it is no longer sent messages until it is heard from.
+    KarmaExceeded(SocketAddr),
+}
+
+/// Why a block was rejected.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RejectReason {
+    /// The block is valid, but conflicts with our chain. The peer's branch is
+    /// fetched to resolve the fork.
+    Conflicting,
+
+    /// The block is invalid, or doesn't follow our chain.
+    Invalid,
+}
@@ -0,0 +1,200 @@
+//! The Ed25519 keys check-in stations sign blocks with.
+
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind as IoErrorKind, Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::result::Result as StdResult;
+use std::str::FromStr;
+
+use crypto::ed25519;
+use rand::{OsRng, Rng};
+use serde::de::Error as DeError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use errors::{ErrorKind, Result, ResultExt};
+
+/// A station's public key.
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct PublicKey(pub [u8; 32]);
+
+/// The all-zero public key, used for the (unsigned) genesis block.
+pub const ZERO_PUBLIC_KEY: PublicKey = PublicKey([0; 32]);
+
+impl PublicKey {
+    /// Checks whether the signature of the message was made by the secret key
+    /// corresponding to this public key.
+    pub fn verify(&self, msg: &[u8], signature: &Signature) -> bool {
+        ed25519::verify(msg, &self.0, &signature.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for PublicKey {
+    fn deserialize<D: Deserializer<'de>>(
+        d: D,
+    ) -> StdResult<PublicKey, D::Error> {
+        String::deserialize(d)?.parse().map_err(D::Error::custom)
+    }
+}
+
+impl Display for PublicKey {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write_hex(&self.0, fmt)
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = String;
+    fn from_str(s: &str) -> StdResult<PublicKey, String> {
+        let mut key = ZERO_PUBLIC_KEY;
+        parse_hex(s, &mut key.0)?;
+        Ok(key)
+    }
+}
+
+impl Serialize for PublicKey {
+    fn serialize<S: Serializer>(&self, s: S) -> StdResult<S::Ok, S::Error> {
+        s.serialize_str(&self.to_string())
+    }
+}
+
+/// An Ed25519 signature.
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Signature(pub [u8; 64]);
+
+/// The all-zero signature, used for the (unsigned) genesis block.
+pub const ZERO_SIGNATURE: Signature = Signature([0; 64]);
+
+impl<'de> Deserialize<'de> for Signature {
+    fn deserialize<D: Deserializer<'de>>(
+        d: D,
+    ) -> StdResult<Signature, D::Error> {
+        let s = String::deserialize(d)?;
+        let mut signature = ZERO_SIGNATURE;
+        parse_hex(&s, &mut signature.0).map_err(D::Error::custom)?;
+        Ok(signature)
+    }
+}
+
+impl Display for Signature {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write_hex(&self.0, fmt)
+    }
+}
+
+impl Serialize for Signature {
+    fn serialize<S: Serializer>(&self, s: S) -> StdResult<S::Ok, S::Error> {
+        s.serialize_str(&self.to_string())
+    }
+}
+
+/// A station's keypair.
+#[derive(Clone)]
+pub struct Keypair {
+    public: PublicKey,
+    secret: [u8; 64],
+    seed: [u8; 32],
+}
+
+impl Keypair {
+    /// Creates a keypair from a 32-byte seed.
+    pub fn from_seed(seed: &[u8; 32]) -> Keypair {
+        let (secret, public) = ed25519::keypair(seed);
+        Keypair {
+            public: PublicKey(public),
+            secret,
+            seed: *seed,
+        }
+    }
+
+    /// Generates a new random keypair.
+    pub fn generate() -> Result<Keypair> {
+        let mut seed = [0; 32];
+        OsRng::new()
+            .chain_err(|| ErrorKind::CouldNotGenerateKey)?
+            .fill_bytes(&mut seed);
+        Ok(Keypair::from_seed(&seed))
+    }
+
+    /// Loads the keypair whose seed is stored in the given file. If the file
+    /// doesn't exist, a new keypair is generated and its seed is stored there.
+    pub fn load_or_generate<P: AsRef<Path>>(path: P) -> Result<Keypair> {
+        let path = path.as_ref();
+        match File::open(path) {
+            Ok(mut file) => {
+                let mut seed = [0; 32];
+                file.read_exact(&mut seed).chain_err(|| {
+                    ErrorKind::CouldNotReadKey(path.to_owned())
+                })?;
+                Ok(Keypair::from_seed(&seed))
+            }
+            Err(ref err) if err.kind() == IoErrorKind::NotFound => {
+                info!("Generating a new keypair in {}", path.display());
+                let keypair = Keypair::generate()?;
+
+                let mut options = OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                options.mode(0o600);
+                options
+                    .open(path)
+                    .and_then(|mut file| {
+                        file.write_all(&keypair.seed)?;
+                        file.sync_all()
+                    })
+                    .chain_err(|| {
+                        ErrorKind::CouldNotWriteKey(path.to_owned())
+                    })?;
+                Ok(keypair)
+            }
+            Err(err) => Err(err)
+                .chain_err(|| ErrorKind::CouldNotReadKey(path.to_owned())),
+        }
+    }
+
+    /// Computes the X25519 shared secret between this keypair and the given
+    /// public key. The holder of the other keypair computes the same secret
+    /// from this keypair's public key.
+    pub fn exchange(&self, public: &PublicKey) -> [u8; 32] {
+        ed25519::exchange(&public.0, &self.secret)
+    }
+
+    /// Returns the public key.
+    pub fn public(&self) -> PublicKey {
+        self.public
+    }
+
+    /// Signs a message.
+    pub fn sign(&self, msg: &[u8]) -> Signature {
+        Signature(ed25519::signature(msg, &self.secret))
+    }
+}
+
+impl Debug for Keypair {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("Keypair")
+            .field("public", &self.public)
+            .finish()
+    }
+}
+
+/// Parses a hex string into the buffer, which it must exactly fill.
+pub(crate) fn parse_hex(s: &str, out: &mut [u8]) -> StdResult<(), String> {
+    if s.len() != out.len() * 2 || !s.is_ascii() {
+        return Err(format!("Expected {} hex digits", out.len() * 2));
+    }
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+            .map_err(|err| err.to_string())?;
+    }
+    Ok(())
+}
+
+fn write_hex(bs: &[u8], fmt: &mut Formatter) -> FmtResult {
+    for b in bs {
+        write!(fmt, "{:02x}", b)?;
+    }
+    Ok(())
+}
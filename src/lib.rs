@@ -3,12 +3,15 @@
 #![warn(missing_docs)]
 
 extern crate arrayvec;
+extern crate base64;
 extern crate byteorder;
 extern crate crossbeam;
 extern crate crypto;
 #[macro_use]
 extern crate error_chain;
 extern crate futures;
+#[cfg(target_os = "linux")]
+extern crate libc;
 #[macro_use]
 extern crate log;
 #[macro_use]
@@ -16,98 +19,293 @@ extern crate nom;
 #[cfg(test)]
 #[macro_use]
 extern crate quickcheck;
+extern crate rand;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate tiny_http;
 extern crate tokio_core;
+extern crate tokio_io;
 extern crate toml;
 
+pub mod api;
 pub mod blockchain;
 pub mod cards;
 mod config;
 mod errors;
+pub mod events;
+pub mod evdev;
+pub mod keys;
+pub mod metrics;
 pub mod p2p;
+pub mod presence;
+mod reactor;
+pub mod registry;
+pub mod storage;
+#[cfg(test)]
+mod tests;
 pub mod util;
 
-use std::collections::HashMap;
+use std::cmp::min;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::net::SocketAddr;
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use std::thread::sleep;
 
 use arrayvec::ArrayVec;
 use crossbeam::{scope, Scope};
-use crossbeam::sync::MsQueue;
 
-use blockchain::{Block, BlockStatus, Chain, Hash};
+use blockchain::{now, Block, BlockStatus, Chain, Hash, Header};
+use cards::{hash_card, CheckIn, EventKind};
 pub use config::Config;
 pub use errors::{Error, ErrorKind, Result, ResultExt};
-use p2p::{Message, P2P, Peer, PeerState};
-use util::log_err;
+use events::{Event, RejectReason};
+use keys::{Keypair, PublicKey};
+use metrics::Metrics;
+use p2p::{
+    Capabilities, Hello, IgnoreReason, Message, P2P, Peer, PeerState,
+    Transport, MAX_BLOCK_RANGE_LEN, MAX_HEADERS, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+use presence::PresenceState;
+use registry::Registry;
+use storage::Storage;
+use util::{log_err, Queue, Shutdown};
+
+/// The longest a thread run by `Client::run_with` waits before checking
+/// whether the client is shutting down.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The most `BlockRangeRequest`s that are sent to a peer before any of them
+/// are answered.
+const SYNC_WINDOW: u64 = 4;
+
+/// The progress of fetching blocks from a peer whose chain is ahead of ours,
+/// or has a branch that competes with ours.
+#[derive(Debug, Default)]
+struct Download {
+    /// The blocks of the peer's branch that have been received, if its chain
+    /// forks from ours.
+    branch: Vec<Block>,
+
+    /// The index at which the peer's chain forks from ours, if it does.
+    fork: Option<u64>,
+
+    /// Received blocks that can't be used until earlier blocks arrive.
+    pending: BTreeMap<u64, Block>,
+
+    /// The index after the last block that has been requested.
+    requested_to: u64,
+}
 
-/// A blockchain client, using the `p2p` module for sending blocks.
+/// A blockchain client, using a `Transport` from the `p2p` module for sending
+/// blocks.
 #[derive(Debug)]
-pub struct Client {
+pub struct Client<T: Transport = P2P> {
+    /// The address to serve the HTTP API on, if any.
+    pub api_addr: Option<SocketAddr>,
+
+    /// The token that must be given to submit check-ins over the HTTP API.
+    /// If not present, check-ins can't be submitted over it.
+    pub api_token: Option<String>,
+
+    /// The secret card data is hashed with before it's recorded. If not
+    /// present, cards can't be recorded.
+    pub card_secret: Option<String>,
+
     /// The time to wait between sending discovery pings.
     pub discovery_ping_interval: Duration,
 
+    /// How long after an attendee's check-in another of the same kind is
+    /// refused as a repeated swipe.
+    pub duplicate_window: Duration,
+
+    /// The kind of event recorded by check-ins at this station, unless
+    /// another is given.
+    pub event_kind: EventKind,
+
+    /// The address to serve the WebSocket feed of blocks on, if any.
+    pub feed_addr: Option<SocketAddr>,
+
     /// The maximum karma value a peer can reach before it is ignored.
     pub max_karma: usize,
 
+    /// The address to serve metrics on, if any.
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// The registered attendees, if a registry was imported.
+    pub registry: Option<Registry>,
+
     /// The time to wait between asking peers for status updates.
     pub status_check_interval: Duration,
 
+    /// The ID of this check-in station, recorded in check-ins submitted over
+    /// the HTTP API.
+    pub station_id: String,
+
     chain: Mutex<Chain>,
-    p2p: P2P,
+    downloads: Mutex<HashMap<SocketAddr, Download>>,
+    hello: Hello,
+    keypair: Keypair,
+    metrics: Metrics,
     peers: Mutex<HashMap<SocketAddr, Peer>>,
-    send_queue: Arc<MsQueue<(Option<SocketAddr>, Message)>>,
+    presence: Mutex<PresenceState>,
+    send_queue: Arc<Queue<(Option<SocketAddr>, Message)>>,
+    shutdown: Shutdown,
+    storage: Option<Mutex<Storage>>,
+    subscribers: Mutex<Vec<Sender<Event>>>,
+    transport: T,
 }
 
-impl Client {
-    /// Creates a new `Client` with the default options.
+impl Client<P2P> {
+    /// Creates a new `Client` with the default options and a newly generated
+    /// keypair.
     pub fn new() -> Result<Client> {
         Client::new_with_opts(
             10101,
             Chain::new(),
+            Keypair::generate()?,
             Duration::from_secs(60),
             Duration::from_secs(30),
             10,
         )
     }
 
-    /// Creates a new `Client` from a `Config`.
+    /// Creates a new `Client` from a `Config`. If the config has a storage
+    /// path, the chain is loaded from it, and new blocks are written to it.
     pub fn new_from_config(config: Config) -> Result<Client> {
-        let mut client = Client::new_with_opts(
-            config.port,
-            Chain::new(),
+        let keypair = Keypair::load_or_generate(&config.key_path)?;
+        info!("This station's public key is {}", keypair.public());
+
+        let mut chain = Chain::new();
+        if config.authorized_stations.is_empty() {
+            warn!("No authorized stations, accepting blocks from any station");
+        } else {
+            let stations = config
+                .authorized_stations
+                .into_iter()
+                .collect::<BTreeSet<_>>();
+            if !stations.contains(&keypair.public()) {
+                warn!("This station is not authorized, and cannot mine");
+            }
+            chain.set_authorized_stations(Some(stations));
+        }
+
+        let registry = match config.registry_path {
+            Some(ref path) => {
+                let mut registry = Registry::load_from(path)?;
+                registry.set_card_secret(config.card_secret.clone());
+                info!("Registry has {} attendees", registry.len());
+                Some(registry)
+            }
+            None => None,
+        };
+
+        let (chain, storage) = match config.storage_path {
+            Some(ref path) => {
+                let (storage, chain) = Storage::open(path, chain)?;
+                (chain, Some(Mutex::new(storage)))
+            }
+            None => (chain, None),
+        };
+        let mut p2p = P2P::with_opts(config.port, config.use_tcp)?;
+        match config.network_key {
+            Some(ref network_key) => {
+                p2p.set_network_key(keypair.clone(), network_key)
+            }
+            None => warn!("No network key, sending messages unencrypted"),
+        }
+        let mut client = Client::with_transport(
+            p2p,
+            chain,
+            keypair,
             Duration::from_secs(config.discovery_ping_interval),
             Duration::from_secs(config.status_check_interval),
             config.max_karma,
-        )?;
+        );
+        client.api_addr = config.api_addr;
+        client.api_token = config.api_token;
+        client.card_secret = config.card_secret;
+        client.duplicate_window = Duration::from_secs(config.duplicate_window);
+        client.event_kind = config.event_kind;
+        client.feed_addr = config.feed_addr;
+        client.metrics_addr = config.metrics_addr;
+        client.registry = registry;
+        client.station_id = config.station_id;
+        client.storage = storage;
         for addr in config.peers {
             client.add_peer(addr);
         }
         Ok(client)
     }
 
-    /// Creates a new `Client`.
+    /// Creates a new `Client`, communicating over UDP.
     pub fn new_with_opts(
         port: u16,
         chain: Chain,
+        keypair: Keypair,
         discovery_ping_interval: Duration,
         status_check_interval: Duration,
         max_karma: usize,
     ) -> Result<Client> {
-        let p2p = P2P::with_port(port)?;
-        Ok(Client {
+        Ok(Client::with_transport(
+            P2P::with_port(port)?,
+            chain,
+            keypair,
+            discovery_ping_interval,
+            status_check_interval,
+            max_karma,
+        ))
+    }
+
+}
+
+impl<T: Transport> Client<T> {
+    /// Creates a new `Client` using the given transport.
+    pub fn with_transport(
+        transport: T,
+        chain: Chain,
+        keypair: Keypair,
+        discovery_ping_interval: Duration,
+        status_check_interval: Duration,
+        max_karma: usize,
+    ) -> Client<T> {
+        let hello = Hello {
+            version: PROTOCOL_VERSION,
+            node_id: keypair.public(),
+            genesis_hash: chain.genesis().hash,
+            capabilities: Capabilities::SUPPORTED,
+        };
+        Client {
+            api_addr: None,
+            api_token: None,
+            card_secret: None,
             discovery_ping_interval,
+            duplicate_window: Duration::from_secs(60),
+            event_kind: EventKind::Arrival,
+            feed_addr: None,
             max_karma,
+            metrics_addr: None,
+            registry: None,
             status_check_interval,
+            station_id: "station".to_string(),
 
             chain: Mutex::new(chain),
-            p2p,
+            downloads: Mutex::new(HashMap::new()),
+            hello,
+            keypair,
+            metrics: Metrics::default(),
             peers: Mutex::new(HashMap::new()),
-            send_queue: Arc::new(MsQueue::new()),
-        })
+            presence: Mutex::new(PresenceState::new()),
+            send_queue: Arc::new(Queue::new()),
+            shutdown: Shutdown::new(),
+            storage: None,
+            subscribers: Mutex::new(Vec::new()),
+            transport,
+        }
     }
 
     /// Adds a peer with the given address.
@@ -115,23 +313,208 @@ impl Client {
         let mut peers = self.peers.lock().unwrap();
         let peer = peers.entry(addr).or_insert_with(|| {
             self.send_queue.push((Some(addr), Message::Ping));
+            self.emit(Event::PeerDiscovered(addr));
             Peer::new(addr)
         });
         peer.karma = 0;
     }
 
-    fn handle_block(&self, block: Block, broadcast: bool) {
+    /// Returns the optional features both we and the given peer support.
+    fn capabilities(&self, addr: SocketAddr) -> Capabilities {
+        let peers = self.peers.lock().unwrap();
+        peers.get(&addr).map(Peer::capabilities).unwrap_or_default()
+    }
+
+    /// Sends an event to all subscribers, forgetting the ones that have gone
+    /// away. No other locks are taken while the subscribers are locked, so
+    /// this can be called with anything locked.
+    fn emit(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Adds the received blocks of the branch being fetched from the given
+    /// peer, and resolves the fork once the branch reaches the peer's tip.
+    /// If the blocks don't form a branch, the peer's chain has presumably
+    /// changed, so the fork is abandoned.
+    fn continue_branch(&self, addr: SocketAddr, fork: u64) {
         let mut chain = self.chain.lock().unwrap();
-        match chain.status(&block) {
+        let tip = match self.peer_tip(addr) {
+            Some(tip) => tip,
+            None => return,
+        };
+
+        let (next, branch) = {
+            let mut downloads = self.downloads.lock().unwrap();
+            let valid = {
+                let download = match downloads.get_mut(&addr) {
+                    Some(download) => download,
+                    None => return,
+                };
+                let mut valid = fork > 0 && fork <= chain.len();
+                while valid {
+                    let next = fork + download.branch.len() as u64;
+                    let block = match download.pending.remove(&next) {
+                        Some(block) => block,
+                        None => break,
+                    };
+                    valid = match download.branch.last() {
+                        Some(prev) => prev.valid_next(&block),
+                        None => {
+                            chain[fork - 1].hash == block.prev_hash
+                                && block.is_valid()
+                        }
+                    };
+                    download.branch.push(block);
+                }
+                valid
+            };
+            if !valid {
+                debug!("Abandoning fork with {}", addr);
+                downloads.remove(&addr);
+                return;
+            }
+
+            let download = downloads.get_mut(&addr).unwrap();
+            let next = fork + download.branch.len() as u64;
+            download.pending = download.pending.split_off(&next);
+            if next > tip {
+                (next, downloads.remove(&addr).map(|d| d.branch))
+            } else {
+                (next, None)
+            }
+        };
+
+        match branch {
+            Some(branch) => self.resolve_fork(&mut chain, addr, fork, branch),
+            None => self.request_blocks(addr, next, tip, false),
+        }
+    }
+
+    /// Adds the received blocks that come after our tip, and requests more
+    /// until our chain reaches the peer's tip.
+    fn continue_download(&self, addr: SocketAddr) {
+        loop {
+            let block = {
+                let len = self.chain.lock().unwrap().len();
+                let mut downloads = self.downloads.lock().unwrap();
+                let download = match downloads.get_mut(&addr) {
+                    Some(download) => download,
+                    None => return,
+                };
+                if download.fork.is_some() {
+                    // A block conflicted with our chain, so the peer's branch
+                    // is being fetched instead.
+                    return;
+                }
+                let idx = match download.pending.keys().next() {
+                    Some(&idx) if idx <= len => idx,
+                    _ => break,
+                };
+                download.pending.remove(&idx).unwrap()
+            };
+            self.handle_block(addr, block, false);
+        }
+
+        let len = self.chain.lock().unwrap().len();
+        match self.peer_tip(addr) {
+            Some(tip) if len <= tip => {
+                self.request_blocks(addr, len, tip, false);
+            }
+            Some(_) => {
+                // Caught up; the peer may have gotten more blocks since.
+                self.downloads.lock().unwrap().remove(&addr);
+                self.send_queue.push((Some(addr), Message::StatusRequest));
+            }
+            None => {
+                self.downloads.lock().unwrap().remove(&addr);
+            }
+        }
+    }
+
+    /// Starts finding where the given peer's chain forks from ours, by asking
+    /// for headers. Peers that don't support headers have their whole chain
+    /// fetched instead, which `Chain::combine` then finds the fork in.
+    fn find_fork_with(&self, chain: &Chain, addr: SocketAddr) {
+        if self.capabilities(addr).contains(Capabilities::HEADERS) {
+            let locator = chain.locator();
+            self.send_queue
+                .push((Some(addr), Message::HeadersRequest(locator)));
+            return;
+        }
+
+        let tip = match self.peer_tip(addr) {
+            Some(tip) => tip,
+            None => return,
+        };
+        let from = {
+            let mut downloads = self.downloads.lock().unwrap();
+            let download = downloads.entry(addr).or_default();
+            if download.fork != Some(1) {
+                *download = Download {
+                    fork: Some(1),
+                    ..Download::default()
+                };
+            }
+            1 + download.branch.len() as u64
+        };
+        self.request_blocks(addr, from, tip, true);
+    }
+
+    fn handle_block(&self, addr: SocketAddr, block: Block, broadcast: bool) {
+        let mut chain = self.chain.lock().unwrap();
+        let status = chain.status(&block);
+        self.metrics.block_received(status);
+        match status {
             // Add it and broadcast it.
             BlockStatus::ValidTip => {
                 debug!("Adding and rebroadcasting block {}", block.hash);
                 assert!(chain.push(block.clone()));
+                self.store(&block);
+                self.emit(Event::BlockAppended(block.clone()));
             }
 
-            // Don't try adding it, but broadcast it.
+            // We're missing the blocks before it, so ask the peer for them.
+            // Don't rebroadcast it, since peers that are just as far behind
+            // would send it right back.
             BlockStatus::PotentiallyValid => {
-                debug!("Not adding (but rebroadcasting) potentially valid block {}", block.hash);
+                debug!(
+                    "Not adding potentially valid block {}, catching up",
+                    block.hash
+                );
+                self.request_blocks(addr, chain.len(), block.index, false);
+                return;
+            }
+
+            // If it's from a peer on our blockchain, it's from a competing
+            // branch, so find where the branch starts. Don't rebroadcast.
+            BlockStatus::Invalid
+                if block.is_valid() && self.is_confirmed(addr) =>
+            {
+                debug!(
+                    "Block {} conflicts with our chain, finding {}'s branch",
+                    block.hash, addr
+                );
+                self.metrics.block_rejected(RejectReason::Conflicting);
+                self.emit(Event::BlockRejected {
+                    addr,
+                    block: block.clone(),
+                    reason: RejectReason::Conflicting,
+                });
+                self.downloads.lock().unwrap().remove(&addr);
+                self.find_fork_with(&chain, addr);
+                return;
+            }
+
+            BlockStatus::Invalid => {
+                debug!("Ignoring invalid block {}", block.hash);
+                self.metrics.block_rejected(RejectReason::Invalid);
+                self.emit(Event::BlockRejected {
+                    addr,
+                    block,
+                    reason: RejectReason::Invalid,
+                });
+                return;
             }
 
             // Ignore it and don't rebroadcast.
@@ -158,6 +541,124 @@ impl Client {
         }
     }
 
+    /// Handles blocks received from a peer in response to a
+    /// `BlockRangeRequest` or, for peers that don't support ranges, a
+    /// `BlockRequest`. Blocks that arrive before the ones preceding them are
+    /// held until they can be used.
+    fn handle_block_range(&self, addr: SocketAddr, blocks: Vec<Block>) {
+        let fork = {
+            let mut downloads = self.downloads.lock().unwrap();
+            let download = match downloads.get_mut(&addr) {
+                Some(download) => download,
+                None => {
+                    debug!("Ignoring unrequested blocks from {}", addr);
+                    return;
+                }
+            };
+            for block in blocks {
+                if block.index < download.requested_to {
+                    download.pending.insert(block.index, block);
+                }
+            }
+            download.fork
+        };
+
+        match fork {
+            Some(fork) => self.continue_branch(addr, fork),
+            None => self.continue_download(addr),
+        }
+    }
+
+    /// Handles headers received from a peer in response to a
+    /// `HeadersRequest`. The first header not on our chain is where the peer's
+    /// chain forks from ours, so blocks are fetched from there.
+    fn handle_headers(&self, addr: SocketAddr, headers: Vec<Header>) {
+        let chain = self.chain.lock().unwrap();
+        let tip = match self.peer_tip(addr) {
+            Some(tip) => tip,
+            None => return,
+        };
+
+        let attached = match headers.first() {
+            Some(first) => {
+                first.index < chain.len()
+                    && chain[first.index].hash == first.hash
+            }
+            None => false,
+        };
+        let consecutive = headers.windows(2).all(|w| w[0].valid_next(&w[1]));
+        if !attached || !consecutive {
+            debug!("Ignoring headers from {} that don't fit our chain", addr);
+            return;
+        }
+
+        let fork = match chain.find_fork_in_headers(&headers) {
+            Some(fork) => fork,
+            None => {
+                // If the response was full, the fork may be past it.
+                if headers.len() == MAX_HEADERS as usize {
+                    let locator = vec![headers[headers.len() - 1].hash];
+                    let msg = Message::HeadersRequest(locator);
+                    self.send_queue.push((Some(addr), msg));
+                }
+                return;
+            }
+        };
+        let fork = if fork == chain.len() { None } else { Some(fork) };
+
+        let from = {
+            let mut downloads = self.downloads.lock().unwrap();
+            let download = downloads.entry(addr).or_default();
+            if download.fork != fork {
+                *download = Download {
+                    fork,
+                    ..Download::default()
+                };
+            }
+            match fork {
+                Some(fork) => fork + download.branch.len() as u64,
+                None => chain.len(),
+            }
+        };
+        self.request_blocks(addr, from, tip, true);
+    }
+
+    /// Handles a peer's half of the handshake, ignoring the peer if we can't
+    /// talk to it. The first time a peer's `Hello` is received, ours is sent
+    /// back, since the peer may not have gotten it.
+    fn handle_hello(&self, addr: SocketAddr, hello: Hello) {
+        let reason = if hello.node_id == self.hello.node_id {
+            Some(IgnoreReason::Ourselves)
+        } else if hello.version < MIN_PROTOCOL_VERSION {
+            Some(IgnoreReason::UnsupportedVersion(hello.version))
+        } else if hello.genesis_hash != self.hello.genesis_hash {
+            Some(IgnoreReason::OtherBlockchain)
+        } else {
+            None
+        };
+
+        let (first, confirmed) = {
+            let mut peers = self.peers.lock().unwrap();
+            let peer = peers.entry(addr).or_insert_with(|| Peer::new(addr));
+            if let Some(reason) = reason {
+                self.ignore_peer(peer, reason);
+                return;
+            }
+            let first = peer.hello.is_none();
+            peer.hello = Some(hello);
+            (first, peer.same_blockchain())
+        };
+        if first {
+            self.send_queue
+                .push((Some(addr), Message::Hello(self.hello)));
+            // Syncing may have started before the peer's capabilities were
+            // known.
+            if confirmed {
+                self.sync_with_peer(addr);
+            }
+        }
+    }
+
     fn handle_peer_status(
         &self,
         addr: SocketAddr,
@@ -168,14 +669,19 @@ impl Client {
         let sync = {
             let chain = self.chain.lock().unwrap();
             let mut peers = self.peers.lock().unwrap();
+            let peer = peers.entry(addr).or_insert_with(|| Peer::new(addr));
 
+            match peer.state {
+                PeerState::Ignore(IgnoreReason::OtherBlockchain) => {}
+                PeerState::Ignore(_) => return,
+                _ => {}
+            }
             if chain.genesis().hash == genesis_hash {
-                peers.entry(addr).or_insert_with(|| Peer::new(addr)).state =
-                    PeerState::Confirmed(tip_index, tip_hash);
+                let state = PeerState::Confirmed(tip_index, tip_hash);
+                self.set_peer_state(peer, state);
                 true
             } else {
-                peers.entry(addr).or_insert_with(|| Peer::new(addr)).state =
-                    PeerState::Ignore;
+                self.ignore_peer(peer, IgnoreReason::OtherBlockchain);
                 false
             }
         };
@@ -184,6 +690,116 @@ impl Client {
         }
     }
 
+    /// Handles a message received from a peer. `run_with` does this for each
+    /// message received; this is for driving a `Client` without it.
+    pub fn handle_message(&self, addr: SocketAddr, msg: Message) {
+        debug!("{} sent {:?}", addr, msg);
+        self.metrics.messages_received[msg.kind()].inc();
+        self.add_peer(addr);
+        match msg {
+            Message::Ping => {
+                self.send_queue.push((Some(addr), Message::Pong));
+            }
+            Message::Pong => {
+                self.mark_peer_exists(addr);
+            }
+            Message::PeerRequest => {
+                let peers = self.peers.lock().unwrap();
+                let peers = peers
+                    .values()
+                    .filter(|p| p.same_blockchain())
+                    .map(|p| p.addr)
+                    .take(8)
+                    .collect();
+                self.send_queue
+                    .push((Some(addr), Message::PeerResponse(peers)));
+            }
+            Message::PeerResponse(peers) => {
+                peers.into_iter().for_each(|addr| self.add_peer(addr))
+            }
+            Message::StatusRequest => {
+                let chain = self.chain.lock().unwrap();
+                let gh = chain.genesis().hash;
+                let tip = chain.tip();
+                let ti = tip.index;
+                let th = tip.hash;
+                let msg = Message::StatusResponse(gh, ti, th);
+                self.send_queue.push((Some(addr), msg));
+            }
+            Message::StatusResponse(gh, ti, th) => {
+                self.handle_peer_status(addr, gh, ti, th);
+            }
+            Message::BlockRequest(idx) => {
+                let chain = self.chain.lock().unwrap();
+                if idx < chain.len() {
+                    self.send_queue.push((
+                        Some(addr),
+                        Message::BlockResponse(chain[idx].clone()),
+                    ));
+                }
+            }
+            Message::BlockResponse(block) => {
+                let downloading =
+                    self.downloads.lock().unwrap().contains_key(&addr);
+                if downloading {
+                    self.handle_block_range(addr, vec![block]);
+                } else {
+                    self.handle_block(addr, block, false);
+                }
+            }
+            Message::BlockAnnounce(block) => {
+                self.handle_block(addr, block, true);
+            }
+            Message::BlockRangeRequest(start, count) => {
+                let chain = self.chain.lock().unwrap();
+                let count = u64::from(min(count, MAX_BLOCK_RANGE_LEN));
+                let end = min(start.saturating_add(count), chain.len());
+                if start < end {
+                    let blocks =
+                        (start..end).map(|i| chain[i].clone()).collect();
+                    let msg = Message::BlockRangeResponse(blocks);
+                    self.send_queue.push((Some(addr), msg));
+                }
+            }
+            Message::BlockRangeResponse(blocks) => {
+                self.handle_block_range(addr, blocks);
+            }
+            Message::HeadersRequest(locator) => {
+                let chain = self.chain.lock().unwrap();
+                if let Some(start) = chain.locate(&locator) {
+                    let headers =
+                        chain.headers(start, u64::from(MAX_HEADERS));
+                    let msg = Message::HeadersResponse(headers);
+                    self.send_queue.push((Some(addr), msg));
+                }
+            }
+            Message::HeadersResponse(headers) => {
+                self.handle_headers(addr, headers);
+            }
+            Message::Hello(hello) => {
+                self.handle_hello(addr, hello);
+            }
+        }
+    }
+
+    /// Ignores the peer for the given reason.
+    fn ignore_peer(&self, peer: &mut Peer, reason: IgnoreReason) {
+        if peer.state != PeerState::Ignore(reason) {
+            info!("Ignoring {}, since {}", peer.addr, reason);
+            self.set_peer_state(peer, PeerState::Ignore(reason));
+        }
+    }
+
+    fn is_confirmed(&self, addr: SocketAddr) -> bool {
+        let peers = self.peers.lock().unwrap();
+        peers.get(&addr).map(Peer::same_blockchain).unwrap_or(false)
+    }
+
+    /// Returns whether the client has been asked to stop.
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.is_set()
+    }
+
     fn mark_peer_exists(&self, addr: SocketAddr) {
         let mut peers = self.peers.lock().unwrap();
         let peer = peers.entry(addr).or_insert_with(|| {
@@ -194,167 +810,401 @@ impl Client {
             peer.state = PeerState::Existent;
             peer
         });
-        if peer.state == PeerState::Speculative {
-            peer.state = PeerState::Existent;
+        // Until we have the peer's half of the handshake and know what
+        // blockchain it's on, keep asking, in case an earlier request or
+        // response was lost.
+        match peer.state {
+            PeerState::Ignore(_) => {}
+            PeerState::Speculative | PeerState::Existent => {
+                self.set_peer_state(peer, PeerState::Existent);
+                if peer.hello.is_none() {
+                    self.send_queue
+                        .push((Some(addr), Message::Hello(self.hello)));
+                }
+                self.send_queue.push((Some(addr), Message::StatusRequest));
+            }
+            PeerState::Confirmed(_, _) => if peer.hello.is_none() {
+                self.send_queue
+                    .push((Some(addr), Message::Hello(self.hello)));
+            },
+        }
+    }
+
+    /// Requests blocks from the given peer, starting at `from` and up to its
+    /// tip, keeping up to `SYNC_WINDOW` ranges in flight, or single blocks
+    /// for peers that don't support ranges. If `restart` is true, ranges that
+    /// are already in flight are requested again, in case they were lost.
+    fn request_blocks(
+        &self,
+        addr: SocketAddr,
+        from: u64,
+        tip: u64,
+        restart: bool,
+    ) {
+        let ranges = self.capabilities(addr)
+            .contains(Capabilities::BLOCK_RANGES);
+        let mut downloads = self.downloads.lock().unwrap();
+        let download = downloads.entry(addr).or_default();
+        if restart || download.requested_to < from {
+            download.requested_to = from;
+        }
+
+        let max_len = if ranges {
+            u64::from(MAX_BLOCK_RANGE_LEN)
+        } else {
+            1
+        };
+        let end = min(tip + 1, from + SYNC_WINDOW * max_len);
+        while download.requested_to < end {
+            let start = download.requested_to;
+            let count = min(end - start, max_len);
+            let msg = if ranges {
+                Message::BlockRangeRequest(start, count as u8)
+            } else {
+                Message::BlockRequest(start)
+            };
+            self.send_queue.push((Some(addr), msg));
+            download.requested_to += count;
+        }
+    }
+
+    /// Asks all peers for their status.
+    pub fn request_status(&self) {
+        debug!("Asking peers for status updates...");
+        self.send_queue.push((None, Message::StatusRequest));
+    }
+
+    /// Replaces our chain with the combination of it and the branch fetched
+    /// from the given peer. Since `Chain::combine` is deterministic, the peer
+    /// will arrive at the same chain when it does the same with our branch.
+    /// If our chain already had everything on the branch, there's nothing
+    /// more to get from the peer until it fetches our branch.
+    fn resolve_fork(
+        &self,
+        chain: &mut Chain,
+        addr: SocketAddr,
+        fork: u64,
+        branch: Vec<Block>,
+    ) {
+        // Our chain was validated as it was built, so only the branch needs
+        // to be.
+        if !chain.valid_branch(fork, &branch) {
+            warn!("{} sent an invalid branch", addr);
+            return;
+        }
+
+        if chain.combine_branch(fork, branch) {
+            info!(
+                "Resolved fork with {}, tip is now {}",
+                addr,
+                chain.tip().hash
+            );
+            if let Some(ref storage) = self.storage {
+                log_err(storage.lock().unwrap().replace(chain));
+            }
+            self.metrics.forks_resolved.inc();
+            self.emit(Event::ForkResolved {
+                addr,
+                fork,
+                len: chain.len(),
+                tip: chain.tip().hash,
+            });
             self.send_queue.push((Some(addr), Message::StatusRequest));
         }
     }
 
+    /// Starts fetching blocks from the peer if its tip isn't on our chain.
     fn sync_with_peer(&self, addr: SocketAddr) {
         let chain = self.chain.lock().unwrap();
-        let peers = self.peers.lock().unwrap();
+        let state = self.peers.lock().unwrap()[&addr].state;
+        if let PeerState::Confirmed(i, h) = state {
+            if i >= chain.len() || chain[i].hash != h {
+                self.find_fork_with(&chain, addr);
+            }
+        }
+    }
 
-        let peer = peers[&addr];
-        if let PeerState::Confirmed(i, _) = peer.state {
-            let l = chain.len();
-            if i >= l {
-                self.send_queue.push((Some(addr), Message::BlockRequest(l)));
-                self.send_queue.push((Some(addr), Message::StatusRequest));
+    /// Returns the identifier recorded in check-ins for a card, given the
+    /// fields that identify it, such as its student ID. The fields are hashed
+    /// with the `card_secret`, so without one, cards can't be recorded.
+    pub fn card_attendee(&self, fields: &[&str]) -> Result<String> {
+        match self.card_secret {
+            Some(ref secret) => Ok(hash_card(secret, fields)),
+            None => Err(ErrorKind::NoCardSecret.into()),
+        }
+    }
+
+    /// Records a check-in by the attendee at this station, and returns the
+    /// block it was mined in. The check-in is refused if it breaks a rule for
+    /// its kind, such as claiming a meal twice, or if the attendee made a
+    /// check-in of the same kind within the `duplicate_window`.
+    pub fn check_in(&self, attendee: &str, kind: EventKind) -> Result<Block> {
+        let check_in =
+            CheckIn::new(attendee.to_string(), self.station_id.clone(), kind);
+        let data = check_in.to_data().ok_or(ErrorKind::CheckInTooLarge)?;
+
+        // The chain stays locked until the block is mined, so another
+        // check-in can't break the rules in the meantime.
+        let mut chain = self.chain.lock().unwrap();
+        {
+            let mut presence = self.presence.lock().unwrap();
+            presence.update(&chain);
+            let window = self.duplicate_window.as_secs();
+            if let Err(violation) = presence.check(&check_in, window) {
+                self.metrics.check_ins_refused.inc();
+                self.emit(Event::CheckInRefused {
+                    check_in,
+                    violation: violation.clone(),
+                });
+                return Err(ErrorKind::CheckInRefused(violation).into());
             }
         }
+        self.mine_onto(&mut chain, check_in.timestamp, data)
+            .ok_or_else(|| ErrorKind::NotAuthorized.into())
+    }
+
+    /// Mines a new block with the given data, signed with this station's
+    /// keypair, and returns it. Does nothing if this station is not
+    /// authorized.
+    pub fn mine(&self, data: ArrayVec<[u8; 256]>) -> Option<Block> {
+        self.mine_at(now(), data)
     }
 
-    /// Mines a new block with the given data.
-    pub fn mine(&self, data: ArrayVec<[u8; 256]>) {
+    /// Mines a new block with the given data and timestamp, signed with this
+    /// station's keypair, and returns it. Does nothing if this station is not
+    /// authorized, and returns the block already mined if there is one with
+    /// the same timestamp and data.
+    pub fn mine_at(
+        &self,
+        timestamp: u64,
+        data: ArrayVec<[u8; 256]>,
+    ) -> Option<Block> {
         let mut chain = self.chain.lock().unwrap();
-        let block = chain.mine(data);
+        self.mine_onto(&mut chain, timestamp, data)
+    }
+
+    /// Mines a new block onto the chain, which must be this client's.
+    fn mine_onto(
+        &self,
+        chain: &mut Chain,
+        timestamp: u64,
+        data: ArrayVec<[u8; 256]>,
+    ) -> Option<Block> {
+        if !chain.is_authorized(&self.keypair.public()) {
+            error!("Not mining block, this station is not authorized");
+            return None;
+        }
+        let signer = self.keypair.public();
+        if let Some(block) = chain.find_signed(&signer, timestamp, &data) {
+            info!("Not mining block, block {} has the same data", block.hash);
+            return Some(block.clone());
+        }
+
+        let block = chain.mine_at(timestamp, data, &self.keypair);
         info!("Mined block {}", block.hash);
+        self.metrics.blocks_mined.inc();
+        self.store(block);
 
         self.send_queue
             .push((None, Message::BlockAnnounce(block.clone())));
+        self.emit(Event::BlockAppended(block.clone()));
+        Some(block.clone())
     }
 
-    /// Runs the `Client` alongside the threads spawned by `spawn_others`.
+    /// Returns the tip index of the given peer, if it is confirmed to be on
+    /// our blockchain.
+    fn peer_tip(&self, addr: SocketAddr) -> Option<u64> {
+        let peers = self.peers.lock().unwrap();
+        match peers.get(&addr).map(|peer| peer.state) {
+            Some(PeerState::Confirmed(tip, _)) => Some(tip),
+            _ => None,
+        }
+    }
+
+    /// Returns the counters of what this client has done.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Returns this station's public key.
+    pub fn public_key(&self) -> PublicKey {
+        self.keypair.public()
+    }
+
+    /// Appends a block to the storage, if there is any. This should be called
+    /// with the chain locked, so blocks are stored in order.
+    fn store(&self, block: &Block) {
+        if let Some(ref storage) = self.storage {
+            log_err(storage.lock().unwrap().append(block));
+        }
+    }
+
+    /// Runs the `Client` on a reactor on the current thread, alongside the
+    /// threads spawned by `spawn_others`, until it is shut down. Then, once
+    /// those threads have returned too, the messages still queued are sent,
+    /// the storage is flushed, and this returns.
     pub fn run_with<F>(&self, spawn_others: F)
     where
-        F: FnOnce(&Scope, Arc<MsQueue<(Option<SocketAddr>, Message)>>),
+        F: FnOnce(&Scope, Arc<Queue<(Option<SocketAddr>, Message)>>),
     {
         scope(|scope| {
-            scope.spawn(|| loop {
-                // Sender thread
-                let (addr, msg) = self.send_queue.pop();
-                let mut peers = self.peers.lock().unwrap();
-                if let Some(addr) = addr {
-                    let peer =
-                        peers.entry(addr).or_insert_with(|| Peer::new(addr));
-                    peer.karma += 1;
-                    if peer.karma > self.max_karma {
-                        peer.state = PeerState::Speculative;
-                    } else {
-                        log_err(self.p2p.send(addr, &msg));
-                    }
-                } else {
-                    let peers = peers.values().filter(|p| p.same_blockchain());
-                    for peer in peers {
-                        log_err(self.p2p.send(peer.addr, &msg));
-                    }
-                }
-            });
-            scope.spawn(|| loop {
-                // Receiver thread
-                match self.p2p.recv() {
-                    Ok((addr, msg)) => {
-                        debug!("{} sent {:?}", addr, msg);
-                        self.add_peer(addr);
-                        match msg {
-                            Message::Ping => {
-                                self.send_queue
-                                    .push((Some(addr), Message::Pong));
-                            }
-                            Message::Pong => {
-                                self.mark_peer_exists(addr);
-                            }
-                            Message::PeerRequest => {
-                                let peers = self.peers.lock().unwrap();
-                                let peers = peers
-                                    .values()
-                                    .filter(|p| p.same_blockchain())
-                                    .map(|p| p.addr)
-                                    .take(8)
-                                    .collect();
-                                self.send_queue.push((
-                                    Some(addr),
-                                    Message::PeerResponse(peers),
-                                ));
-                            }
-                            Message::PeerResponse(peers) => peers
-                                .into_iter()
-                                .for_each(|addr| self.add_peer(addr)),
-                            Message::StatusRequest => {
-                                let chain = self.chain.lock().unwrap();
-                                let gh = chain.genesis().hash;
-                                let tip = chain.tip();
-                                let ti = tip.index;
-                                let th = tip.hash;
-                                let msg = Message::StatusResponse(gh, ti, th);
-                                self.send_queue.push((Some(addr), msg));
-                            }
-                            Message::StatusResponse(gh, ti, th) => {
-                                self.handle_peer_status(addr, gh, ti, th);
-                            }
-                            Message::BlockRequest(idx) => {
-                                let chain = self.chain.lock().unwrap();
-                                if idx < chain.len() {
-                                    self.send_queue.push((
-                                        Some(addr),
-                                        Message::BlockResponse(
-                                            chain[idx].clone(),
-                                        ),
-                                    ));
-                                }
-                            }
-                            Message::BlockResponse(block) => {
-                                self.handle_block(block, false);
-                            }
-                            Message::BlockAnnounce(block) => {
-                                self.handle_block(block, true);
-                            }
-                        }
-                    }
-                    Err(err) => {
-                        log_err(Err(err));
-                    }
-                }
-            });
-            scope.spawn(|| loop {
-                // Discovery thread
-                debug!("Sending discovery ping...");
-                log_err(self.p2p.send_discovery());
-                sleep(self.discovery_ping_interval);
-            });
-            scope.spawn(|| loop {
-                // Status check thread
-                debug!("Asking peers for status updates...");
-                self.send_queue.push((None, Message::StatusRequest));
-                sleep(self.status_check_interval);
-            });
+            if let Some(addr) = self.api_addr {
+                scope.spawn(move || log_err(api::serve(self, addr)));
+            }
+            if let Some(addr) = self.feed_addr {
+                scope.spawn(move || log_err(api::feed::serve(self, addr)));
+            }
+            if let Some(addr) = self.metrics_addr {
+                scope.spawn(move || log_err(metrics::serve(self, addr)));
+            }
             spawn_others(scope, self.send_queue.clone());
-        })
+
+            if log_err(self.run_reactor()) {
+                self.shutdown();
+            }
+            // Threads waiting for events return once their subscriptions end.
+            self.subscribers.lock().unwrap().clear();
+        });
+
+        self.send_pending();
+        if let Some(ref storage) = self.storage {
+            log_err(storage.lock().unwrap().sync());
+        }
+        info!("Shut down");
     }
 
-    /// Runs the `Client` alongside the thread given by the function.
+    /// Runs the `Client` alongside the thread given by the function. The
+    /// thread should return once the client is shutting down.
     pub fn run_with_one<F>(&self, thread: F)
     where
-        F: 'static + FnOnce(&MsQueue<(Option<SocketAddr>, Message)>) + Send,
+        F: 'static + FnOnce(&Queue<(Option<SocketAddr>, Message)>) + Send,
     {
         self.run_with(|scope, send_queue| {
             scope.spawn(move || thread(&send_queue));
         })
     }
 
+    /// Sends a message, or broadcasts it to all peers on the same blockchain
+    /// if no address is given. Sending a message to a peer expends its karma.
+    fn send(&self, addr: Option<SocketAddr>, msg: Message) {
+        // The peers lock is released before sending, so a slow transport
+        // doesn't hold up everything else that needs the peers.
+        let addrs = {
+            let mut peers = self.peers.lock().unwrap();
+            if let Some(addr) = addr {
+                let peer =
+                    peers.entry(addr).or_insert_with(|| Peer::new(addr));
+                peer.karma += 1;
+                if peer.karma > self.max_karma {
+                    if peer.karma == self.max_karma + 1 {
+                        self.emit(Event::KarmaExceeded(addr));
+                    }
+                    self.set_peer_state(peer, PeerState::Speculative);
+                    Vec::new()
+                } else {
+                    vec![addr]
+                }
+            } else {
+                peers
+                    .values()
+                    .filter(|p| p.same_blockchain())
+                    .map(|p| p.addr)
+                    .collect()
+            }
+        };
+        for addr in addrs {
+            self.send_to(addr, &msg);
+        }
+    }
+
+    /// Sends a message to a peer over the transport, counting it.
+    fn send_to(&self, addr: SocketAddr, msg: &Message) {
+        if log_err(self.transport.send(addr, msg)) {
+            self.metrics.send_errors.inc();
+        } else {
+            self.metrics.messages_sent[msg.kind()].inc();
+        }
+    }
+
+    /// Sets the peer's state, telling subscribers if it changed.
+    fn set_peer_state(&self, peer: &mut Peer, state: PeerState) {
+        if peer.state != state {
+            self.emit(Event::PeerStateChanged {
+                addr: peer.addr,
+                old: peer.state,
+                new: state,
+            });
+            peer.state = state;
+        }
+    }
+
+    /// Asks the client to stop. See `run_with` for what happens then.
+    pub fn shutdown(&self) {
+        info!("Shutting down...");
+        self.shutdown.set();
+    }
+
+    /// Returns a handle that shuts down the client when set, which can be
+    /// kept by something that doesn't have the client, like a signal
+    /// handler.
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Sends a discovery ping.
+    pub fn send_discovery(&self) {
+        debug!("Sending discovery ping...");
+        log_err(self.transport.send_discovery());
+    }
+
+    /// Sends all queued messages. `run_with` sends messages as they are
+    /// queued; this is for driving a `Client` without it.
+    pub fn send_pending(&self) {
+        while let Some((addr, msg)) = self.send_queue.try_pop() {
+            self.send(addr, msg);
+        }
+    }
+
+    /// Returns a receiver for the events that happen from now on. The
+    /// receiver should be drained promptly, since events are queued for it
+    /// until it is dropped.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Returns the transport used to exchange messages with peers.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
     /// Runs the given closure with the blockchain as an argument.
-    pub fn with_chain<F, T>(&self, f: F) -> T
+    pub fn with_chain<F, R>(&self, f: F) -> R
     where
-        F: FnOnce(&Chain) -> T,
+        F: FnOnce(&Chain) -> R,
     {
         let chain = self.chain.lock().unwrap();
         f(&chain)
     }
 
+    /// Runs the given closure with the presence of attendees, as of the tip
+    /// of the chain, as an argument. Only the blocks appended since the last
+    /// call are replayed, unless a fork was resolved since.
+    pub fn with_presence<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&PresenceState) -> R,
+    {
+        let chain = self.chain.lock().unwrap();
+        let mut presence = self.presence.lock().unwrap();
+        presence.update(&chain);
+        f(&presence)
+    }
+
     /// Runs the given closure with the peer list as an argument.
-    pub fn with_peers<F, T>(&self, f: F) -> T
+    pub fn with_peers<F, R>(&self, f: F) -> R
     where
-        F: FnOnce(&HashMap<SocketAddr, Peer>) -> T,
+        F: FnOnce(&HashMap<SocketAddr, Peer>) -> R,
     {
         let peers = self.peers.lock().unwrap();
         f(&peers)
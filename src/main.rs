@@ -1,6 +1,6 @@
-extern crate arrayvec;
 #[macro_use]
 extern crate clap;
+extern crate ctrlc;
 extern crate dotenv;
 extern crate error_chain;
 #[macro_use]
@@ -10,30 +10,45 @@ extern crate pretty_env_logger;
 extern crate serde_cbor;
 extern crate toml;
 
-use std::io::{stdin, BufRead, BufReader, Write};
+use std::cmp::min;
+use std::io::{stdin, BufRead, BufReader, Result as IoResult};
 use std::process::exit;
 use std::sync::Arc;
-
-use arrayvec::ArrayVec;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
 
 use error_chain::ChainedError;
 use minnehack_check_in::{Client, Config};
-use minnehack_check_in::cards::{parse_card, CardParse};
+use minnehack_check_in::cards::parse_card;
+use minnehack_check_in::evdev::{self, InputDevice, Swipes};
+use minnehack_check_in::registry::Registry;
+
+/// The longest time to wait before opening the card reader again.
+const MAX_REOPEN_DELAY: Duration = Duration::from_secs(60);
 
 fn main() {
     dotenv::dotenv().ok();
     pretty_env_logger::init().unwrap();
 
-    clap_app!((crate_name!()) =>
+    let matches = clap_app!((crate_name!()) =>
         (about: crate_description!())
         (author: crate_authors!())
         (version: crate_version!())
+        (@subcommand attendee =>
+            (about: "Looks up attendees in the registry")
+            (@arg QUERY: +required "A U Card ID, or part of a name or email"))
     ).get_matches();
 
+    let config =
+        Config::load_from("minnehack-check-in.toml").unwrap_or_default();
+    if let Some(matches) = matches.subcommand_matches("attendee") {
+        let query = matches.value_of("QUERY").unwrap();
+        exit(find_attendees(&config, query));
+    }
+
     info!("Starting up...");
-    let client = match Client::new_from_config(
-        Config::load_from("minnehack-check-in.toml").unwrap_or_default(),
-    ) {
+    let card_reader = config.card_reader.clone();
+    let client = match Client::new_from_config(config) {
         Ok(val) => Arc::new(val),
         Err(err) => {
             error!("{}", err.display_chain());
@@ -42,29 +57,127 @@ fn main() {
         }
     };
 
-    client.clone().run_with_one(move |_queue| {
-        let mut stdin = BufReader::new(stdin());
-        let mut line = String::new();
-        loop {
-            line.clear();
-            stdin.read_line(&mut line).unwrap();
+    let shutdown = client.shutdown_handle();
+    if let Err(err) = ctrlc::set_handler(move || shutdown.set()) {
+        warn!("Could not install a signal handler: {}", err);
+    }
+
+    // Reading cards blocks, so the card reader isn't run by the client,
+    // which waits for its threads to return before shutting down. Running
+    // out of cards doesn't stop the station; only a signal does.
+    if client.card_secret.is_none() {
+        error!("No card_secret is set in the config, so cards won't be read");
+    } else {
+        let reader = client.clone();
+        spawn(move || match card_reader {
+            Some(device) => read_card_reader(&reader, &device),
+            None => read_cards(&reader, BufReader::new(stdin()).lines()),
+        });
+    }
+    client.run_with(|_, _| {});
+}
+
+/// Prints the attendees in the registry matching the query, returning the
+/// exit status.
+fn find_attendees(config: &Config, query: &str) -> i32 {
+    let registry = match config.registry_path {
+        Some(ref path) => match Registry::load_from(path) {
+            Ok(registry) => registry,
+            Err(err) => {
+                error!("{}", err.display_chain());
+                return 1;
+            }
+        },
+        None => {
+            error!("No registry_path is set in the config");
+            return 1;
+        }
+    };
+
+    let attendees = registry.search(query);
+    if attendees.is_empty() {
+        println!("No attendees match {:?}", query);
+        return 1;
+    }
+    for attendee in attendees {
+        println!("{}", attendee.name);
+        println!("  Email:     {}", attendee.email);
+        println!("  U Card ID: {}", attendee.ucard_id);
+        println!("  Dietary:   {}", attendee.dietary);
+    }
+    0
+}
 
-            match parse_card(&line) {
-                CardParse::Card(fields) => {
-                    let mut buf = ArrayVec::<[u8; 256]>::new();
-                    let l = fields.len();
-                    assert!(l < 256);
-                    buf.push(l as u8);
-                    for field in fields.iter() {
-                        let l = field.len();
-                        assert!(l < 256);
-                        buf.push(l as u8);
-                        buf.write_all(field.as_bytes()).unwrap();
-                    }
-                    client.mine(buf);
-                }
-                err => error!("Error reading card: {:?}", err),
+/// Records a check-in for each card swiped on the card reader. If it can't be
+/// opened, or stops being readable, it's opened again after a delay that
+/// grows each time.
+fn read_card_reader(client: &Client, device: &InputDevice) {
+    let mut delay = Duration::from_secs(1);
+    while !client.is_shutting_down() {
+        match evdev::open(device) {
+            Ok(file) => {
+                info!("Reading cards from {}", device);
+                delay = Duration::from_secs(1);
+                read_cards(client, Swipes::new(file));
             }
+            Err(err) => error!("{}", err.display_chain()),
+        }
+        info!("Opening {} again in {}s", device, delay.as_secs());
+        sleep(delay);
+        delay = min(delay * 2, MAX_REOPEN_DELAY);
+    }
+}
+
+/// Records a check-in for each card read, until there are no more lines.
+fn read_cards<I>(client: &Client, lines: I)
+where
+    I: IntoIterator<Item = IoResult<String>>,
+{
+    for line in lines {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                error!("Could not read a card: {}", err);
+                return;
+            }
+        };
+
+        let card = match parse_card(&line) {
+            Ok(card) => card,
+            Err(err) => {
+                error!("Error reading card: {}", err);
+                continue;
+            }
+        };
+        let id = match (card.student_id(), card.account_number()) {
+            (Some(id), _) => id,
+            (None, Some(number)) => {
+                warn!("Not a U Card, using its account number");
+                number
+            }
+            (None, None) => {
+                error!("Error reading card: No account number was read");
+                continue;
+            }
+        };
+
+        let attendee = match client.card_attendee(&[id]) {
+            Ok(attendee) => attendee,
+            Err(err) => {
+                error!("Error reading card: {}", err);
+                continue;
+            }
+        };
+        if let Some(ref registry) = client.registry {
+            match registry.lookup_card(&[id]) {
+                Some(attendee) => info!("Checking in {}", attendee.name),
+                None => warn!("Unknown card: {}", attendee),
+            }
+        }
+        let kind = client.event_kind.clone();
+        if let Err(err) = client.check_in(&attendee, kind) {
+            warn!("{}", err);
         }
-    })
+    }
+    info!("There are no more cards to read");
 }
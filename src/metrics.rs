@@ -0,0 +1,272 @@
+//! Counters of what a `Client` has done, and an HTTP server exposing them,
+//! along with gauges of its current state, in the Prometheus text format.
+
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tiny_http::{Header, Response, Server};
+
+use blockchain::BlockStatus;
+use errors::{Error, ErrorKind, Result, ResultExt};
+use events::RejectReason;
+use p2p::{PeerState, Transport, MESSAGE_KINDS, NUM_MESSAGE_KINDS};
+use {Client, POLL_INTERVAL};
+
+/// A count that only goes up.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    /// Returns the count.
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Adds one to the count.
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The counters kept by a `Client`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Blocks mined by this station.
+    pub blocks_mined: Counter,
+
+    /// Blocks received from peers, by their status relative to our chain.
+    pub blocks_received: [Counter; 4],
+
+    /// Blocks received from peers that weren't added to our chain, by the
+    /// reason they were rejected.
+    pub blocks_rejected: [Counter; 2],
+
+    /// Check-ins at this station that were refused.
+    pub check_ins_refused: Counter,
+
+    /// Forks resolved with peers.
+    pub forks_resolved: Counter,
+
+    /// Packets that couldn't be parsed or authenticated.
+    pub invalid_packets: Counter,
+
+    /// Messages received from peers, by kind.
+    pub messages_received: [Counter; NUM_MESSAGE_KINDS],
+
+    /// Messages sent to peers, by kind.
+    pub messages_sent: [Counter; NUM_MESSAGE_KINDS],
+
+    /// Messages that couldn't be sent.
+    pub send_errors: Counter,
+}
+
+impl Metrics {
+    /// Counts a block received from a peer.
+    pub fn block_received(&self, status: BlockStatus) {
+        self.blocks_received[status_index(status)].inc();
+    }
+
+    /// Returns the number of blocks received from peers with the given
+    /// status.
+    pub fn block_received_count(&self, status: BlockStatus) -> u64 {
+        self.blocks_received[status_index(status)].get()
+    }
+
+    /// Counts a block received from a peer that was rejected.
+    pub fn block_rejected(&self, reason: RejectReason) {
+        self.blocks_rejected[reason_index(reason)].inc();
+    }
+}
+
+/// Serves the client's metrics on the given address, until the client is shut
+/// down.
+pub fn serve<T: Transport>(client: &Client<T>, addr: SocketAddr) -> Result<()> {
+    let server = Server::http(addr)
+        .map_err(|err| Error::from(err.to_string()))
+        .chain_err(|| ErrorKind::CouldNotStartMetrics(addr))?;
+    info!("Serving metrics on {}", addr);
+
+    let content_type = Header::from_bytes(
+        &b"Content-Type"[..],
+        &b"text/plain; version=0.0.4"[..],
+    ).unwrap();
+    while !client.is_shutting_down() {
+        let request = match server.recv_timeout(POLL_INTERVAL) {
+            Ok(Some(request)) => request,
+            Ok(None) => continue,
+            Err(err) => {
+                warn!("Could not receive a metrics request: {}", err);
+                continue;
+            }
+        };
+        let response = Response::from_string(render(client))
+            .with_header(content_type.clone());
+        if let Err(err) = request.respond(response) {
+            warn!("Could not respond to a metrics request: {}", err);
+        }
+    }
+    Ok(())
+}
+
+/// Renders the client's metrics in the Prometheus text format.
+pub fn render<T: Transport>(client: &Client<T>) -> String {
+    let metrics = client.metrics();
+    let mut out = String::new();
+
+    let len = client.with_chain(|chain| chain.len());
+    gauge(&mut out, "chain_length", "Blocks in the chain.", &[("", len)]);
+
+    let mut peers = [0; 4];
+    client.with_peers(|p| {
+        for peer in p.values() {
+            peers[state_index(peer.state)] += 1;
+        }
+    });
+    let peers = label("state", &STATES, |i| peers[i]);
+    gauge(&mut out, "peers", "Known peers, by state.", &peers);
+
+    let queued = [("", client.send_queue.len() as u64)];
+    gauge(&mut out, "send_queue_length", "Messages to be sent.", &queued);
+
+    let mined = [("", metrics.blocks_mined.get())];
+    counter(&mut out, "blocks_mined", "Blocks mined.", &mined);
+    let received = label("status", &STATUSES, |i| {
+        metrics.blocks_received[i].get()
+    });
+    counter(
+        &mut out,
+        "blocks_received",
+        "Blocks received, by status relative to the chain.",
+        &received,
+    );
+    let rejected = label("reason", &REASONS, |i| {
+        metrics.blocks_rejected[i].get()
+    });
+    counter(
+        &mut out,
+        "blocks_rejected",
+        "Blocks received that weren't added to the chain, by reason.",
+        &rejected,
+    );
+    let refused = [("", metrics.check_ins_refused.get())];
+    counter(
+        &mut out,
+        "check_ins_refused",
+        "Check-ins refused, such as repeated swipes.",
+        &refused,
+    );
+    let forks = [("", metrics.forks_resolved.get())];
+    counter(&mut out, "forks_resolved", "Forks resolved.", &forks);
+
+    let received = label("kind", MESSAGE_KINDS, |i| {
+        metrics.messages_received[i].get()
+    });
+    counter(
+        &mut out,
+        "messages_received",
+        "Messages received, by kind.",
+        &received,
+    );
+    let sent = label("kind", MESSAGE_KINDS, |i| {
+        metrics.messages_sent[i].get()
+    });
+    counter(&mut out, "messages_sent", "Messages sent, by kind.", &sent);
+    let invalid = [("", metrics.invalid_packets.get())];
+    counter(
+        &mut out,
+        "invalid_packets",
+        "Packets that couldn't be parsed or authenticated.",
+        &invalid,
+    );
+    let errors = [("", metrics.send_errors.get())];
+    counter(
+        &mut out,
+        "send_errors",
+        "Messages that couldn't be sent.",
+        &errors,
+    );
+    out
+}
+
+const REASONS: [&str; 2] = ["conflicting", "invalid"];
+
+const STATES: [&str; 4] = ["speculative", "existent", "confirmed", "ignored"];
+
+const STATUSES: [&str; 4] =
+    ["contained", "valid_tip", "potentially_valid", "invalid"];
+
+fn reason_index(reason: RejectReason) -> usize {
+    match reason {
+        RejectReason::Conflicting => 0,
+        RejectReason::Invalid => 1,
+    }
+}
+
+fn state_index(state: PeerState) -> usize {
+    match state {
+        PeerState::Speculative => 0,
+        PeerState::Existent => 1,
+        PeerState::Confirmed(_, _) => 2,
+        PeerState::Ignore(_) => 3,
+    }
+}
+
+fn status_index(status: BlockStatus) -> usize {
+    match status {
+        BlockStatus::Contained => 0,
+        BlockStatus::ValidTip => 1,
+        BlockStatus::PotentiallyValid => 2,
+        BlockStatus::Invalid => 3,
+    }
+}
+
+/// Labels the values with the corresponding names.
+fn label<F>(key: &str, names: &[&str], value: F) -> Vec<(String, u64)>
+where
+    F: Fn(usize) -> u64,
+{
+    names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| (format!("{}=\"{}\"", key, name), value(i)))
+        .collect()
+}
+
+fn counter<L: AsRef<str>>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    samples: &[(L, u64)],
+) {
+    metric(out, &format!("{}_total", name), "counter", help, samples)
+}
+
+fn gauge<L: AsRef<str>>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    samples: &[(L, u64)],
+) {
+    metric(out, name, "gauge", help, samples)
+}
+
+fn metric<L: AsRef<str>>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: &[(L, u64)],
+) {
+    let name = format!("minnehack_check_in_{}", name);
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+    for &(ref labels, value) in samples {
+        let labels = labels.as_ref();
+        if labels.is_empty() {
+            writeln!(out, "{} {}", name, value).unwrap();
+        } else {
+            writeln!(out, "{}{{{}}} {}", name, labels, value).unwrap();
+        }
+    }
+}
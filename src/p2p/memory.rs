@@ -0,0 +1,106 @@
+//! An in-process transport, for testing.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::Stream;
+use tokio_core::reactor::Handle;
+
+use errors::{ErrorKind, Result};
+use p2p::{Incoming, Message, Transport};
+use util::Queue;
+
+/// A transport's queue of received messages.
+type Inbox = Arc<Queue<(SocketAddr, Message)>>;
+
+/// A network of `MemoryTransport`s. Messages are delivered reliably and in
+/// order, to transports on the same network.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryNetwork {
+    nodes: Arc<Mutex<HashMap<SocketAddr, Inbox>>>,
+}
+
+impl MemoryNetwork {
+    /// Creates a new, empty network.
+    pub fn new() -> MemoryNetwork {
+        MemoryNetwork::default()
+    }
+
+    /// Creates a transport on the network with the given address, replacing
+    /// any existing transport with that address.
+    pub fn transport(&self, addr: SocketAddr) -> MemoryTransport {
+        let inbox = Arc::new(Queue::new());
+        self.nodes.lock().unwrap().insert(addr, inbox.clone());
+        MemoryTransport {
+            addr,
+            inbox,
+            network: self.clone(),
+        }
+    }
+}
+
+/// A transport that sends messages to other transports on a `MemoryNetwork`.
+#[derive(Debug)]
+pub struct MemoryTransport {
+    addr: SocketAddr,
+    inbox: Inbox,
+    network: MemoryNetwork,
+}
+
+impl MemoryTransport {
+    /// Returns the address of the transport.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns a received message, if there is one, without blocking.
+    pub fn try_recv(&self) -> Option<(SocketAddr, Message)> {
+        self.inbox.try_pop()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn incoming<'a>(&'a self, _handle: &Handle) -> Result<Incoming<'a>> {
+        let msgs = self.inbox
+            .stream()
+            .map_err(|()| ErrorKind::CouldNotRecvMessage.into());
+        Ok(Box::new(msgs))
+    }
+
+    fn recv(&self) -> Result<(SocketAddr, Message)> {
+        Ok(self.inbox.pop())
+    }
+
+    fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<Option<(SocketAddr, Message)>> {
+        Ok(self.inbox.pop_timeout(timeout))
+    }
+
+    /// Sends a `Ping` to every other transport on the network.
+    fn send_discovery(&self) -> Result<()> {
+        let nodes = self.network.nodes.lock().unwrap();
+        for (&addr, node) in nodes.iter() {
+            if addr != self.addr {
+                node.push((self.addr, Message::Ping));
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&self, addr: SocketAddr, msg: &Message) -> Result<()> {
+        let nodes = self.network.nodes.lock().unwrap();
+        match nodes.get(&addr) {
+            Some(node) => {
+                node.push((self.addr, msg.clone()));
+                Ok(())
+            }
+            None => {
+                Err(ErrorKind::CouldNotSendMessage(msg.clone(), addr).into())
+            }
+        }
+    }
+}
@@ -6,7 +6,8 @@ use arrayvec::ArrayVec;
 #[cfg(test)]
 use quickcheck::{Arbitrary, Gen};
 
-use blockchain::{Block, Hash};
+use blockchain::{Block, Hash, Header};
+use keys::PublicKey;
 
 /// A message sent over the P2P layer.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -38,12 +39,137 @@ pub enum Message {
 
     /// An announced block.
     BlockAnnounce(Block),
+
+    /// A request for up to the given number of blocks, starting at the given
+    /// index. At most `MAX_BLOCK_RANGE_LEN` blocks will be sent.
+    BlockRangeRequest(u64, u8),
+
+    /// Consecutive blocks, sent in response to a `BlockRangeRequest`.
+    BlockRangeResponse(Vec<Block>),
+
+    /// A request for headers, with a block locator (see `Chain::locator`) of
+    /// up to `MAX_LOCATOR_LEN` hashes.
+    HeadersRequest(Vec<Hash>),
+
+    /// Up to `MAX_HEADERS` consecutive headers, sent in response to a
+    /// `HeadersRequest`. They start with the last block in the locator that
+    /// is on the sender's chain.
+    HeadersResponse(Vec<Header>),
+
+    /// The sender's half of the handshake. Sent to peers when they are found
+    /// to exist, and in response to a `Hello` from a peer we haven't yet had
+    /// one from.
+    Hello(Hello),
+}
+
+/// The names of the kinds of message, indexed by `Message::kind`.
+pub const MESSAGE_KINDS: &[&str] = &[
+    "Ping",
+    "Pong",
+    "PeerRequest",
+    "PeerResponse",
+    "StatusRequest",
+    "StatusResponse",
+    "BlockRequest",
+    "BlockResponse",
+    "BlockAnnounce",
+    "BlockRangeRequest",
+    "BlockRangeResponse",
+    "HeadersRequest",
+    "HeadersResponse",
+    "Hello",
+];
+
+/// The number of kinds of message.
+pub const NUM_MESSAGE_KINDS: usize = MESSAGE_KINDS.len();
+
+impl Message {
+    /// Returns the kind of message this is, as an index into
+    /// `MESSAGE_KINDS`. This is also the tag the message is serialized with.
+    pub fn kind(&self) -> usize {
+        match *self {
+            Message::Ping => 0,
+            Message::Pong => 1,
+            Message::PeerRequest => 2,
+            Message::PeerResponse(_) => 3,
+            Message::StatusRequest => 4,
+            Message::StatusResponse(_, _, _) => 5,
+            Message::BlockRequest(_) => 6,
+            Message::BlockResponse(_) => 7,
+            Message::BlockAnnounce(_) => 8,
+            Message::BlockRangeRequest(_, _) => 9,
+            Message::BlockRangeResponse(_) => 10,
+            Message::HeadersRequest(_) => 11,
+            Message::HeadersResponse(_) => 12,
+            Message::Hello(_) => 13,
+        }
+    }
+}
+
+/// What a node tells its peers about itself when they first meet.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Hello {
+    /// The newest protocol version the node speaks.
+    pub version: u16,
+
+    /// The node's ID, which is its station's public key.
+    pub node_id: PublicKey,
+
+    /// The hash of the genesis block of the node's blockchain.
+    pub genesis_hash: Hash,
+
+    /// The optional messages the node understands.
+    pub capabilities: Capabilities,
+}
+
+/// A set of optional protocol features. Bits that aren't known are kept, but
+/// never used.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// Understanding `BlockRangeRequest` and `BlockRangeResponse`.
+    pub const BLOCK_RANGES: Capabilities = Capabilities(0x1);
+
+    /// Understanding `HeadersRequest` and `HeadersResponse`.
+    pub const HEADERS: Capabilities = Capabilities(0x2);
+
+    /// The capabilities this version of the protocol supports.
+    pub const SUPPORTED: Capabilities = Capabilities(0x3);
+
+    /// Returns whether every capability in `other` is in this set.
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the capabilities that are in both sets.
+    pub fn intersection(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
 }
 
+/// The newest protocol version, sent in `Hello`s.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// The oldest protocol version a peer may speak without being ignored.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// The most blocks sent in a single `BlockRangeResponse`. Blocks are at most
+/// 432 bytes long when serialized, so this keeps responses well within the
+/// limits of both UDP packets and TCP frames.
+pub const MAX_BLOCK_RANGE_LEN: u8 = 32;
+
+/// The most headers sent in a single `HeadersResponse`.
+pub const MAX_HEADERS: u8 = 200;
+
+/// The most hashes in the block locator of a `HeadersRequest`. A locator for
+/// a chain of any realistic length is much shorter than this.
+pub const MAX_LOCATOR_LEN: u8 = 64;
+
 #[cfg(test)]
 impl Arbitrary for Message {
     fn arbitrary<G: Gen>(gen: &mut G) -> Message {
-        match gen.gen::<u8>() % 8 {
+        match gen.gen::<usize>() % NUM_MESSAGE_KINDS {
             0 => Message::Ping,
             1 => Message::Pong,
             2 => Message::PeerRequest,
@@ -65,6 +191,36 @@ impl Arbitrary for Message {
             6 => Message::BlockRequest(u64::arbitrary(gen)),
             7 => Message::BlockResponse(Block::arbitrary(gen)),
             8 => Message::BlockAnnounce(Block::arbitrary(gen)),
+            9 => Message::BlockRangeRequest(
+                u64::arbitrary(gen),
+                u8::arbitrary(gen),
+            ),
+            10 => {
+                let len = gen.gen::<u8>() % (MAX_BLOCK_RANGE_LEN + 1);
+                let blocks = (0..len).map(|_| Block::arbitrary(gen)).collect();
+                Message::BlockRangeResponse(blocks)
+            }
+            11 => {
+                let len = gen.gen::<u8>() % (MAX_LOCATOR_LEN + 1);
+                let hashes = (0..len).map(|_| Hash::arbitrary(gen)).collect();
+                Message::HeadersRequest(hashes)
+            }
+            12 => {
+                let len = gen.gen::<u8>() % (MAX_HEADERS + 1);
+                let headers =
+                    (0..len).map(|_| Header::arbitrary(gen)).collect();
+                Message::HeadersResponse(headers)
+            }
+            13 => {
+                let mut node_id = [0; 32];
+                gen.fill_bytes(&mut node_id);
+                Message::Hello(Hello {
+                    version: u16::arbitrary(gen),
+                    node_id: PublicKey(node_id),
+                    genesis_hash: Hash::arbitrary(gen),
+                    capabilities: Capabilities(u32::arbitrary(gen)),
+                })
+            }
             _ => unreachable!(),
         }
     }
@@ -1,23 +1,51 @@
 //! The p2p messaging layer under the blockchain.
 
+mod memory;
 mod message;
 pub(crate) mod parse;
+mod secure;
 mod serialize;
+mod tcp;
 #[cfg(test)]
 mod tests;
 
-use std::net::{SocketAddr, UdpSocket};
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::cmp::max;
+use std::io::{ErrorKind as IoErrorKind, Result as IoResult};
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::stream::poll_fn;
+use futures::{Async, Stream};
+use tokio_core::net::UdpSocket as AsyncUdpSocket;
+use tokio_core::reactor::Handle;
 
 use blockchain::Hash;
-use errors::{ErrorKind, Result, ResultExt};
+use errors::{Error, ErrorKind, Result, ResultExt};
+use keys::Keypair;
+use util::Queue;
 
-pub use self::message::Message;
+pub use self::memory::{MemoryNetwork, MemoryTransport};
+pub use self::message::{
+    Capabilities, Hello, Message, MAX_BLOCK_RANGE_LEN, MAX_HEADERS,
+    MAX_LOCATOR_LEN, MESSAGE_KINDS, MIN_PROTOCOL_VERSION, NUM_MESSAGE_KINDS,
+    PROTOCOL_VERSION,
+};
+use self::secure::Secure;
+use self::tcp::Tcp;
 
 /// A client for the P2P protocol.
+///
+/// Discovery pings are always broadcast over UDP. Other messages are sent over
+/// UDP, or over TCP if the `P2P` was created with TCP enabled. If a network
+/// key is set, messages are encrypted and authenticated with it.
 #[derive(Debug)]
 pub struct P2P {
     port: u16,
+    secure: Option<Secure>,
     socket: UdpSocket,
+    tcp: Option<Tcp>,
 }
 
 impl P2P {
@@ -26,46 +54,275 @@ impl P2P {
         P2P::with_port(10101)
     }
 
-    /// Waits for a message, blocking until one is received.
-    pub fn recv(&self) -> Result<(SocketAddr, Message)> {
-        let mut buf = [0; 0x10000];
-        let (len, addr) = self.socket
-            .recv_from(&mut buf)
-            .chain_err(|| ErrorKind::CouldNotRecvMessage)?;
-        let buf = &buf[..len];
+    /// Creates a new `P2P` instance with the given port, using only UDP.
+    pub fn with_port(port: u16) -> Result<P2P> {
+        P2P::with_opts(port, false)
+    }
+
+    /// Creates a new `P2P` instance with the given port. If `use_tcp` is true,
+    /// messages other than discovery pings are sent over TCP, with a listener
+    /// on the same port.
+    pub fn with_opts(port: u16, use_tcp: bool) -> Result<P2P> {
+        let listener = if use_tcp {
+            let addr = SocketAddr::from(([0; 4], port));
+            Some(
+                TcpListener::bind(addr)
+                    .chain_err(|| ErrorKind::CouldNotStartListener)?,
+            )
+        } else {
+            None
+        };
+        let port = match listener {
+            Some(ref listener) => listener
+                .local_addr()
+                .chain_err(|| ErrorKind::CouldNotStartListener)?
+                .port(),
+            None => port,
+        };
+
+        let addr = SocketAddr::from(([0; 4], port));
+        let socket = UdpSocket::bind(&addr)
+            .chain_err(|| ErrorKind::CouldNotStartListener)?;
+        socket
+            .set_broadcast(true)
+            .chain_err(|| ErrorKind::CouldNotStartListener)?;
+        let port = socket
+            .local_addr()
+            .chain_err(|| ErrorKind::CouldNotStartListener)?
+            .port();
+
+        let tcp = match listener {
+            Some(listener) => {
+                let socket = socket
+                    .try_clone()
+                    .chain_err(|| ErrorKind::CouldNotStartListener)?;
+                let packets = Arc::new(Queue::new());
+                Some(Tcp::listen(listener, socket, packets)?)
+            }
+            None => None,
+        };
+
+        Ok(P2P {
+            port,
+            secure: None,
+            socket,
+            tcp,
+        })
+    }
+
+    /// Returns the port being listened on.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Encrypts and authenticates messages with the given network key, using
+    /// the keypair to identify this node. Only peers with the same network
+    /// key can read our messages, and messages from other peers are dropped.
+    pub fn set_network_key(&mut self, keypair: Keypair, network_key: &str) {
+        self.secure = Some(Secure::new(keypair, network_key));
+    }
+
+    /// Waits for a message, blocking until one is received or the deadline,
+    /// if there is one, passes.
+    fn recv_until(
+        &self,
+        deadline: Option<Instant>,
+    ) -> Result<Option<(SocketAddr, Message)>> {
+        loop {
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(None);
+                    }
+                    Some(deadline - now)
+                }
+                None => None,
+            };
+            let packet = match self.tcp {
+                Some(ref tcp) => tcp.recv(timeout)?,
+                None => recv_packet(&self.socket, timeout)?,
+            };
+            let (addr, buf) = match packet {
+                Some(packet) => packet,
+                None => return Ok(None),
+            };
+            if let Some(msg) = self.open(addr, buf)? {
+                return Ok(Some((addr, msg)));
+            }
+        }
+    }
+
+    /// Decrypts and parses a received packet, sending any replies needed to
+    /// establish a session. Returns `None` if the packet only carried part of
+    /// a handshake.
+    fn open(&self, addr: SocketAddr, buf: Vec<u8>) -> Result<Option<Message>> {
+        let buf = match self.secure {
+            Some(ref secure) => {
+                let mut replies = Vec::new();
+                let opened = secure.open(addr, &buf, &mut replies)?;
+                for reply in replies {
+                    if let Err(err) = self.send_packet(addr, &reply) {
+                        warn!("Could not reply to {}: {}", addr, err);
+                    }
+                }
+                match opened {
+                    Some(buf) => buf,
+                    None => return Ok(None),
+                }
+            }
+            None => buf,
+        };
 
         let msg = Message::parse_from(&buf)
-            .chain_err(|| ErrorKind::InvalidPacket(buf.to_vec()))?;
-        Ok((addr, msg))
+            .chain_err(|| ErrorKind::InvalidPacket(buf.clone()))?;
+        Ok(Some(msg))
+    }
+
+    /// Sends a packet to the peer, without encrypting it.
+    fn send_packet(&self, addr: SocketAddr, buf: &[u8]) -> IoResult<()> {
+        match self.tcp {
+            Some(ref tcp) => tcp.send(addr, buf),
+            None => self.socket.send_to(buf, addr).map(|_| ()),
+        }
+    }
+}
+
+impl Transport for P2P {
+    /// Returns a stream of received messages. In UDP mode, the socket is
+    /// registered with the reactor, so `recv` and `recv_timeout` shouldn't
+    /// be used afterwards. In TCP mode, packets are still read on a reactor of
+    /// their own, along with the UDP socket.
+    fn incoming<'a>(&'a self, handle: &Handle) -> Result<Incoming<'a>> {
+        let packets: Box<dyn Stream<Item = _, Error = _>> = match self.tcp {
+            Some(ref tcp) => Box::new(
+                tcp.packets()
+                    .map_err(|()| Error::from(ErrorKind::CouldNotRecvMessage))
+                    .and_then(|packet| packet),
+            ),
+            None => {
+                let socket = self
+                    .socket
+                    .try_clone()
+                    .and_then(|socket| {
+                        AsyncUdpSocket::from_socket(socket, handle)
+                    })
+                    .chain_err(|| ErrorKind::CouldNotStartListener)?;
+                let mut buf = vec![0; 0x10000];
+                Box::new(poll_fn(move || match socket.recv_from(&mut buf) {
+                    Ok((len, addr)) => {
+                        Ok(Async::Ready(Some((addr, buf[..len].to_vec()))))
+                    }
+                    Err(ref err) if err.kind() == IoErrorKind::WouldBlock => {
+                        Ok(Async::NotReady)
+                    }
+                    Err(err) => {
+                        Err(err).chain_err(|| ErrorKind::CouldNotRecvMessage)
+                    }
+                }))
+            }
+        };
+        let msgs = packets
+            .and_then(move |(addr, buf)| {
+                self.open(addr, buf).map(|msg| msg.map(|msg| (addr, msg)))
+            })
+            .filter_map(|msg| msg);
+        Ok(Box::new(msgs))
+    }
+
+    fn recv(&self) -> Result<(SocketAddr, Message)> {
+        self.recv_until(None).map(Option::unwrap)
+    }
+
+    fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<Option<(SocketAddr, Message)>> {
+        self.recv_until(Some(Instant::now() + timeout))
     }
 
     /// Broadcasts a discovery message. This only helps to discover peers on
     /// the same LAN, and only for IPv4.
-    pub fn send_discovery(&self) -> Result<()> {
+    fn send_discovery(&self) -> Result<()> {
         let addr = ([0xff; 4], self.port).into();
-        self.send(addr, &Message::Ping)
-    }
-
-    /// Sends a message to the peer.
-    pub fn send(&self, addr: SocketAddr, msg: &Message) -> Result<()> {
+        let msg = Message::Ping;
         let mut buf = Vec::new();
         msg.write_to(&mut buf).unwrap();
+        if let Some(ref secure) = self.secure {
+            buf = secure.seal_discovery(&buf);
+        }
         self.socket
-            .send_to(&buf, &addr)
-            .chain_err(|| ErrorKind::CouldNotSendMessage(msg.clone(), addr))
+            .send_to(&buf, addr)
+            .chain_err(|| ErrorKind::CouldNotSendMessage(msg, addr))
             .map(|_| ())
     }
 
-    /// Creates a new `P2P` instance with the given port.
-    pub fn with_port(port: u16) -> Result<P2P> {
-        let addr = SocketAddr::from(([0; 4], port));
-        let socket = UdpSocket::bind(&addr)
-            .chain_err(|| ErrorKind::CouldNotStartListener)?;
-        socket
-            .set_broadcast(true)
-            .chain_err(|| ErrorKind::CouldNotStartListener)?;
+    fn send(&self, addr: SocketAddr, msg: &Message) -> Result<()> {
+        let mut buf = Vec::new();
+        msg.write_to(&mut buf).unwrap();
+        let err = || ErrorKind::CouldNotSendMessage(msg.clone(), addr);
+        let packets = match self.secure {
+            Some(ref secure) => secure.seal(addr, &buf).chain_err(err)?,
+            None => vec![buf],
+        };
+        for packet in packets {
+            self.send_packet(addr, &packet).chain_err(err)?;
+        }
+        Ok(())
+    }
+}
+
+/// A stream of the messages received by a `Transport`. An error receiving one
+/// message doesn't end the stream.
+pub type Incoming<'a> =
+    Box<dyn Stream<Item = (SocketAddr, Message), Error = Error> + 'a>;
+
+/// A way of exchanging messages with peers.
+pub trait Transport: Debug + Send + Sync {
+    /// Returns a stream of received messages, driven by the reactor with the
+    /// given handle.
+    fn incoming<'a>(&'a self, handle: &Handle) -> Result<Incoming<'a>>;
+
+    /// Waits for a message, blocking until one is received.
+    fn recv(&self) -> Result<(SocketAddr, Message)>;
+
+    /// Waits for a message, blocking until one is received or the timeout
+    /// passes. Returns `None` if the timeout passed.
+    fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<Option<(SocketAddr, Message)>>;
 
-        Ok(P2P { port, socket })
+    /// Sends a message to every peer that can be found without knowing its
+    /// address, so that they respond and become known.
+    fn send_discovery(&self) -> Result<()>;
+
+    /// Sends a message to the peer.
+    fn send(&self, addr: SocketAddr, msg: &Message) -> Result<()>;
+}
+
+/// Waits for a packet on a UDP socket, blocking until one is received or the
+/// timeout, if there is one, passes.
+fn recv_packet(
+    socket: &UdpSocket,
+    timeout: Option<Duration>,
+) -> Result<Option<(SocketAddr, Vec<u8>)>> {
+    // A zero timeout would mean no timeout at all.
+    let timeout = timeout.map(|t| max(t, Duration::from_millis(1)));
+    socket
+        .set_read_timeout(timeout)
+        .chain_err(|| ErrorKind::CouldNotRecvMessage)?;
+
+    let mut buf = [0; 0x10000];
+    match socket.recv_from(&mut buf) {
+        Ok((len, addr)) => Ok(Some((addr, buf[..len].to_vec()))),
+        Err(ref err)
+            if err.kind() == IoErrorKind::WouldBlock
+                || err.kind() == IoErrorKind::TimedOut =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(err).chain_err(|| ErrorKind::CouldNotRecvMessage),
     }
 }
 
@@ -75,6 +332,10 @@ pub struct Peer {
     /// The peer's address.
     pub addr: SocketAddr,
 
+    /// The peer's half of the handshake, if it has been received. Peers
+    /// running versions from before the handshake never send one.
+    pub hello: Option<Hello>,
+
     /// The peer's karma.
     ///
     /// Karma is expended every time we send a packet, and increased every time
@@ -91,11 +352,21 @@ impl Peer {
     pub fn new(addr: SocketAddr) -> Peer {
         Peer {
             addr,
+            hello: None,
             karma: 0,
             state: PeerState::Speculative,
         }
     }
 
+    /// Returns the optional features both we and the peer support. Peers
+    /// that haven't sent a `Hello` are assumed to support none.
+    pub fn capabilities(&self) -> Capabilities {
+        self.hello
+            .map(|hello| hello.capabilities)
+            .unwrap_or_default()
+            .intersection(Capabilities::SUPPORTED)
+    }
+
     /// Returns whether the peer has been confirmed to be on the same
     /// blockchain as us.
     pub fn same_blockchain(&self) -> bool {
@@ -121,5 +392,35 @@ pub enum PeerState {
     Confirmed(u64, Hash),
 
     /// The peer is on another blockchain or is blocked for other reasons.
-    Ignore,
+    Ignore(IgnoreReason),
+}
+
+/// Why a peer is being ignored.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IgnoreReason {
+    /// The peer's genesis block is different from ours.
+    OtherBlockchain,
+
+    /// The peer has our node ID, so it's presumably us.
+    Ourselves,
+
+    /// The peer speaks a protocol version older than `MIN_PROTOCOL_VERSION`,
+    /// which is the parameter.
+    UnsupportedVersion(u16),
+}
+
+impl Display for IgnoreReason {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            IgnoreReason::OtherBlockchain => {
+                write!(fmt, "it is on another blockchain")
+            }
+            IgnoreReason::Ourselves => write!(fmt, "it has our node ID"),
+            IgnoreReason::UnsupportedVersion(version) => write!(
+                fmt,
+                "it speaks protocol version {}, but at least {} is required",
+                version, MIN_PROTOCOL_VERSION
+            ),
+        }
+    }
 }
@@ -1,9 +1,12 @@
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
-use nom::{IResult, le_u16, le_u64, le_u8};
+use nom::{IResult, le_u16, le_u32, le_u64, le_u8};
 
-use blockchain::parse::{block, hash};
-use p2p::Message;
+use blockchain::parse::{block, hash, header, public_key};
+use p2p::{
+    Capabilities, Hello, Message, MAX_BLOCK_RANGE_LEN, MAX_HEADERS,
+    MAX_LOCATOR_LEN,
+};
 use util::vec_to_arrayvec;
 
 impl Message {
@@ -22,7 +25,9 @@ impl Message {
 
 named!(message(&[u8]) -> Message, alt_complete!(
     ping | pong | peer_request | peer_response | status_request |
-    status_response | block_request | block_response | block_announce
+    status_response | block_request | block_response | block_announce |
+    block_range_request | block_range_response | headers_request |
+    headers_response | hello
 ));
 
 named!(ping(&[u8]) -> Message, map!(tag!([0x00]), |_| Message::Ping));
@@ -53,6 +58,44 @@ named!(block_announce(&[u8]) -> Message, do_parse!(
     block: block >>
     ( Message::BlockAnnounce(block) )));
 
+named!(block_range_request(&[u8]) -> Message, do_parse!(
+    tag!([0x09]) >>
+    start: le_u64 >>
+    count: le_u8 >>
+    ( Message::BlockRangeRequest(start, count) )));
+named!(block_range_response(&[u8]) -> Message, do_parse!(
+    tag!([0x0a]) >>
+    blocks: length_count!(
+        verify!(le_u8, |l| l <= MAX_BLOCK_RANGE_LEN),
+        block) >>
+    ( Message::BlockRangeResponse(blocks) )));
+
+named!(headers_request(&[u8]) -> Message, do_parse!(
+    tag!([0x0b]) >>
+    locator: length_count!(
+        verify!(le_u8, |l| l <= MAX_LOCATOR_LEN),
+        hash) >>
+    ( Message::HeadersRequest(locator) )));
+named!(headers_response(&[u8]) -> Message, do_parse!(
+    tag!([0x0c]) >>
+    headers: length_count!(
+        verify!(le_u8, |l| l <= MAX_HEADERS),
+        header) >>
+    ( Message::HeadersResponse(headers) )));
+
+named!(hello(&[u8]) -> Message, do_parse!(
+    tag!([0x0d]) >>
+    version: le_u16 >>
+    node_id: public_key >>
+    genesis_hash: hash >>
+    capabilities: le_u32 >>
+    ( Message::Hello(Hello {
+        version,
+        node_id,
+        genesis_hash,
+        capabilities: Capabilities(capabilities),
+    }) )));
+
 named!(sock_addr(&[u8]) -> SocketAddr, alt_complete!(
     map!(sock_addr_4, SocketAddr::V4) |
     map!(sock_addr_6, SocketAddr::V6)
@@ -0,0 +1,478 @@
+//! Encrypting and authenticating messages between peers.
+//!
+//! Peers that share a network key set up sessions with a handshake modeled on
+//! Noise's `IXpsk0` pattern, using their stations' keypairs as static keys and
+//! new keypairs as ephemeral keys:
+//!
+//! ```text
+//! -> INIT, e, s, tag
+//! <- RESPONSE, e, s, tag
+//! ```
+//!
+//! Both start from a chaining key derived from the network key. The
+//! initiation's tag is keyed by it and the initiator's keys, so packets from
+//! peers without the network key are dropped. The response's tag and the
+//! session keys also mix in the `ee`, `es` and `se` Diffie-Hellman results,
+//! so only the holders of both static keys can use the session.
+//!
+//! Messages are then sent as `TRANSPORT`, a little-endian `u64` counter, the
+//! ciphertext and a tag, with each side sending under its own key. Counters
+//! that were already received, or are too old to tell, are dropped. The
+//! initiator sends an empty message once the handshake completes, and the
+//! responder keeps sending on its old session until a message arrives on the
+//! new one, so a replayed initiation can't break an existing session.
+//!
+//! Discovery pings are broadcast as `DISCOVERY`, a random `u64` nonce, the
+//! ciphertext and a tag, under a key derived from the network key alone.
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, LE};
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::hkdf::{hkdf_expand, hkdf_extract};
+use crypto::sha2::Sha256;
+use rand::{thread_rng, Rng};
+
+use errors::{ErrorKind, Result};
+use keys::{Keypair, PublicKey};
+
+const INIT: u8 = 0xe1;
+const RESPONSE: u8 = 0xe2;
+const TRANSPORT: u8 = 0xe3;
+const DISCOVERY: u8 = 0xe4;
+
+/// The salt the first chaining key is derived from the network key with.
+const PROTOCOL_NAME: &[u8] =
+    b"minnehack-check-in IXpsk0 25519 ChaChaPoly SHA256";
+
+/// The time to wait for a response to a handshake before starting another,
+/// and the least time between starting handshakes with a peer.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The length of a handshake packet: its type, two public keys and a tag.
+const HANDSHAKE_LEN: usize = 1 + 32 + 32 + TAG_LEN;
+
+/// The length of the type and nonce at the start of other packets.
+const HEADER_LEN: usize = 1 + 8;
+
+/// The most messages held for a peer while waiting for a handshake.
+const MAX_QUEUED: usize = 64;
+
+const TAG_LEN: usize = 16;
+
+/// Encrypted sessions with peers.
+pub struct Secure {
+    chaining_key: [u8; 32],
+    discovery_key: [u8; 32],
+    keypair: Keypair,
+    links: Mutex<HashMap<SocketAddr, Link>>,
+}
+
+impl Secure {
+    /// Creates a `Secure` using the given keypair as the static key, for
+    /// peers with the given network key.
+    pub fn new(keypair: Keypair, network_key: &str) -> Secure {
+        let mut chaining_key = [0; 32];
+        hkdf_extract(
+            Sha256::new(),
+            PROTOCOL_NAME,
+            network_key.as_bytes(),
+            &mut chaining_key,
+        );
+        Secure {
+            chaining_key,
+            discovery_key: derive(&chaining_key, b"discovery"),
+            keypair,
+            links: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Decrypts a packet from a peer, returning the message in it, if there
+    /// is one. Packets that should be sent back to the peer, such as
+    /// handshake responses, are added to `replies`. Nothing should be sent
+    /// back for packets that can't be opened.
+    pub fn open(
+        &self,
+        addr: SocketAddr,
+        packet: &[u8],
+        replies: &mut Vec<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>> {
+        let opened = match packet.first() {
+            Some(&INIT) if packet.len() == HANDSHAKE_LEN => {
+                self.open_init(addr, packet, replies)
+            }
+            Some(&RESPONSE) if packet.len() == HANDSHAKE_LEN => {
+                self.open_response(addr, packet, replies)
+            }
+            Some(&TRANSPORT) if packet.len() >= HEADER_LEN + TAG_LEN => {
+                self.open_transport(addr, packet, replies)
+            }
+            Some(&DISCOVERY) if packet.len() >= HEADER_LEN + TAG_LEN => {
+                let nonce = &packet[1..HEADER_LEN];
+                let (aad, sealed) = packet.split_at(HEADER_LEN);
+                open_with(&self.discovery_key, nonce, aad, sealed).map(Some)
+            }
+            _ => None,
+        };
+        opened.ok_or_else(|| ErrorKind::UnauthenticatedPacket(addr).into())
+    }
+
+    /// Encrypts a message to a peer, returning the packets to send. If there
+    /// is no session with the peer yet, the message is held until there is,
+    /// and a handshake is started.
+    pub fn seal(&self, addr: SocketAddr, msg: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let mut links = self.links.lock().unwrap();
+        let link = links.entry(addr).or_default();
+        if let Some(ref mut session) = link.current {
+            return Ok(vec![session.seal(msg)]);
+        }
+
+        if link.queued.len() == MAX_QUEUED {
+            link.queued.remove(0);
+        }
+        link.queued.push(msg.to_vec());
+        if link.handshake_due() {
+            Ok(vec![self.start_handshake(link)?])
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    /// Encrypts a discovery ping for broadcasting.
+    pub fn seal_discovery(&self, msg: &[u8]) -> Vec<u8> {
+        let mut packet = vec![DISCOVERY, 0, 0, 0, 0, 0, 0, 0, 0];
+        thread_rng().fill_bytes(&mut packet[1..]);
+        let sealed = seal_with(
+            &self.discovery_key,
+            &packet[1..],
+            &packet[..HEADER_LEN],
+            msg,
+        );
+        packet.extend(sealed);
+        packet
+    }
+
+    /// Handles a peer's initiation, responding to it. The new session only
+    /// replaces an existing one once the peer sends a message on it.
+    fn open_init(
+        &self,
+        addr: SocketAddr,
+        packet: &[u8],
+        replies: &mut Vec<Vec<u8>>,
+    ) -> Option<Option<Vec<u8>>> {
+        let (their_e, their_s) = handshake_keys(packet);
+        let ck = mix(&self.chaining_key, &[&packet[1..65]]);
+        let key = derive(&ck, b"init");
+        open_with(&key, &[0; 8], &packet[..65], &packet[65..])?;
+
+        let e = match Keypair::generate() {
+            Ok(e) => e,
+            Err(err) => {
+                error!("Could not respond to {}'s handshake: {}", addr, err);
+                return Some(None);
+            }
+        };
+        let mut response = vec![RESPONSE];
+        response.extend(&e.public().0);
+        response.extend(&self.keypair.public().0);
+        let ck = mix(
+            &ck,
+            &[
+                &response[1..],
+                &e.exchange(&their_e),
+                &self.keypair.exchange(&their_e),
+                &e.exchange(&their_s),
+            ],
+        );
+        let key = derive(&ck, b"response");
+        let tag = seal_with(&key, &[0; 8], &response, &[]);
+        response.extend(tag);
+        replies.push(response);
+
+        let mut session = Session::new(
+            derive(&ck, b"responder"),
+            derive(&ck, b"initiator"),
+        );
+        let mut links = self.links.lock().unwrap();
+        let link = links.entry(addr).or_default();
+        if link.current.is_none() {
+            let queued = link.queued.drain(..);
+            replies.extend(queued.map(|msg| session.seal(&msg)));
+            link.current = Some(session);
+        } else {
+            link.pending = Some(session);
+        }
+        Some(None)
+    }
+
+    /// Handles a response to our initiation, completing the handshake.
+    fn open_response(
+        &self,
+        addr: SocketAddr,
+        packet: &[u8],
+        replies: &mut Vec<Vec<u8>>,
+    ) -> Option<Option<Vec<u8>>> {
+        let mut links = self.links.lock().unwrap();
+        let link = links.get_mut(&addr)?;
+        let mut session = {
+            let e = link.handshake.as_ref()?;
+            let (their_e, their_s) = handshake_keys(packet);
+            let ck = mix(
+                &self.chaining_key,
+                &[&e.public().0, &self.keypair.public().0],
+            );
+            let ck = mix(
+                &ck,
+                &[
+                    &packet[1..65],
+                    &e.exchange(&their_e),
+                    &e.exchange(&their_s),
+                    &self.keypair.exchange(&their_e),
+                ],
+            );
+            let key = derive(&ck, b"response");
+            open_with(&key, &[0; 8], &packet[..65], &packet[65..])?;
+            Session::new(derive(&ck, b"initiator"), derive(&ck, b"responder"))
+        };
+
+        // Tell the peer the session works, even if there's nothing to send.
+        if link.queued.is_empty() {
+            replies.push(session.seal(&[]));
+        } else {
+            let queued = link.queued.drain(..);
+            replies.extend(queued.map(|msg| session.seal(&msg)));
+        }
+        link.handshake = None;
+        link.previous = link.current.take();
+        link.current = Some(session);
+        Some(None)
+    }
+
+    /// Decrypts a message on one of the sessions with the peer. If none of
+    /// them work, the peer has presumably forgotten them, so a handshake is
+    /// started, unless one was started recently, and the packet is treated
+    /// as carrying no message. Packets from peers we have no link with are
+    /// dropped, so strangers can't make us start handshakes.
+    fn open_transport(
+        &self,
+        addr: SocketAddr,
+        packet: &[u8],
+        replies: &mut Vec<Vec<u8>>,
+    ) -> Option<Option<Vec<u8>>> {
+        let mut links = self.links.lock().unwrap();
+        let link = links.get_mut(&addr)?;
+
+        let mut msg = link.current.as_mut().and_then(|s| s.open(packet));
+        if msg.is_none() {
+            msg = link.pending.as_mut().and_then(|s| s.open(packet));
+            if msg.is_some() {
+                link.previous = link.current.take();
+                link.current = link.pending.take();
+            }
+        }
+        if msg.is_none() {
+            msg = link.previous.as_mut().and_then(|s| s.open(packet));
+        }
+
+        match msg {
+            Some(msg) => Some(if msg.is_empty() { None } else { Some(msg) }),
+            None if link.handshake_due() => {
+                debug!("No session with {}, starting a handshake", addr);
+                match self.start_handshake(link) {
+                    Ok(init) => {
+                        replies.push(init);
+                        Some(None)
+                    }
+                    Err(err) => {
+                        error!(
+                            "Could not start a handshake with {}: {}",
+                            addr, err
+                        );
+                        None
+                    }
+                }
+            }
+            None => None,
+        }
+    }
+
+    /// Starts a handshake, returning the initiation to send.
+    fn start_handshake(&self, link: &mut Link) -> Result<Vec<u8>> {
+        let e = Keypair::generate()?;
+        let mut init = vec![INIT];
+        init.extend(&e.public().0);
+        init.extend(&self.keypair.public().0);
+        let ck = mix(&self.chaining_key, &[&init[1..]]);
+        let tag = seal_with(&derive(&ck, b"init"), &[0; 8], &init, &[]);
+        init.extend(tag);
+
+        link.handshake = Some(e);
+        link.handshake_sent = Some(Instant::now());
+        Ok(init)
+    }
+}
+
+impl Debug for Secure {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("Secure")
+            .field("keypair", &self.keypair)
+            .finish()
+    }
+}
+
+/// The sessions with a single peer.
+#[derive(Default)]
+struct Link {
+    /// The session messages are sent on.
+    current: Option<Session>,
+
+    /// Our ephemeral keypair, if we're waiting for a response to our
+    /// initiation.
+    handshake: Option<Keypair>,
+
+    /// When we last sent an initiation.
+    handshake_sent: Option<Instant>,
+
+    /// A session the peer started, which becomes current once the peer sends
+    /// a message on it.
+    pending: Option<Session>,
+
+    /// The session that was current before, kept for messages that were
+    /// already on their way.
+    previous: Option<Session>,
+
+    /// Messages waiting for a session.
+    queued: Vec<Vec<u8>>,
+}
+
+impl Link {
+    /// Returns whether a handshake should be started, because none was
+    /// started within `HANDSHAKE_TIMEOUT`. Whether the last one finished
+    /// doesn't matter, so packets that can't be opened can't make us start
+    /// handshakes any more often.
+    fn handshake_due(&self) -> bool {
+        match self.handshake_sent {
+            Some(sent) => sent.elapsed() >= HANDSHAKE_TIMEOUT,
+            None => true,
+        }
+    }
+}
+
+/// The keys and counters of one session.
+struct Session {
+    recv_key: [u8; 32],
+    recv_next: u64,
+    recv_seen: u64,
+    send_key: [u8; 32],
+    send_next: u64,
+}
+
+impl Session {
+    fn new(send_key: [u8; 32], recv_key: [u8; 32]) -> Session {
+        Session {
+            recv_key,
+            recv_next: 0,
+            recv_seen: 0,
+            send_key,
+            send_next: 0,
+        }
+    }
+
+    /// Decrypts a transport packet, unless its counter was already received
+    /// or is more than 64 behind the newest.
+    fn open(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        let counter = LE::read_u64(&packet[1..HEADER_LEN]);
+        let age = if counter < self.recv_next {
+            Some(self.recv_next - 1 - counter)
+        } else {
+            None
+        };
+        match age {
+            Some(age) if age >= 64 || self.recv_seen & (1 << age) != 0 => {
+                return None
+            }
+            _ => {}
+        }
+
+        let (aad, sealed) = packet.split_at(HEADER_LEN);
+        let msg = open_with(&self.recv_key, &aad[1..], aad, sealed)?;
+        match age {
+            Some(age) => self.recv_seen |= 1 << age,
+            None => {
+                let shift = counter - self.recv_next + 1;
+                self.recv_seen = if shift < 64 {
+                    self.recv_seen << shift | 1
+                } else {
+                    1
+                };
+                self.recv_next = counter + 1;
+            }
+        }
+        Some(msg)
+    }
+
+    /// Encrypts a message into a transport packet.
+    fn seal(&mut self, msg: &[u8]) -> Vec<u8> {
+        let mut packet = vec![TRANSPORT, 0, 0, 0, 0, 0, 0, 0, 0];
+        LE::write_u64(&mut packet[1..], self.send_next);
+        self.send_next += 1;
+        let sealed = seal_with(&self.send_key, &packet[1..], &packet, msg);
+        packet.extend(sealed);
+        packet
+    }
+}
+
+/// Derives a key for the given purpose from a chaining key.
+fn derive(ck: &[u8; 32], purpose: &[u8]) -> [u8; 32] {
+    let mut key = [0; 32];
+    hkdf_expand(Sha256::new(), ck, purpose, &mut key);
+    key
+}
+
+/// Returns the ephemeral and static keys in a handshake packet.
+fn handshake_keys(packet: &[u8]) -> (PublicKey, PublicKey) {
+    let mut e = PublicKey([0; 32]);
+    let mut s = PublicKey([0; 32]);
+    e.0.copy_from_slice(&packet[1..33]);
+    s.0.copy_from_slice(&packet[33..65]);
+    (e, s)
+}
+
+/// Mixes the inputs into a chaining key, returning the new chaining key.
+fn mix(ck: &[u8; 32], inputs: &[&[u8]]) -> [u8; 32] {
+    let input = inputs.concat();
+    let mut out = [0; 32];
+    hkdf_extract(Sha256::new(), ck, &input, &mut out);
+    out
+}
+
+/// Decrypts and authenticates a ciphertext followed by its tag.
+fn open_with(
+    key: &[u8; 32],
+    nonce: &[u8],
+    aad: &[u8],
+    sealed: &[u8],
+) -> Option<Vec<u8>> {
+    let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_LEN);
+    let mut msg = vec![0; ciphertext.len()];
+    let mut cipher = ChaCha20Poly1305::new(key, nonce, aad);
+    if cipher.decrypt(ciphertext, &mut msg, tag) {
+        Some(msg)
+    } else {
+        None
+    }
+}
+
+/// Encrypts a message, returning the ciphertext followed by its tag.
+fn seal_with(key: &[u8; 32], nonce: &[u8], aad: &[u8], msg: &[u8]) -> Vec<u8> {
+    let mut sealed = vec![0; msg.len() + TAG_LEN];
+    let (ciphertext, tag) = sealed.split_at_mut(msg.len());
+    let mut cipher = ChaCha20Poly1305::new(key, nonce, aad);
+    cipher.encrypt(msg, ciphertext, tag);
+    sealed
+}
@@ -3,7 +3,7 @@ use std::net::SocketAddr;
 
 use byteorder::{ByteOrder, LE};
 
-use p2p::Message;
+use p2p::{Message, MAX_BLOCK_RANGE_LEN, MAX_HEADERS, MAX_LOCATOR_LEN};
 use util::write_u64_to;
 
 impl Message {
@@ -41,6 +41,48 @@ impl Message {
                 w.write_all(&[0x08])?;
                 block.write_to(w)
             }
+            Message::BlockRangeRequest(start, count) => {
+                w.write_all(&[0x09])?;
+                write_u64_to(start, &mut w)?;
+                w.write_all(&[count])
+            }
+            Message::BlockRangeResponse(ref blocks) => {
+                let l = blocks.len();
+                assert!(l <= MAX_BLOCK_RANGE_LEN as usize);
+                w.write_all(&[0x0a, l as u8])?;
+                for block in blocks {
+                    block.write_to(&mut w)?;
+                }
+                Ok(())
+            }
+            Message::HeadersRequest(ref locator) => {
+                let l = locator.len();
+                assert!(l <= MAX_LOCATOR_LEN as usize);
+                w.write_all(&[0x0b, l as u8])?;
+                for hash in locator {
+                    w.write_all(&hash.0)?;
+                }
+                Ok(())
+            }
+            Message::HeadersResponse(ref headers) => {
+                let l = headers.len();
+                assert!(l <= MAX_HEADERS as usize);
+                w.write_all(&[0x0c, l as u8])?;
+                for header in headers {
+                    header.write_to(&mut w)?;
+                }
+                Ok(())
+            }
+            Message::Hello(ref hello) => {
+                let mut buf = [0; 6];
+                LE::write_u16(&mut buf[..2], hello.version);
+                LE::write_u32(&mut buf[2..], hello.capabilities.0);
+                w.write_all(&[0x0d])?;
+                w.write_all(&buf[..2])?;
+                w.write_all(&hello.node_id.0)?;
+                w.write_all(&hello.genesis_hash.0)?;
+                w.write_all(&buf[2..])
+            }
         }
     }
 }
@@ -0,0 +1,433 @@
+//! Sending messages over persistent TCP connections.
+//!
+//! Each connection starts with a preface of `PREFACE_MAGIC` followed by the
+//! port the connecting peer listens on, so inbound connections can be matched
+//! to the address the peer is known by. After that, each packet is sent as a
+//! little-endian `u32` length followed by the packet.
+//!
+//! Connections are made, read and written on a reactor running on a thread of
+//! their own, so sending never waits on the network. Discovery still happens
+//! over UDP, so the UDP socket is read there too.
+
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, LE};
+use futures::future::poll_fn;
+use futures::stream::unfold;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::{Async, Future, IntoFuture, Stream};
+use tokio_core::net::{
+    TcpListener as AsyncTcpListener, TcpStream as AsyncTcpStream,
+    UdpSocket as AsyncUdpSocket,
+};
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tokio_io::io::{read_exact, write_all};
+use tokio_io::AsyncRead;
+
+use errors::{ErrorKind, Result, ResultExt};
+use util::{Queue, QueueStream};
+
+/// The bytes each connection starts with.
+const PREFACE_MAGIC: &[u8] = b"MHCI";
+
+/// The largest packet that will be read.
+const MAX_FRAME_LEN: usize = 0x10000;
+
+/// The time to wait for a connection to be established or a write to finish.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest time to wait before trying to reconnect to a peer.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A queue of received packets.
+pub type Packets = Arc<Queue<Result<(SocketAddr, Vec<u8>)>>>;
+
+/// The connections to each peer, by the address the peer is known by.
+type Connections = Arc<Mutex<HashMap<SocketAddr, Connection>>>;
+
+/// A request for the reactor to open a connection: the peer's address, the
+/// connection's ID, and the frames to send once it's open.
+type Connect = (SocketAddr, usize, UnboundedReceiver<Vec<u8>>);
+
+/// Connections to peers over TCP.
+#[derive(Debug)]
+pub struct Tcp {
+    connections: Connections,
+    connects: UnboundedSender<Connect>,
+    next_id: Arc<AtomicUsize>,
+    packets: Packets,
+}
+
+impl Tcp {
+    /// Starts accepting connections on the given listener, and reading the
+    /// UDP socket. Received packets are pushed to `packets`.
+    pub fn listen(
+        listener: TcpListener,
+        socket: UdpSocket,
+        packets: Packets,
+    ) -> Result<Tcp> {
+        let port = listener
+            .local_addr()
+            .chain_err(|| ErrorKind::CouldNotStartListener)?
+            .port();
+        let (connects, connect_requests) = unbounded();
+        let tcp = Tcp {
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            connects,
+            next_id: Arc::new(AtomicUsize::new(0)),
+            packets,
+        };
+
+        let connections = tcp.connections.clone();
+        let next_id = tcp.next_id.clone();
+        let packets = tcp.packets.clone();
+        let (started, start_result) = channel();
+        spawn(move || {
+            let result = Core::new().and_then(|core| {
+                let worker = Worker {
+                    connections,
+                    handle: core.handle(),
+                    next_id,
+                    packets,
+                    port,
+                };
+                worker.listen(listener)?;
+                worker.forward(socket)?;
+                Ok((core, worker))
+            });
+            match result {
+                Ok((mut core, worker)) => {
+                    let _ = started.send(Ok(()));
+                    // This runs until the `Tcp` is dropped.
+                    let _ = core.run(connect_requests.for_each(
+                        |(addr, id, frames)| {
+                            worker.connect(addr, id, frames);
+                            Ok(())
+                        },
+                    ));
+                }
+                Err(err) => {
+                    let _ = started.send(Err(err));
+                }
+            }
+        });
+
+        match start_result.recv() {
+            Ok(Ok(())) => Ok(tcp),
+            Ok(Err(err)) => {
+                Err(err).chain_err(|| ErrorKind::CouldNotStartListener)
+            }
+            Err(_) => Err(ErrorKind::CouldNotStartListener.into()),
+        }
+    }
+
+    /// Waits for a received packet, blocking until one is available or the
+    /// timeout, if there is one, passes.
+    pub fn recv(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<(SocketAddr, Vec<u8>)>> {
+        match timeout {
+            Some(timeout) => match self.packets.pop_timeout(timeout) {
+                Some(packet) => packet.map(Some),
+                None => Ok(None),
+            },
+            None => self.packets.pop().map(Some),
+        }
+    }
+
+    /// Returns a stream of received packets.
+    pub fn packets(&self) -> QueueStream<'_, Result<(SocketAddr, Vec<u8>)>> {
+        self.packets.stream()
+    }
+
+    /// Queues a packet to be sent to the peer, connecting to it if there is
+    /// no open connection. If connecting or sending fails, further attempts
+    /// are delayed by an exponentially increasing amount of time, and packets
+    /// sent in the meantime are refused.
+    pub fn send(&self, addr: SocketAddr, buf: &[u8]) -> IoResult<()> {
+        let mut frame = frame(buf);
+        let mut connections = self.connections.lock().unwrap();
+        let conn = connections.entry(addr).or_insert_with(Connection::new);
+
+        if let Some(ref frames) = conn.frames {
+            match frames.unbounded_send(frame) {
+                Ok(()) => return Ok(()),
+                // The connection closed, but its reader hasn't noticed yet.
+                Err(err) => frame = err.into_inner(),
+            }
+        }
+        if Instant::now() < conn.retry_at {
+            return Err(IoError::new(
+                IoErrorKind::NotConnected,
+                "waiting to reconnect",
+            ));
+        }
+
+        let (frames, queued) = unbounded();
+        frames.unbounded_send(frame).unwrap();
+        conn.id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        conn.frames = Some(frames);
+        self.connects
+            .unbounded_send((addr, conn.id, queued))
+            .map_err(|_| {
+                IoError::new(IoErrorKind::BrokenPipe, "TCP reactor stopped")
+            })
+    }
+}
+
+/// The state of the connection to a single peer.
+#[derive(Debug)]
+struct Connection {
+    failures: u32,
+    id: usize,
+    retry_at: Instant,
+
+    /// Frames to be written to the connection. While the connection is being
+    /// opened, they are queued until it's ready.
+    frames: Option<UnboundedSender<Vec<u8>>>,
+}
+
+impl Connection {
+    fn new() -> Connection {
+        Connection {
+            failures: 0,
+            id: 0,
+            retry_at: Instant::now(),
+            frames: None,
+        }
+    }
+
+    /// Records a failure to connect or send, delaying the next attempt.
+    fn failed(&mut self) {
+        let backoff = Duration::from_secs(1 << self.failures.min(6));
+        self.failures += 1;
+        self.retry_at = Instant::now() + backoff.min(MAX_BACKOFF);
+        self.frames = None;
+    }
+}
+
+/// The half of `Tcp` that runs on its reactor.
+#[derive(Clone)]
+struct Worker {
+    connections: Connections,
+    handle: Handle,
+    next_id: Arc<AtomicUsize>,
+    packets: Packets,
+    port: u16,
+}
+
+impl Worker {
+    /// Accepts connections from the listener.
+    fn listen(&self, listener: TcpListener) -> IoResult<()> {
+        let addr = listener.local_addr()?;
+        let listener =
+            AsyncTcpListener::from_listener(listener, &addr, &self.handle)?;
+        let worker = self.clone();
+        let accept = listener
+            .incoming()
+            .then(Ok::<_, ()>)
+            .for_each(move |result| {
+                match result {
+                    Ok((stream, addr)) => worker.accept(stream, addr),
+                    Err(err) => warn!("Could not accept a connection: {}", err),
+                }
+                Ok(())
+            });
+        self.handle.spawn(accept);
+        Ok(())
+    }
+
+    /// Pushes the packets received on a UDP socket to `packets`.
+    fn forward(&self, socket: UdpSocket) -> IoResult<()> {
+        let socket = AsyncUdpSocket::from_socket(socket, &self.handle)?;
+        let packets = self.packets.clone();
+        let mut buf = vec![0; 0x10000];
+        self.handle.spawn(poll_fn(move || loop {
+            match socket.recv_from(&mut buf) {
+                Ok((len, addr)) => {
+                    packets.push(Ok((addr, buf[..len].to_vec())))
+                }
+                Err(ref err) if err.kind() == IoErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady)
+                }
+                Err(err) => packets.push(
+                    Err(err).chain_err(|| ErrorKind::CouldNotRecvMessage),
+                ),
+            }
+        }));
+        Ok(())
+    }
+
+    /// Reads the preface from an inbound connection, then starts reading
+    /// packets from it. The connection is also used to send packets to the
+    /// peer, replacing any existing connection.
+    fn accept(&self, stream: AsyncTcpStream, mut addr: SocketAddr) {
+        let worker = self.clone();
+        let preface = timeout(read_exact(stream, [0; 6]), &self.handle)
+            .and_then(move |(stream, preface)| {
+                if &preface[..4] != PREFACE_MAGIC {
+                    return Err(IoError::new(
+                        IoErrorKind::InvalidData,
+                        "bad preface",
+                    ));
+                }
+                addr.set_port(LE::read_u16(&preface[4..]));
+                debug!("Accepted connection from {}", addr);
+
+                let id = worker.next_id.fetch_add(1, Ordering::SeqCst);
+                let (frames, queued) = unbounded();
+                {
+                    let mut connections = worker.connections.lock().unwrap();
+                    let conn =
+                        connections.entry(addr).or_insert_with(Connection::new);
+                    conn.failures = 0;
+                    conn.id = id;
+                    conn.frames = Some(frames);
+                }
+                worker.start(addr, id, stream, queued);
+                Ok(())
+            })
+            .map_err(|err| warn!("Could not accept a connection: {}", err));
+        self.handle.spawn(preface);
+    }
+
+    /// Opens a connection to a peer, then starts sending it the queued frames
+    /// and reading packets from it.
+    fn connect(
+        &self,
+        addr: SocketAddr,
+        id: usize,
+        frames: UnboundedReceiver<Vec<u8>>,
+    ) {
+        let mut preface = PREFACE_MAGIC.to_vec();
+        preface.extend(&[0; 2]);
+        LE::write_u16(&mut preface[PREFACE_MAGIC.len()..], self.port);
+
+        let connect = AsyncTcpStream::connect(&addr, &self.handle)
+            .and_then(move |stream| write_all(stream, preface));
+        let worker = self.clone();
+        let connect = timeout(connect, &self.handle).then(move |result| {
+            match result {
+                Ok((stream, _)) => {
+                    debug!("Connected to {}", addr);
+                    worker.update(addr, id, |conn| conn.failures = 0);
+                    worker.start(addr, id, stream, frames);
+                }
+                Err(err) => {
+                    debug!("Could not connect to {}: {}", addr, err);
+                    worker.update(addr, id, Connection::failed);
+                }
+            }
+            Ok(())
+        });
+        self.handle.spawn(connect);
+    }
+
+    /// Starts writing frames to and reading packets from an open connection.
+    fn start(
+        &self,
+        addr: SocketAddr,
+        id: usize,
+        stream: AsyncTcpStream,
+        frames: UnboundedReceiver<Vec<u8>>,
+    ) {
+        let (reader, writer) = stream.split();
+
+        let handle = self.handle.clone();
+        let worker = self.clone();
+        let write = frames
+            .map_err(|()| -> IoError { unreachable!() })
+            .fold(writer, move |writer, frame| {
+                timeout(write_all(writer, frame), &handle)
+                    .map(|(writer, _)| writer)
+            })
+            .then(move |result| {
+                if let Err(err) = result {
+                    debug!("Could not send to {}: {}", addr, err);
+                    worker.update(addr, id, Connection::failed);
+                }
+                Ok(())
+            });
+        self.handle.spawn(write);
+
+        let packets = self.packets.clone();
+        let worker = self.clone();
+        let read = unfold(reader, |reader| Some(read_frame(reader)))
+            .for_each(move |buf| {
+                packets.push(Ok((addr, buf)));
+                Ok(())
+            })
+            .or_else(move |err| {
+                debug!("Connection with {} closed: {}", addr, err);
+                worker.update(addr, id, |conn| conn.frames = None);
+                Ok(())
+            });
+        self.handle.spawn(read);
+    }
+
+    /// Updates the connection to a peer, unless it has since been replaced by
+    /// another.
+    fn update<F: FnOnce(&mut Connection)>(
+        &self,
+        addr: SocketAddr,
+        id: usize,
+        f: F,
+    ) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(conn) = connections.get_mut(&addr) {
+            if conn.id == id {
+                f(conn);
+            }
+        }
+    }
+}
+
+/// Fails with `TimedOut` if the future doesn't finish within `TIMEOUT`.
+fn timeout<F: Future<Error = IoError>>(
+    f: F,
+    handle: &Handle,
+) -> impl Future<Item = F::Item, Error = IoError> {
+    let timeout = Timeout::new(TIMEOUT, handle)
+        .into_future()
+        .flatten()
+        .and_then(|()| Err(IoError::new(IoErrorKind::TimedOut, "timed out")));
+    f.select(timeout)
+        .map(|(item, _)| item)
+        .map_err(|(err, _)| err)
+}
+
+/// Reads a single length-prefixed frame.
+fn read_frame<R: AsyncRead>(
+    r: R,
+) -> impl Future<Item = (Vec<u8>, R), Error = IoError> {
+    read_exact(r, [0; 4])
+        .and_then(|(r, len)| {
+            let len = LE::read_u32(&len) as usize;
+            if len > MAX_FRAME_LEN {
+                return Err(IoError::new(
+                    IoErrorKind::InvalidData,
+                    "frame too long",
+                ));
+            }
+            Ok(read_exact(r, vec![0; len]))
+        })
+        .flatten()
+        .map(|(r, buf)| (buf, r))
+}
+
+/// Prefixes a packet with its length.
+fn frame(buf: &[u8]) -> Vec<u8> {
+    assert!(buf.len() <= MAX_FRAME_LEN);
+    let mut frame = vec![0; 4];
+    LE::write_u32(&mut frame, buf.len() as u32);
+    frame.extend(buf);
+    frame
+}
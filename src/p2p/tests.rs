@@ -1,4 +1,16 @@
-use p2p::Message;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread::spawn;
+use std::time::Duration;
+
+use futures::Stream;
+use tokio_core::reactor::Core;
+
+use blockchain::Chain;
+use keys::Keypair;
+use p2p::secure::Secure;
+use p2p::{Message, Transport, MAX_BLOCK_RANGE_LEN, P2P};
+use util::vec_to_arrayvec;
 
 quickcheck! {
     fn serialize_parse_is_identity(msg: Message) -> () {
@@ -7,4 +19,208 @@ quickcheck! {
         let msg2 = Message::parse_from(&buf).expect("Failed to parse");
         assert_eq!(msg, msg2);
     }
+
+    fn kind_is_tag(msg: Message) -> () {
+        let mut buf = Vec::new();
+        msg.write_to(&mut buf).expect("Failed to serialize");
+        assert_eq!(buf[0] as usize, msg.kind());
+    }
+}
+
+#[test]
+fn tcp_send_recv() {
+    let a = P2P::with_opts(0, true).expect("Failed to start a");
+    let b = P2P::with_opts(0, true).expect("Failed to start b");
+    let a_addr = SocketAddr::from(([127, 0, 0, 1], a.port()));
+    let b_addr = SocketAddr::from(([127, 0, 0, 1], b.port()));
+
+    a.send(b_addr, &Message::Ping).expect("Failed to send to b");
+    assert_eq!(b.recv().expect("Failed to recv"), (a_addr, Message::Ping));
+
+    // b should reply over the connection a opened.
+    b.send(a_addr, &Message::Pong).expect("Failed to send to a");
+    assert_eq!(a.recv().expect("Failed to recv"), (b_addr, Message::Pong));
+
+    let msg = Message::BlockRequest(42);
+    a.send(b_addr, &msg).expect("Failed to send to b");
+    assert_eq!(b.recv().expect("Failed to recv"), (a_addr, msg));
+}
+
+#[test]
+fn recv_times_out() {
+    for &use_tcp in &[false, true] {
+        let a = P2P::with_opts(0, use_tcp).expect("Failed to start a");
+        let b = P2P::with_opts(0, use_tcp).expect("Failed to start b");
+        let a_addr = SocketAddr::from(([127, 0, 0, 1], a.port()));
+        let b_addr = SocketAddr::from(([127, 0, 0, 1], b.port()));
+
+        let timeout = Duration::from_millis(50);
+        assert!(b.recv_timeout(timeout).expect("Failed to recv").is_none());
+        a.send(b_addr, &Message::Ping).expect("Failed to send to b");
+        assert_eq!(
+            b.recv_timeout(Duration::from_secs(5)).expect("Failed to recv"),
+            Some((a_addr, Message::Ping))
+        );
+    }
+}
+
+#[test]
+fn incoming_stream() {
+    for &use_tcp in &[false, true] {
+        let mut core = Core::new().expect("Failed to start a reactor");
+        let a = P2P::with_opts(0, use_tcp).expect("Failed to start a");
+        let b = P2P::with_opts(0, use_tcp).expect("Failed to start b");
+        let a_addr = SocketAddr::from(([127, 0, 0, 1], a.port()));
+        let b_addr = SocketAddr::from(([127, 0, 0, 1], b.port()));
+
+        let incoming = b.incoming(&core.handle()).expect("Failed to listen");
+        a.send(b_addr, &Message::Ping).expect("Failed to send to b");
+        a.send(b_addr, &Message::Pong).expect("Failed to send to b");
+        let msgs = core
+            .run(incoming.take(2).collect())
+            .expect("Failed to recv");
+        assert_eq!(msgs, [(a_addr, Message::Ping), (a_addr, Message::Pong)]);
+    }
+}
+
+#[test]
+fn block_range_fits_in_a_packet() {
+    let keypair = Keypair::from_seed(&[0; 32]);
+    let mut chain = Chain::new();
+    for _ in 0..MAX_BLOCK_RANGE_LEN {
+        let data = (0..255).collect::<Vec<u8>>();
+        chain.mine(vec_to_arrayvec(data).unwrap(), &keypair);
+    }
+    let blocks = (1..chain.len()).map(|i| chain[i].clone()).collect();
+
+    let mut buf = Vec::new();
+    Message::BlockRangeResponse(blocks)
+        .write_to(&mut buf)
+        .expect("Failed to serialize");
+    assert!(buf.len() <= 65507);
+}
+
+fn secure(seed: u8, network_key: &str) -> (SocketAddr, Secure) {
+    let addr = SocketAddr::from(([10, 0, 0, seed], 10101));
+    let keypair = Keypair::from_seed(&[seed; 32]);
+    (addr, Secure::new(keypair, network_key))
+}
+
+/// Opens a packet, asserting that it's authentic, and returns the message and
+/// replies.
+fn open(
+    secure: &Secure,
+    from: SocketAddr,
+    packet: &[u8],
+) -> (Option<Vec<u8>>, Vec<Vec<u8>>) {
+    let mut replies = Vec::new();
+    let msg = secure
+        .open(from, packet, &mut replies)
+        .expect("Failed to open");
+    (msg, replies)
+}
+
+#[test]
+fn secure_session() {
+    let (a_addr, a) = secure(0, "hunter2");
+    let (b_addr, b) = secure(1, "hunter2");
+
+    let init = a.seal(b_addr, b"foo").expect("Failed to seal");
+    assert_eq!(init.len(), 1);
+    let (msg, response) = open(&b, a_addr, &init[0]);
+    assert_eq!(msg, None);
+    let (msg, foo) = open(&a, b_addr, &response[0]);
+    assert_eq!(msg, None);
+    assert_eq!(open(&b, a_addr, &foo[0]), (Some(b"foo".to_vec()), vec![]));
+
+    let bar = b.seal(a_addr, b"bar").expect("Failed to seal");
+    assert_eq!(open(&a, b_addr, &bar[0]), (Some(b"bar".to_vec()), vec![]));
+
+    // Reordered packets are fine.
+    let one = b.seal(a_addr, b"1").expect("Failed to seal");
+    let two = b.seal(a_addr, b"2").expect("Failed to seal");
+    assert_eq!(open(&a, b_addr, &two[0]), (Some(b"2".to_vec()), vec![]));
+    assert_eq!(open(&a, b_addr, &one[0]), (Some(b"1".to_vec()), vec![]));
+
+    // Replayed and tampered packets are dropped. b can't tell a tampered
+    // packet from one on a session a has forgotten, so it starts a handshake,
+    // but only one.
+    assert!(a.open(b_addr, &bar[0], &mut Vec::new()).is_err());
+    let mut baz = a.seal(b_addr, b"baz").expect("Failed to seal").remove(0);
+    let last = baz.len() - 1;
+    baz[last] ^= 1;
+    let mut replies = Vec::new();
+    assert_eq!(b.open(a_addr, &baz, &mut replies).ok(), Some(None));
+    assert_eq!(replies.len(), 1);
+    assert!(b.open(a_addr, &baz, &mut replies).is_err());
+    assert_eq!(replies.len(), 1);
+}
+
+#[test]
+fn secure_rejects_other_network_keys() {
+    let (a_addr, a) = secure(0, "hunter2");
+    let (b_addr, b) = secure(1, "hunter3");
+
+    let init = a.seal(b_addr, b"foo").expect("Failed to seal");
+    let mut replies = Vec::new();
+    assert!(b.open(a_addr, &init[0], &mut replies).is_err());
+    assert!(replies.is_empty());
+
+    let ping = a.seal_discovery(b"ping");
+    assert!(b.open(a_addr, &ping, &mut Vec::new()).is_err());
+    assert_eq!(open(&a, a_addr, &ping), (Some(b"ping".to_vec()), vec![]));
+}
+
+#[test]
+fn secure_recovers_after_restart() {
+    let (a_addr, a) = secure(0, "hunter2");
+    let (b_addr, b) = secure(1, "hunter2");
+    let init = a.seal(b_addr, b"foo").expect("Failed to seal");
+    let (_, response) = open(&b, a_addr, &init[0]);
+    let (_, foo) = open(&a, b_addr, &response[0]);
+    open(&b, a_addr, &foo[0]);
+
+    // a forgets its session, so it can't read b's messages. Since b is now a
+    // stranger to a, they're dropped without a reply.
+    let (_, a) = secure(0, "hunter2");
+    let bar = b.seal(a_addr, b"bar").expect("Failed to seal");
+    let mut replies = Vec::new();
+    assert!(a.open(b_addr, &bar[0], &mut replies).is_err());
+    assert!(replies.is_empty());
+
+    // Once a sends to b, it starts a new handshake, and doesn't start another
+    // for messages it still can't read. b keeps its session until a sends on
+    // the new one.
+    let init = a.seal(b_addr, b"qux").expect("Failed to seal");
+    assert!(a.open(b_addr, &bar[0], &mut replies).is_err());
+    assert!(replies.is_empty());
+    let (_, response) = open(&b, a_addr, &init[0]);
+    let (_, qux) = open(&a, b_addr, &response[0]);
+    assert_eq!(open(&b, a_addr, &qux[0]), (Some(b"qux".to_vec()), vec![]));
+
+    let baz = b.seal(a_addr, b"baz").expect("Failed to seal");
+    assert_eq!(open(&a, b_addr, &baz[0]), (Some(b"baz".to_vec()), vec![]));
+}
+
+#[test]
+fn encrypted_send_recv() {
+    let mut a = P2P::with_port(0).expect("Failed to start a");
+    let mut b = P2P::with_port(0).expect("Failed to start b");
+    a.set_network_key(Keypair::from_seed(&[0; 32]), "hunter2");
+    b.set_network_key(Keypair::from_seed(&[1; 32]), "hunter2");
+    let a_addr = SocketAddr::from(([127, 0, 0, 1], a.port()));
+    let b_addr = SocketAddr::from(([127, 0, 0, 1], b.port()));
+
+    // a only sends the ping once it receives b's handshake response.
+    let a = Arc::new(a);
+    a.send(b_addr, &Message::Ping).expect("Failed to send to b");
+    let pong = {
+        let a = a.clone();
+        spawn(move || a.recv())
+    };
+    assert_eq!(b.recv().expect("Failed to recv"), (a_addr, Message::Ping));
+
+    b.send(a_addr, &Message::Pong).expect("Failed to send to a");
+    let pong = pong.join().unwrap().expect("Failed to recv");
+    assert_eq!(pong, (b_addr, Message::Pong));
 }
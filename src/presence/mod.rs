@@ -0,0 +1,253 @@
+//! Who is at the event, and what they've claimed, derived by replaying the
+//! check-ins in the chain.
+//!
+//! An arrival starts a visit, and a departure ends it. An arrival while an
+//! attendee is already here, or a departure while they aren't, is a repeated
+//! swipe, and doesn't change when their visit started or add to their time on
+//! site.
+//!
+//! Meals, workshops and swag can each be claimed once per attendee. Stations
+//! refuse check-ins that would claim one again, but two stations may still
+//! record the same claim before hearing of each other's; only the first
+//! counts.
+//!
+//! Card readers sometimes read a card twice, and people swipe again when
+//! they aren't sure it worked, so stations also refuse a check-in of the same
+//! kind as one the attendee made within the last few seconds.
+
+#[cfg(test)]
+mod tests;
+
+use std::collections::btree_map::{BTreeMap, Iter};
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use blockchain::{Block, Chain, Hash};
+use cards::{CheckIn, EventKind};
+
+/// What is known of an attendee's presence.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Presence {
+    /// When the attendee first arrived, as a Unix timestamp. This is `None`
+    /// if they've only been seen leaving.
+    pub first_arrival: Option<u64>,
+
+    /// When the attendee's current visit started, if they are here.
+    pub arrived_at: Option<u64>,
+
+    /// The seconds spent on site in visits that have ended.
+    pub time_on_site: u64,
+
+    /// The meals, workshops and swag the attendee has claimed.
+    pub claimed: BTreeSet<EventKind>,
+
+    /// The number of check-ins that claimed something the attendee had
+    /// already claimed.
+    pub duplicate_claims: u64,
+
+    /// When the attendee last checked in with each kind of event.
+    pub last_check_ins: BTreeMap<EventKind, u64>,
+}
+
+impl Presence {
+    /// Returns whether the attendee is here.
+    pub fn is_here(&self) -> bool {
+        self.arrived_at.is_some()
+    }
+
+    /// Returns the seconds spent on site as of the given time, including the
+    /// current visit.
+    pub fn time_on_site_at(&self, now: u64) -> u64 {
+        let current = self.arrived_at.map_or(0, |t| now.saturating_sub(t));
+        self.time_on_site + current
+    }
+
+    /// Updates the presence with a check-in.
+    fn apply(&mut self, kind: &EventKind, timestamp: u64) {
+        let last = self.last_check_ins.entry(kind.clone()).or_insert(0);
+        *last = (*last).max(timestamp);
+
+        match *kind {
+            EventKind::Arrival => if self.arrived_at.is_none() {
+                self.arrived_at = Some(timestamp);
+                self.first_arrival = Some(
+                    self.first_arrival.map_or(timestamp, |t| t.min(timestamp)),
+                );
+            },
+            EventKind::Departure => if let Some(t) = self.arrived_at.take() {
+                // Stations' clocks may disagree a little.
+                self.time_on_site += timestamp.saturating_sub(t);
+            },
+            EventKind::Meal(_) | EventKind::Workshop(_) | EventKind::Swag => {
+                if !self.claimed.insert(kind.clone()) {
+                    self.duplicate_claims += 1;
+                }
+            }
+        }
+    }
+}
+
+/// A rule a check-in would break.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Violation {
+    /// The attendee already claimed the meal, workshop or swag.
+    AlreadyClaimed(EventKind),
+
+    /// The attendee checked in with the same kind of event too recently, so
+    /// this is probably a repeated swipe.
+    TooSoon {
+        /// The kind of event.
+        kind: EventKind,
+
+        /// The seconds since the previous check-in.
+        elapsed: u64,
+    },
+}
+
+impl Display for Violation {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            Violation::AlreadyClaimed(ref kind) => {
+                write!(fmt, "The attendee already claimed {}", kind)
+            }
+            Violation::TooSoon { ref kind, elapsed } => write!(
+                fmt,
+                "The attendee already checked in for {} {}s ago",
+                kind, elapsed
+            ),
+        }
+    }
+}
+
+/// The presence of every attendee who has checked in, as of some block in a
+/// chain.
+#[derive(Clone, Debug)]
+pub struct PresenceState {
+    attendees: BTreeMap<String, Presence>,
+    len: u64,
+    tip: Option<Hash>,
+}
+
+impl PresenceState {
+    /// Creates a state in which nobody has checked in, before any blocks.
+    pub fn new() -> PresenceState {
+        PresenceState {
+            attendees: BTreeMap::new(),
+            len: 0,
+            tip: None,
+        }
+    }
+
+    /// Replays the check-ins in a chain.
+    pub fn from_chain(chain: &Chain) -> PresenceState {
+        let mut state = PresenceState::new();
+        state.update(chain);
+        state
+    }
+
+    /// Applies the next block. Blocks must be applied in order, starting
+    /// with the genesis block.
+    pub fn apply(&mut self, block: &Block) {
+        assert_eq!(block.index, self.len, "Blocks must be applied in order");
+        if let Some(check_in) = CheckIn::from_block(block) {
+            self.apply_check_in(&check_in);
+        }
+        self.len += 1;
+        self.tip = Some(block.hash);
+    }
+
+    /// Applies a check-in.
+    fn apply_check_in(&mut self, check_in: &CheckIn) {
+        self.attendees
+            .entry(check_in.attendee.clone())
+            .or_default()
+            .apply(&check_in.kind, check_in.timestamp);
+    }
+
+    /// Checks that a check-in doesn't break any rules for its kind, and
+    /// isn't within `window` seconds of a check-in of the same kind by the
+    /// attendee.
+    pub fn check(
+        &self,
+        check_in: &CheckIn,
+        window: u64,
+    ) -> Result<(), Violation> {
+        let kind = &check_in.kind;
+        let presence = match self.get(&check_in.attendee) {
+            Some(presence) => presence,
+            None => return Ok(()),
+        };
+        if kind.is_once_per_attendee() && presence.claimed.contains(kind) {
+            return Err(Violation::AlreadyClaimed(kind.clone()));
+        }
+        if let Some(&last) = presence.last_check_ins.get(kind) {
+            // Stations' clocks may disagree a little.
+            let elapsed = check_in.timestamp.saturating_sub(last);
+            if elapsed < window {
+                return Err(Violation::TooSoon {
+                    kind: kind.clone(),
+                    elapsed,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the blocks of the chain that haven't been applied yet. If the
+    /// chain no longer contains the last block applied, because a fork was
+    /// resolved, the whole chain is replayed.
+    pub fn update(&mut self, chain: &Chain) {
+        if let Some(tip) = self.tip {
+            if self.len > chain.len() || chain[self.len - 1].hash != tip {
+                *self = PresenceState::new();
+            }
+        }
+        for i in self.len..chain.len() {
+            self.apply(&chain[i]);
+        }
+    }
+
+    /// Returns the presence of an attendee, if they've checked in.
+    pub fn get(&self, attendee: &str) -> Option<&Presence> {
+        self.attendees.get(attendee)
+    }
+
+    /// Returns the attendees who are here.
+    pub fn here(&self) -> Vec<(&str, &Presence)> {
+        self.iter().filter(|&(_, p)| p.is_here()).collect()
+    }
+
+    /// Returns an iterator over every attendee who has checked in, ordered by
+    /// their identifier.
+    pub fn iter(&self) -> PresenceIter<'_> {
+        PresenceIter(self.attendees.iter())
+    }
+
+    /// Returns the number of blocks that have been applied.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns whether no blocks have been applied.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Default for PresenceState {
+    fn default() -> PresenceState {
+        PresenceState::new()
+    }
+}
+
+/// An iterator over the attendees in a `PresenceState`.
+#[derive(Debug)]
+pub struct PresenceIter<'a>(Iter<'a, String, Presence>);
+
+impl<'a> Iterator for PresenceIter<'a> {
+    type Item = (&'a str, &'a Presence);
+
+    fn next(&mut self) -> Option<(&'a str, &'a Presence)> {
+        self.0.next().map(|(a, p)| (a.as_str(), p))
+    }
+}
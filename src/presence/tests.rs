@@ -0,0 +1,127 @@
+use blockchain::Chain;
+use cards::{CheckIn, EventKind};
+use keys::Keypair;
+use presence::{Presence, PresenceState, Violation};
+
+fn check_in(chain: &mut Chain, attendee: &str, kind: EventKind, at: u64) {
+    let check_in = CheckIn {
+        attendee: attendee.to_string(),
+        station: "station".to_string(),
+        kind,
+        timestamp: at,
+    };
+    let keypair = Keypair::from_seed(&[0; 32]);
+    chain.mine_at(at, check_in.to_data().unwrap(), &keypair);
+}
+
+#[test]
+fn visits() {
+    let mut chain = Chain::new();
+    check_in(&mut chain, "ada", EventKind::Arrival, 100);
+    check_in(&mut chain, "grace", EventKind::Arrival, 150);
+    check_in(&mut chain, "ada", EventKind::Arrival, 160);
+    check_in(&mut chain, "ada", EventKind::Departure, 200);
+    check_in(&mut chain, "ada", EventKind::Departure, 210);
+    check_in(&mut chain, "ada", EventKind::Arrival, 300);
+    check_in(&mut chain, "linus", EventKind::Departure, 310);
+
+    let state = PresenceState::from_chain(&chain);
+    assert_eq!(state.len(), chain.len());
+    let ada = state.get("ada").unwrap();
+    let last_check_ins =
+        vec![(EventKind::Arrival, 300), (EventKind::Departure, 210)];
+    assert_eq!(
+        *ada,
+        Presence {
+            first_arrival: Some(100),
+            arrived_at: Some(300),
+            time_on_site: 100,
+            last_check_ins: last_check_ins.into_iter().collect(),
+            ..Presence::default()
+        }
+    );
+    assert_eq!(ada.time_on_site_at(350), 150);
+    assert_eq!(state.get("grace").unwrap().time_on_site_at(250), 100);
+
+    let linus = state.get("linus").unwrap();
+    assert!(!linus.is_here());
+    assert_eq!(linus.first_arrival, None);
+
+    let here = state.here().into_iter().map(|(a, _)| a).collect::<Vec<_>>();
+    assert_eq!(here, ["ada", "grace"]);
+}
+
+#[test]
+fn incremental_updates() {
+    let mut chain = Chain::new();
+    check_in(&mut chain, "ada", EventKind::Arrival, 100);
+    let mut state = PresenceState::from_chain(&chain);
+
+    let mut fork = chain.clone();
+    check_in(&mut chain, "ada", EventKind::Departure, 200);
+    state.update(&chain);
+    assert!(!state.get("ada").unwrap().is_here());
+    assert_eq!(state.len(), 3);
+
+    // Updating with a chain that doesn't contain the applied blocks replays
+    // it instead.
+    check_in(&mut fork, "grace", EventKind::Arrival, 150);
+    state.update(&fork);
+    assert!(state.get("ada").unwrap().is_here());
+    assert!(state.get("grace").unwrap().is_here());
+}
+
+#[test]
+fn claims() {
+    let mut chain = Chain::new();
+    check_in(&mut chain, "ada", EventKind::Meal(1), 100);
+    check_in(&mut chain, "ada", EventKind::Workshop("rust".to_string()), 110);
+    check_in(&mut chain, "ada", EventKind::Meal(1), 120);
+    let state = PresenceState::from_chain(&chain);
+
+    let ada = state.get("ada").unwrap();
+    assert_eq!(ada.claimed.len(), 2);
+    assert_eq!(ada.duplicate_claims, 1);
+    assert!(!ada.is_here());
+
+    let attempt = |attendee: &str, kind| CheckIn {
+        attendee: attendee.to_string(),
+        station: "station".to_string(),
+        kind,
+        timestamp: 200,
+    };
+    assert_eq!(
+        state.check(&attempt("ada", EventKind::Meal(1)), 0),
+        Err(Violation::AlreadyClaimed(EventKind::Meal(1)))
+    );
+    assert_eq!(state.check(&attempt("ada", EventKind::Meal(2)), 0), Ok(()));
+    let grace = attempt("grace", EventKind::Meal(1));
+    assert_eq!(state.check(&grace, 0), Ok(()));
+    assert_eq!(state.check(&attempt("ada", EventKind::Arrival), 0), Ok(()));
+}
+
+#[test]
+fn repeated_swipes() {
+    let mut chain = Chain::new();
+    check_in(&mut chain, "ada", EventKind::Arrival, 100);
+    check_in(&mut chain, "ada", EventKind::Departure, 130);
+    let state = PresenceState::from_chain(&chain);
+
+    let attempt = |kind, timestamp| CheckIn {
+        attendee: "ada".to_string(),
+        station: "station".to_string(),
+        kind,
+        timestamp,
+    };
+    assert_eq!(
+        state.check(&attempt(EventKind::Arrival, 130), 60),
+        Err(Violation::TooSoon {
+            kind: EventKind::Arrival,
+            elapsed: 30,
+        })
+    );
+    assert_eq!(state.check(&attempt(EventKind::Arrival, 160), 60), Ok(()));
+    assert_eq!(state.check(&attempt(EventKind::Arrival, 130), 0), Ok(()));
+    assert!(state.check(&attempt(EventKind::Departure, 150), 60).is_err());
+    assert_eq!(state.check(&attempt(EventKind::Swag, 130), 60), Ok(()));
+}
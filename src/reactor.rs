@@ -0,0 +1,95 @@
+//! The reactor a `Client` is run on. Queued messages are sent, received
+//! messages are handled, and discovery pings and status requests are sent on
+//! timers, all from a single thread.
+
+use std::time::Duration;
+
+use futures::future::select_all;
+use futures::{Future, Stream};
+use tokio_core::reactor::{Core, Handle, Interval};
+
+use errors::{Error, ErrorKind, Result, ResultExt};
+use p2p::Transport;
+use util::log_err;
+use {Client, POLL_INTERVAL};
+
+/// A task run on the reactor.
+type Task<'a> = Box<dyn Future<Item = (), Error = Error> + 'a>;
+
+impl<T: Transport> Client<T> {
+    /// Runs the reactor on the current thread, until the client is shut
+    /// down.
+    pub(crate) fn run_reactor(&self) -> Result<()> {
+        let mut core =
+            Core::new().chain_err(|| ErrorKind::CouldNotStartReactor)?;
+        let handle = core.handle();
+
+        let sender = self.send_queue
+            .stream()
+            .for_each(move |(addr, msg)| {
+                self.send(addr, msg);
+                Ok(())
+            })
+            .map_err(|()| unreachable!());
+        let receiver = self.transport
+            .incoming(&handle)?
+            .then(move |result| {
+                match result {
+                    Ok((addr, msg)) => self.handle_message(addr, msg),
+                    Err(err) => self.recv_failed(err),
+                }
+                Ok(())
+            })
+            .for_each(|()| Ok(()));
+
+        self.send_discovery();
+        let discovery = every(&handle, self.discovery_ping_interval)?
+            .for_each(move |()| {
+                self.send_discovery();
+                Ok(())
+            });
+        self.request_status();
+        let status = every(&handle, self.status_check_interval)?
+            .for_each(move |()| {
+                self.request_status();
+                Ok(())
+            });
+        let shutdown = every(&handle, POLL_INTERVAL)?
+            .take_while(move |()| Ok(!self.is_shutting_down()))
+            .for_each(|()| Ok(()));
+
+        // Only the shutdown task finishes on its own, and the rest are
+        // dropped once it does.
+        let tasks: Vec<Task> = vec![
+            Box::new(sender),
+            Box::new(receiver),
+            Box::new(discovery),
+            Box::new(status),
+            Box::new(shutdown),
+        ];
+        core.run(select_all(tasks))
+            .map(|_| ())
+            .map_err(|(err, _, _)| err)
+    }
+
+    /// Notes an error receiving a message.
+    fn recv_failed(&self, err: Error) {
+        match *err.kind() {
+            ErrorKind::InvalidPacket(_) | ErrorKind::UnauthenticatedPacket(_) => {
+                self.metrics.invalid_packets.inc()
+            }
+            _ => {}
+        }
+        log_err(Err(err));
+    }
+}
+
+/// Returns a stream that yields once per interval.
+fn every(
+    handle: &Handle,
+    interval: Duration,
+) -> Result<impl Stream<Item = (), Error = Error>> {
+    let interval = Interval::new(interval, handle)
+        .chain_err(|| ErrorKind::CouldNotStartReactor)?;
+    Ok(interval.map_err(|err| Error::with_chain(err, "A timer failed")))
+}
@@ -0,0 +1,258 @@
+//! The registry of attendees, imported from the registration CSV, which maps
+//! the cards that are read to the people they belong to.
+//!
+//! The CSV must start with a header row. The `name` and `U Card ID` columns
+//! are required, and `email` and `dietary` columns are used if present; other
+//! columns are ignored. Header names are matched ignoring case, spaces and
+//! punctuation. Attendees without a U Card ID are kept, but can't be matched
+//! to a card.
+//!
+//! If card data is hashed before it's recorded, the registry is given the
+//! same secret, so it can match check-ins to attendees by their hashed U Card
+//! IDs.
+
+#[cfg(test)]
+mod tests;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use cards::{hash_card_field, CheckIn};
+use errors::{ErrorKind, Result, ResultExt};
+
+/// A registered attendee.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Attendee {
+    /// The attendee's name.
+    pub name: String,
+
+    /// The attendee's email address.
+    pub email: String,
+
+    /// The ID of the attendee's U Card, if they have one.
+    pub ucard_id: String,
+
+    /// Any dietary restrictions the attendee registered with.
+    pub dietary: String,
+}
+
+/// The registered attendees, indexed by U Card ID.
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    attendees: Vec<Attendee>,
+    by_card_hash: HashMap<String, usize>,
+    by_ucard_id: HashMap<String, usize>,
+    card_secret: Option<String>,
+}
+
+impl Registry {
+    /// Creates an empty registry.
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    /// Imports a registry from the registration CSV.
+    pub fn from_csv(csv: &str) -> Result<Registry> {
+        let mut records = parse_csv(csv)?.into_iter();
+        let header = match records.next() {
+            Some((_, header)) => header,
+            None => return Ok(Registry::new()),
+        };
+        let column = |names: &[&str]| {
+            header.iter().position(|h| {
+                let h = h.chars()
+                    .filter(|c| c.is_alphanumeric())
+                    .collect::<String>()
+                    .to_lowercase();
+                names.contains(&h.as_str())
+            })
+        };
+        let missing = |name: &str| {
+            ErrorKind::InvalidRegistry(1, format!("No {} column", name))
+        };
+        let name =
+            column(&["name", "fullname"]).ok_or_else(|| missing("name"))?;
+        let ucard_id = column(&["ucardid", "ucard", "cardid"])
+            .ok_or_else(|| missing("U Card ID"))?;
+        let email = column(&["email", "emailaddress"]);
+        let dietary = column(&["dietary", "dietaryrestrictions"]);
+
+        let mut registry = Registry::new();
+        for (line, fields) in records {
+            if fields.len() != header.len() {
+                let msg = format!(
+                    "Expected {} fields, found {}",
+                    header.len(),
+                    fields.len()
+                );
+                return Err(ErrorKind::InvalidRegistry(line, msg).into());
+            }
+            let field = |i: Option<usize>| {
+                i.map(|i| fields[i].trim().to_string()).unwrap_or_default()
+            };
+            registry.insert(Attendee {
+                name: field(Some(name)),
+                email: field(email),
+                ucard_id: field(Some(ucard_id)),
+                dietary: field(dietary),
+            });
+        }
+        Ok(registry)
+    }
+
+    /// Imports a registry from a registration CSV file.
+    pub fn load_from<P: AsRef<Path>>(path: P) -> Result<Registry> {
+        let path = path.as_ref();
+        let mut csv = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut csv))
+            .chain_err(|| ErrorKind::CouldNotReadRegistry(path.to_owned()))?;
+        Registry::from_csv(&csv)
+            .chain_err(|| ErrorKind::CouldNotReadRegistry(path.to_owned()))
+    }
+
+    /// Sets the secret card data is hashed with, so check-ins with hashed
+    /// card data can be matched to attendees.
+    pub fn set_card_secret(&mut self, secret: Option<String>) {
+        self.by_card_hash.clear();
+        if let Some(ref secret) = secret {
+            for (ucard_id, &i) in &self.by_ucard_id {
+                let hash = hash_card_field(secret, ucard_id);
+                self.by_card_hash.insert(hash, i);
+            }
+        }
+        self.card_secret = secret;
+    }
+
+    /// Adds an attendee, replacing any attendee with the same U Card ID.
+    pub fn insert(&mut self, attendee: Attendee) {
+        if attendee.ucard_id.is_empty() {
+            self.attendees.push(attendee);
+            return;
+        }
+        match self.by_ucard_id.get(&attendee.ucard_id) {
+            Some(&i) => {
+                warn!("U Card ID {} is registered twice", attendee.ucard_id);
+                self.attendees[i] = attendee;
+            }
+            None => {
+                let i = self.attendees.len();
+                if let Some(ref secret) = self.card_secret {
+                    let hash = hash_card_field(secret, &attendee.ucard_id);
+                    self.by_card_hash.insert(hash, i);
+                }
+                self.by_ucard_id.insert(attendee.ucard_id.clone(), i);
+                self.attendees.push(attendee);
+            }
+        }
+    }
+
+    /// Returns all the attendees, in the order they were registered.
+    pub fn attendees(&self) -> &[Attendee] {
+        &self.attendees
+    }
+
+    /// Returns the attendee with the given U Card ID.
+    pub fn get(&self, ucard_id: &str) -> Option<&Attendee> {
+        self.by_ucard_id
+            .get(ucard_id.trim())
+            .map(|&i| &self.attendees[i])
+    }
+
+    /// Returns the attendee whose U Card ID hashes to the given hash, with
+    /// the secret given to `set_card_secret`.
+    pub fn get_by_card_hash(&self, hash: &str) -> Option<&Attendee> {
+        self.by_card_hash.get(hash).map(|&i| &self.attendees[i])
+    }
+
+    /// Returns whether there are no attendees.
+    pub fn is_empty(&self) -> bool {
+        self.attendees.is_empty()
+    }
+
+    /// Returns the number of attendees.
+    pub fn len(&self) -> usize {
+        self.attendees.len()
+    }
+
+    /// Returns the attendee a card belongs to, given the fields that identify
+    /// it, such as its student ID. A card belongs to an attendee if one of
+    /// its fields is their U Card ID.
+    pub fn lookup_card(&self, fields: &[&str]) -> Option<&Attendee> {
+        fields.iter().find_map(|field| self.get(field))
+    }
+
+    /// Returns the attendee a check-in was made by, if they are known. The
+    /// check-in's card data may be hashed or not.
+    pub fn lookup_check_in(&self, check_in: &CheckIn) -> Option<&Attendee> {
+        check_in.attendee.split('^').find_map(|field| {
+            self.get(field).or_else(|| self.get_by_card_hash(field))
+        })
+    }
+
+    /// Returns the attendees whose U Card ID is the query, or whose name or
+    /// email contains it, ignoring case.
+    pub fn search(&self, query: &str) -> Vec<&Attendee> {
+        let query = query.trim().to_lowercase();
+        self.attendees
+            .iter()
+            .filter(|a| {
+                a.ucard_id == query || a.name.to_lowercase().contains(&query)
+                    || a.email.to_lowercase().contains(&query)
+            })
+            .collect()
+    }
+}
+
+/// Parses CSV, as described by RFC 4180, into records of fields, each with
+/// the line it starts on. Blank lines are skipped.
+fn parse_csv(csv: &str) -> Result<Vec<(usize, Vec<String>)>> {
+    let mut records = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut line = 1;
+    let mut start = 1;
+
+    let mut chars = csv.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => if chars.peek() == Some(&'"') {
+                chars.next();
+                field.push('"');
+            } else {
+                quoted = false;
+            },
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => fields.push(field.split_off(0)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                fields.push(field.split_off(0));
+                if fields.len() > 1 || !fields[0].is_empty() {
+                    records.push((start, fields.split_off(0)));
+                } else {
+                    fields.clear();
+                }
+                line += 1;
+                start = line;
+            }
+            c => {
+                if c == '\n' {
+                    line += 1;
+                }
+                field.push(c);
+            }
+        }
+    }
+    if quoted {
+        let msg = "Unterminated quoted field".to_string();
+        return Err(ErrorKind::InvalidRegistry(start, msg).into());
+    }
+    if !fields.is_empty() || !field.is_empty() {
+        fields.push(field);
+        records.push((start, fields));
+    }
+    Ok(records)
+}
@@ -0,0 +1,113 @@
+use cards::{hash_card, CheckIn, EventKind};
+use registry::{parse_csv, Attendee, Registry};
+
+const CSV: &str = "\
+Name,Email Address,U Card ID,Dietary Restrictions,T-Shirt Size\r
+Ada Lovelace,ada@example.com,1234567,,M\r
+\"Hopper, Grace\",grace@example.com,7654321,\"Vegetarian, \"\"no nuts\"\"\",L\r
+\r
+Walk In,walk.in@example.com,,,S\r
+";
+
+#[test]
+fn import() {
+    let registry = Registry::from_csv(CSV).expect("Failed to import");
+    assert_eq!(registry.len(), 3);
+    assert_eq!(
+        registry.get("7654321"),
+        Some(&Attendee {
+            name: "Hopper, Grace".to_string(),
+            email: "grace@example.com".to_string(),
+            ucard_id: "7654321".to_string(),
+            dietary: "Vegetarian, \"no nuts\"".to_string(),
+        })
+    );
+    assert_eq!(registry.attendees()[2].name, "Walk In");
+    assert_eq!(registry.get(""), None);
+}
+
+#[test]
+fn lookup() {
+    let registry = Registry::from_csv(CSV).expect("Failed to import");
+    let ada = registry.get("1234567");
+    assert!(ada.is_some());
+    assert_eq!(registry.lookup_card(&["B1234567", "1234567", "LOVELACE"]), ada);
+    assert_eq!(registry.lookup_card(&["B1111111", "1111111"]), None);
+
+    let check_in = CheckIn {
+        attendee: "B1234567^1234567^LOVELACE".to_string(),
+        station: "station".to_string(),
+        kind: EventKind::Arrival,
+        timestamp: 0,
+    };
+    assert_eq!(registry.lookup_check_in(&check_in), ada);
+}
+
+#[test]
+fn search() {
+    let registry = Registry::from_csv(CSV).expect("Failed to import");
+    let names = |query| {
+        registry
+            .search(query)
+            .into_iter()
+            .map(|a| a.name.as_str())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names("grace"), ["Hopper, Grace"]);
+    assert_eq!(names("1234567"), ["Ada Lovelace"]);
+    assert_eq!(names("EXAMPLE.COM").len(), 3);
+    assert!(names("nobody").is_empty());
+}
+
+#[test]
+fn invalid() {
+    let err = Registry::from_csv("Name,Email\nAda,ada@example.com\n");
+    assert!(err.is_err());
+    let err = Registry::from_csv("Name,U Card ID\nAda,1234567,extra\n");
+    assert!(err.is_err());
+    let err = Registry::from_csv("Name,U Card ID\n\"Ada,1234567\n");
+    assert!(err.is_err());
+}
+
+#[test]
+fn csv_lines() {
+    let records = parse_csv("a,\"b\nc\"\n\nd\n").expect("Failed to parse");
+    assert_eq!(
+        records,
+        [
+            (1, vec!["a".to_string(), "b\nc".to_string()]),
+            (4, vec!["d".to_string()]),
+        ]
+    );
+}
+
+#[test]
+fn hashed_lookup() {
+    let mut registry = Registry::from_csv(CSV).expect("Failed to import");
+    let check_in = |attendee| CheckIn {
+        attendee,
+        station: "station".to_string(),
+        kind: EventKind::Arrival,
+        timestamp: 0,
+    };
+    let hashed = check_in(hash_card("secret", &["B7654321", "7654321"]));
+    assert_eq!(registry.lookup_check_in(&hashed), None);
+
+    registry.set_card_secret(Some("secret".to_string()));
+    let grace = registry.get("7654321");
+    assert!(grace.is_some());
+    assert_eq!(registry.lookup_check_in(&hashed), grace);
+    let unhashed = check_in("B7654321^7654321".to_string());
+    assert_eq!(registry.lookup_check_in(&unhashed), grace);
+
+    registry.insert(Attendee {
+        name: "Late Registrant".to_string(),
+        ucard_id: "1111111".to_string(),
+        ..Attendee::default()
+    });
+    let late = check_in(hash_card("secret", &["1111111"]));
+    let late = registry.lookup_check_in(&late).unwrap();
+    assert_eq!(late.name, "Late Registrant");
+    let wrong = check_in(hash_card("guess", &["7654321"]));
+    assert_eq!(registry.lookup_check_in(&wrong), None);
+}
@@ -0,0 +1,170 @@
+//! Durable, append-only storage for the blockchain.
+//!
+//! The log is a sequence of records, each of which is a little-endian `u16`
+//! length followed by a block in the format written by `Block::write_to`. The
+//! first record is always the genesis block.
+
+#[cfg(test)]
+mod tests;
+
+use std::fs::{rename, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LE};
+
+use blockchain::{Block, Chain};
+use errors::{ErrorKind, Result, ResultExt};
+
+/// An append-only log of blocks on disk.
+#[derive(Debug)]
+pub struct Storage {
+    file: File,
+    path: PathBuf,
+}
+
+impl Storage {
+    /// Appends a block to the log, returning once it has been flushed to disk.
+    pub fn append(&mut self, block: &Block) -> Result<()> {
+        let buf = record(block);
+        self.file
+            .write_all(&buf)
+            .and_then(|()| self.file.sync_data())
+            .chain_err(|| ErrorKind::CouldNotWriteStorage(self.path.clone()))
+    }
+
+    /// Opens the log at the given path, returning it and the chain stored in
+    /// it. If the log does not exist or is empty, it is initialized with the
+    /// given chain.
+    ///
+    /// A record left incomplete by a crash at the end of the log is discarded.
+    /// Any other damage, or a chain that fails `Chain::is_valid`, is an error.
+    /// The chain loaded keeps the authorized stations of the given chain.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        chain: Chain,
+    ) -> Result<(Storage, Chain)> {
+        let path = path.as_ref().to_owned();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .chain_err(|| ErrorKind::CouldNotOpenStorage(path.clone()))?;
+
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)
+            .chain_err(|| ErrorKind::CouldNotOpenStorage(path.clone()))?;
+
+        if buf.is_empty() {
+            let mut storage = Storage { file, path };
+            storage.replace(&chain)?;
+            return Ok((storage, chain));
+        }
+
+        let (blocks, len) = parse_records(&buf)
+            .ok_or_else(|| ErrorKind::CorruptStorage(path.clone()))?;
+        if len != buf.len() {
+            warn!(
+                "Discarding {} bytes of incomplete record at the end of {}",
+                buf.len() - len,
+                path.display()
+            );
+            file.set_len(len as u64)
+                .and_then(|()| file.sync_all())
+                .chain_err(|| ErrorKind::CouldNotWriteStorage(path.clone()))?;
+        }
+
+        let mut blocks = blocks.into_iter();
+        let genesis = blocks
+            .next()
+            .ok_or_else(|| ErrorKind::CorruptStorage(path.clone()))?;
+        if &genesis != chain.genesis() {
+            bail!(ErrorKind::GenesisMismatch(path));
+        }
+
+        let mut chain = chain;
+        chain.truncate(1);
+        for block in blocks {
+            if !chain.push(block) {
+                bail!(ErrorKind::CorruptStorage(path));
+            }
+        }
+        if !chain.is_valid() {
+            bail!(ErrorKind::CorruptStorage(path));
+        }
+
+        info!("Loaded {} blocks from {}", chain.len(), path.display());
+        Ok((Storage { file, path }, chain))
+    }
+
+    /// Atomically replaces the contents of the log with the given chain.
+    pub fn replace(&mut self, chain: &Chain) -> Result<()> {
+        let mut buf = Vec::new();
+        for block in chain {
+            buf.extend(record(block));
+        }
+
+        let tmp_path = self.path.with_extension("tmp");
+        let path = &self.path;
+        let file = File::create(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(&buf)?;
+                file.sync_all()?;
+                rename(&tmp_path, path)?;
+                OpenOptions::new().read(true).append(true).open(path)
+            })
+            .chain_err(|| ErrorKind::CouldNotWriteStorage(path.clone()))?;
+
+        // Make the rename itself durable.
+        if let Some(dir) = path.parent() {
+            let dir = if dir == Path::new("") {
+                Path::new(".")
+            } else {
+                dir
+            };
+            File::open(dir)
+                .and_then(|dir| dir.sync_all())
+                .chain_err(|| ErrorKind::CouldNotWriteStorage(path.clone()))?;
+        }
+
+        self.file = file;
+        Ok(())
+    }
+
+    /// Flushes everything written to the log to disk. Appends and
+    /// replacements already are, so this is only a precaution before exiting.
+    pub fn sync(&mut self) -> Result<()> {
+        self.file
+            .sync_all()
+            .chain_err(|| ErrorKind::CouldNotWriteStorage(self.path.clone()))
+    }
+}
+
+/// Parses as many complete records as possible from the buffer, returning the
+/// blocks and the number of bytes they occupied. Returns `None` if a complete
+/// record does not contain a valid block.
+fn parse_records(mut buf: &[u8]) -> Option<(Vec<Block>, usize)> {
+    let mut blocks = Vec::new();
+    let mut len = 0;
+    while buf.len() >= 2 {
+        let l = LE::read_u16(buf) as usize;
+        if buf.len() < l + 2 {
+            break;
+        }
+        blocks.push(Block::parse_from(&buf[2..l + 2])?);
+        buf = &buf[l + 2..];
+        len += l + 2;
+    }
+    Some((blocks, len))
+}
+
+/// Serializes a block as a length-prefixed record.
+fn record(block: &Block) -> Vec<u8> {
+    let mut buf = vec![0; 2];
+    block.write_to(&mut buf).unwrap();
+    let l = buf.len() - 2;
+    assert!(l <= 0xffff);
+    LE::write_u16(&mut buf[..2], l as u16);
+    buf
+}
@@ -0,0 +1,84 @@
+use std::env::temp_dir;
+use std::fs::{remove_file, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use blockchain::Chain;
+use keys::Keypair;
+use storage::Storage;
+use util::str_to_arrayvec;
+
+fn keypair() -> Keypair {
+    Keypair::from_seed(&[0; 32])
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    let path = temp_dir().join(format!("minnehack-check-in-{}.chain", name));
+    let _ = remove_file(&path);
+    path
+}
+
+#[test]
+fn append_and_reload() {
+    let path = temp_path("append_and_reload");
+
+    let (mut storage, mut chain) = Storage::open(&path, Chain::new()).unwrap();
+    assert_eq!(chain, Chain::new());
+    for (i, s) in ["foo", "bar", "baz"].iter().enumerate() {
+        let block =
+            chain.mine_at(i as u64, str_to_arrayvec(s).unwrap(), &keypair());
+        storage.append(block).unwrap();
+    }
+    drop(storage);
+
+    let (_, loaded) = Storage::open(&path, Chain::new()).unwrap();
+    assert_eq!(loaded, chain);
+    remove_file(&path).unwrap();
+}
+
+#[test]
+fn torn_write_is_discarded() {
+    let path = temp_path("torn_write_is_discarded");
+
+    let (mut storage, mut chain) = Storage::open(&path, Chain::new()).unwrap();
+    let block = chain
+        .mine_at(1000, str_to_arrayvec("foo").unwrap(), &keypair())
+        .clone();
+    storage.append(&block).unwrap();
+    drop(storage);
+
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&[0x50, 0x00, 0x01, 0x02]).unwrap();
+    drop(file);
+
+    let (mut storage, mut loaded) = Storage::open(&path, Chain::new()).unwrap();
+    assert_eq!(loaded, chain);
+    let block =
+        loaded.mine_at(2000, str_to_arrayvec("bar").unwrap(), &keypair());
+    storage.append(block).unwrap();
+    drop(storage);
+
+    let (_, reloaded) = Storage::open(&path, Chain::new()).unwrap();
+    assert_eq!(reloaded, loaded);
+    remove_file(&path).unwrap();
+}
+
+#[test]
+fn replace() {
+    let path = temp_path("replace");
+
+    let (mut storage, mut chain) = Storage::open(&path, Chain::new()).unwrap();
+    let block =
+        chain.mine_at(1000, str_to_arrayvec("foo").unwrap(), &keypair());
+    storage.append(block).unwrap();
+
+    let mut other = Chain::new();
+    other.mine_at(1000, str_to_arrayvec("bar").unwrap(), &keypair());
+    other.mine_at(2000, str_to_arrayvec("baz").unwrap(), &keypair());
+    storage.replace(&other).unwrap();
+    drop(storage);
+
+    let (_, loaded) = Storage::open(&path, Chain::new()).unwrap();
+    assert_eq!(loaded, other);
+    remove_file(&path).unwrap();
+}
@@ -0,0 +1,381 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use blockchain::{BlockStatus, Chain, Hash};
+use cards::{hash_card, CheckIn, EventKind};
+use events::{Event, RejectReason};
+use keys::Keypair;
+use metrics::render;
+use p2p::{
+    Capabilities, Hello, IgnoreReason, MemoryNetwork, MemoryTransport,
+    Message, PeerState, Transport, PROTOCOL_VERSION,
+};
+use util::str_to_arrayvec;
+use Client;
+
+fn start(n: u8) -> Vec<Client<MemoryTransport>> {
+    let network = MemoryNetwork::new();
+    (0..n)
+        .map(|i| {
+            let addr = SocketAddr::from(([10, 0, 0, i], 10101));
+            Client::with_transport(
+                network.transport(addr),
+                Chain::new(),
+                Keypair::from_seed(&[i; 32]),
+                Duration::from_secs(60),
+                Duration::from_secs(30),
+                10,
+            )
+        })
+        .collect()
+}
+
+/// Delivers messages until the clients stop sending them, then has them ask
+/// each other for their status, a few times over.
+fn run(clients: &[Client<MemoryTransport>]) {
+    run_dropping(clients, |_| false)
+}
+
+/// Like `run`, but drops the messages for which `drop` returns true.
+fn run_dropping<F>(clients: &[Client<MemoryTransport>], drop: F)
+where
+    F: Fn(&Message) -> bool,
+{
+    for _ in 0..5 {
+        for client in clients {
+            client.request_status();
+        }
+
+        let mut quiet = false;
+        for _ in 0..1000 {
+            quiet = true;
+            for client in clients {
+                client.send_pending();
+            }
+            for client in clients {
+                while let Some((addr, msg)) = client.transport().try_recv() {
+                    if !drop(&msg) {
+                        client.handle_message(addr, msg);
+                    }
+                    quiet = false;
+                }
+            }
+            if quiet {
+                break;
+            }
+        }
+        assert!(quiet, "Clients never stopped sending messages");
+    }
+}
+
+fn assert_converged(clients: &[Client<MemoryTransport>]) -> Chain {
+    let chain = clients[0].with_chain(|chain| chain.clone());
+    for client in clients {
+        client.with_chain(|c| assert_eq!(c, &chain));
+    }
+    assert!(chain.is_valid());
+    chain
+}
+
+#[test]
+fn clients_converge() {
+    let clients = start(4);
+    clients[0].transport().send_discovery().unwrap();
+    clients[3].transport().send_discovery().unwrap();
+    run(&clients);
+
+    clients[0].mine(str_to_arrayvec("foo").unwrap());
+    clients[2].mine(str_to_arrayvec("bar").unwrap());
+    run(&clients);
+    clients[0].mine(str_to_arrayvec("baz").unwrap());
+    run(&clients);
+
+    let chain = assert_converged(&clients);
+    assert_eq!(chain.len(), 4);
+}
+
+#[test]
+fn concurrent_mining_converges() {
+    let clients = start(3);
+    for client in &clients {
+        client.transport().send_discovery().unwrap();
+    }
+    run(&clients);
+
+    // Each client mines a block without hearing about the others, so they
+    // all fork from each other.
+    for (i, client) in clients.iter().enumerate() {
+        client.mine(str_to_arrayvec(&format!("block {}", i)).unwrap());
+        client.mine(str_to_arrayvec(&format!("block {}'", i)).unwrap());
+    }
+    for client in &clients {
+        while client.transport().try_recv().is_some() {}
+    }
+    run(&clients);
+
+    let chain = assert_converged(&clients);
+    assert_eq!(chain.len(), 7);
+}
+
+#[test]
+fn new_client_catches_up() {
+    let clients = start(2);
+    for i in 0..200 {
+        clients[0].mine(str_to_arrayvec(&format!("block {}", i)).unwrap());
+    }
+    while clients[1].transport().try_recv().is_some() {}
+
+    clients[1].transport().send_discovery().unwrap();
+    run(&clients);
+
+    let chain = assert_converged(&clients);
+    assert_eq!(chain.len(), 201);
+}
+
+#[test]
+fn peers_exchange_hellos() {
+    let clients = start(2);
+    clients[0].transport().send_discovery().unwrap();
+    run(&clients);
+
+    for (client, other) in clients.iter().zip(clients.iter().rev()) {
+        let addr = other.transport().addr();
+        client.with_peers(|peers| {
+            let hello = peers[&addr].hello.expect("No hello from peer");
+            assert_eq!(hello.node_id, other.public_key());
+            assert_eq!(peers[&addr].capabilities(), Capabilities::SUPPORTED);
+            assert!(peers[&addr].same_blockchain());
+        });
+    }
+}
+
+#[test]
+fn incompatible_peers_are_ignored() {
+    let clients = start(1);
+    let genesis_hash = Chain::new().genesis().hash;
+    let hello = Hello {
+        version: PROTOCOL_VERSION,
+        node_id: Keypair::from_seed(&[1; 32]).public(),
+        genesis_hash,
+        capabilities: Capabilities::SUPPORTED,
+    };
+    let cases = vec![
+        (
+            Hello { version: 0, ..hello },
+            IgnoreReason::UnsupportedVersion(0),
+        ),
+        (
+            Hello {
+                node_id: clients[0].public_key(),
+                ..hello
+            },
+            IgnoreReason::Ourselves,
+        ),
+        (
+            Hello {
+                genesis_hash: Hash([1; 32]),
+                ..hello
+            },
+            IgnoreReason::OtherBlockchain,
+        ),
+    ];
+
+    for (i, (hello, reason)) in cases.into_iter().enumerate() {
+        let addr = SocketAddr::from(([10, 0, 1, i as u8], 10101));
+        clients[0].handle_message(addr, Message::Hello(hello));
+        // A status response consistent with the hello doesn't change that.
+        let gh = hello.genesis_hash;
+        let msg = Message::StatusResponse(gh, 0, gh);
+        clients[0].handle_message(addr, msg);
+        clients[0].with_peers(|peers| {
+            assert_eq!(peers[&addr].state, PeerState::Ignore(reason));
+        });
+    }
+}
+
+#[test]
+fn syncs_with_peers_without_a_handshake() {
+    let clients = start(3);
+    for client in &clients {
+        client.transport().send_discovery().unwrap();
+    }
+    run_dropping(&clients, is_hello);
+
+    for (i, client) in clients.iter().enumerate() {
+        client.mine(str_to_arrayvec(&format!("block {}", i)).unwrap());
+    }
+    for client in &clients {
+        while client.transport().try_recv().is_some() {}
+    }
+    run_dropping(&clients, is_hello);
+
+    let chain = assert_converged(&clients);
+    assert_eq!(chain.len(), 4);
+    for client in &clients {
+        client.with_peers(|peers| {
+            assert!(peers.values().all(|peer| peer.hello.is_none()));
+        });
+    }
+}
+
+#[test]
+fn subscribers_see_events() {
+    let clients = start(2);
+    let events = clients[1].subscribe();
+    let addr = clients[0].transport().addr();
+    clients[0].transport().send_discovery().unwrap();
+    run(&clients);
+
+    let block = clients[0].mine(str_to_arrayvec("foo").unwrap()).unwrap();
+    run(&clients);
+    let events = events.try_iter().collect::<Vec<_>>();
+    assert_eq!(events[0], Event::PeerDiscovered(addr));
+    assert!(events.contains(&Event::PeerStateChanged {
+        addr,
+        old: PeerState::Existent,
+        new: PeerState::Confirmed(0, Chain::new().genesis().hash),
+    }));
+    assert!(events.contains(&Event::BlockAppended(block.clone())));
+
+    let mut forged = block;
+    forged.data = str_to_arrayvec("bar").unwrap();
+    let events = clients[1].subscribe();
+    clients[1].handle_message(addr, Message::BlockAnnounce(forged.clone()));
+    assert_eq!(
+        events.try_recv(),
+        Ok(Event::BlockRejected {
+            addr,
+            block: forged,
+            reason: RejectReason::Invalid,
+        })
+    );
+}
+
+#[test]
+fn subscribers_see_karma_exceeded() {
+    let mut clients = start(1);
+    clients[0].max_karma = 0;
+    let events = clients[0].subscribe();
+    let addr = SocketAddr::from(([10, 0, 1, 0], 10101));
+    clients[0].add_peer(addr);
+    clients[0].send_pending();
+    clients[0].handle_message(addr, Message::Pong);
+    clients[0].send_pending();
+
+    let (speculative, existent) = (PeerState::Speculative, PeerState::Existent);
+    assert_eq!(
+        events.try_iter().collect::<Vec<_>>(),
+        vec![
+            Event::PeerDiscovered(addr),
+            Event::KarmaExceeded(addr),
+            Event::PeerStateChanged {
+                addr,
+                old: speculative,
+                new: existent,
+            },
+            Event::KarmaExceeded(addr),
+            Event::PeerStateChanged {
+                addr,
+                old: existent,
+                new: speculative,
+            },
+        ]
+    );
+}
+
+#[test]
+fn cards_need_a_secret() {
+    let mut clients = start(1);
+    assert!(clients[0].card_attendee(&["1234567"]).is_err());
+
+    clients[0].card_secret = Some("secret".to_string());
+    let attendee = clients[0].card_attendee(&["1234567"]).unwrap();
+    assert_eq!(attendee, hash_card("secret", &["1234567"]));
+}
+
+#[test]
+fn mining_the_same_data_again() {
+    let clients = start(1);
+    let data = str_to_arrayvec("foo").unwrap();
+    let block = clients[0].mine_at(1000, data.clone()).unwrap();
+    assert_eq!(clients[0].mine_at(1000, data.clone()), Some(block));
+    assert_eq!(clients[0].metrics().blocks_mined.get(), 1);
+    assert!(clients[0].mine_at(1001, data).is_some());
+    assert_eq!(clients[0].with_chain(|chain| chain.len()), 3);
+}
+
+#[test]
+fn metrics_count_activity() {
+    let clients = start(2);
+    clients[0].transport().send_discovery().unwrap();
+    run(&clients);
+    clients[0].mine(str_to_arrayvec("foo").unwrap());
+    run(&clients);
+
+    let metrics = clients[1].metrics();
+    assert_eq!(metrics.blocks_mined.get(), 0);
+    assert_eq!(metrics.block_received_count(BlockStatus::ValidTip), 1);
+    assert!(metrics.messages_received[Message::Ping.kind()].get() > 0);
+
+    let rendered = render(&clients[0]);
+    let lines = rendered.lines().collect::<Vec<_>>();
+    for line in &[
+        "# TYPE minnehack_check_in_chain_length gauge",
+        "minnehack_check_in_chain_length 2",
+        "minnehack_check_in_blocks_mined_total 1",
+        "minnehack_check_in_peers{state=\"confirmed\"} 1",
+        "minnehack_check_in_send_queue_length 0",
+        "minnehack_check_in_send_errors_total 0",
+    ] {
+        assert!(lines.contains(line), "No {:?} in {}", line, rendered);
+    }
+}
+
+#[test]
+fn peers_see_presence() {
+    let clients = start(2);
+    clients[0].transport().send_discovery().unwrap();
+    run(&clients);
+    let check_in = CheckIn::new(
+        "attendee".to_string(),
+        "station".to_string(),
+        EventKind::Arrival,
+    );
+    clients[0].mine(check_in.to_data().unwrap());
+    run(&clients);
+
+    for client in &clients {
+        client.with_presence(|presence| {
+            assert!(presence.get("attendee").unwrap().is_here());
+            assert_eq!(presence.len(), 2);
+        });
+    }
+}
+
+#[test]
+fn run_with_returns_after_shutdown() {
+    let clients = start(2);
+    let addr = clients[1].transport().addr();
+    let shutdown = clients[0].shutdown_handle();
+    clients[0].run_with(move |scope, send_queue| {
+        scope.spawn(move || {
+            send_queue.push((Some(addr), Message::PeerRequest));
+            shutdown.set();
+        });
+    });
+
+    // Whatever was queued before the shutdown is still sent.
+    assert!(clients[0].is_shutting_down());
+    let mut sent = Vec::new();
+    while let Some((_, msg)) = clients[1].transport().try_recv() {
+        sent.push(msg);
+    }
+    assert!(sent.contains(&Message::PeerRequest), "Sent {:?}", sent);
+}
+
+fn is_hello(msg: &Message) -> bool {
+    match *msg {
+        Message::Hello(_) => true,
+        _ => false,
+    }
+}
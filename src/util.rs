@@ -1,10 +1,15 @@
 //! Utility functions.
 
+use std::collections::VecDeque;
 use std::io::{Error as IoError, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 use arrayvec::{Array, ArrayVec};
 use byteorder::{ByteOrder, LE};
 use error_chain::ChainedError;
+use futures::task::{self, Task};
+use futures::{Async, Poll, Stream};
 
 use errors::Error;
 
@@ -19,6 +24,149 @@ pub fn log_err<E: Into<Error>>(r: Result<(), E>) -> bool {
     }
 }
 
+/// A blocking FIFO queue, usable from many threads at once. Items can also be
+/// taken from it asynchronously, as a `Stream`.
+///
+/// This replaces `crossbeam::sync::MsQueue`, which relies on
+/// `mem::uninitialized` and aborts for most types on current compilers.
+#[derive(Debug, Default)]
+pub struct Queue<T> {
+    items: Mutex<VecDeque<T>>,
+    ready: Condvar,
+    task: Mutex<Option<Task>>,
+}
+
+impl<T> Queue<T> {
+    /// Creates an empty queue.
+    pub fn new() -> Queue<T> {
+        Queue {
+            items: Mutex::new(VecDeque::new()),
+            ready: Condvar::new(),
+            task: Mutex::new(None),
+        }
+    }
+
+    /// Returns whether the queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.items.lock().unwrap().is_empty()
+    }
+
+    /// Returns the number of items in the queue.
+    pub fn len(&self) -> usize {
+        self.items.lock().unwrap().len()
+    }
+
+    /// Removes an item from the front of the queue, blocking until there is
+    /// one.
+    pub fn pop(&self) -> T {
+        let mut items = self.items.lock().unwrap();
+        loop {
+            match items.pop_front() {
+                Some(item) => return item,
+                None => items = self.ready.wait(items).unwrap(),
+            }
+        }
+    }
+
+    /// Removes an item from the front of the queue, blocking until there is
+    /// one or the timeout passes.
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<T> {
+        let deadline = Instant::now() + timeout;
+        let mut items = self.items.lock().unwrap();
+        loop {
+            if let Some(item) = items.pop_front() {
+                return Some(item);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            items = self.ready.wait_timeout(items, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Adds an item to the back of the queue.
+    pub fn push(&self, item: T) {
+        self.items.lock().unwrap().push_back(item);
+        self.ready.notify_one();
+        if let Some(task) = self.task.lock().unwrap().take() {
+            task.notify();
+        }
+    }
+
+    /// Returns a stream of the items removed from the front of the queue.
+    /// Only one stream should be polled at a time, since only the last task
+    /// to poll one is woken when an item is added.
+    pub fn stream(&self) -> QueueStream<'_, T> {
+        QueueStream { queue: self }
+    }
+
+    /// Removes an item from the front of the queue, if there is one.
+    pub fn try_pop(&self) -> Option<T> {
+        self.items.lock().unwrap().pop_front()
+    }
+}
+
+/// A stream of the items in a `Queue`, from `Queue::stream`. It never ends.
+#[derive(Debug)]
+pub struct QueueStream<'a, T: 'a> {
+    queue: &'a Queue<T>,
+}
+
+impl<'a, T> Stream for QueueStream<'a, T> {
+    type Item = T;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<T>, ()> {
+        let mut items = self.queue.items.lock().unwrap();
+        match items.pop_front() {
+            Some(item) => Ok(Async::Ready(Some(item))),
+            None => {
+                // This is done while holding the lock on the items, so an
+                // item can't be pushed without the task being woken.
+                *self.queue.task.lock().unwrap() = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+/// A flag that is set once to ask threads to stop. Clones share the flag, so
+/// one can be handed to whatever decides when to stop, such as a signal
+/// handler.
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Shutdown {
+    /// Creates a flag that hasn't been set.
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    /// Returns whether the flag has been set.
+    pub fn is_set(&self) -> bool {
+        *(self.inner.0).lock().unwrap()
+    }
+
+    /// Sets the flag, waking any threads waiting on it.
+    pub fn set(&self) {
+        *(self.inner.0).lock().unwrap() = true;
+        (self.inner.1).notify_all();
+    }
+
+    /// Waits until the flag is set or the timeout passes, returning whether
+    /// the flag is set.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let set = (self.inner.0).lock().unwrap();
+        let (set, _) = (self.inner.1)
+            .wait_timeout_while(set, timeout, |set| !*set)
+            .unwrap();
+        *set
+    }
+}
+
 /// Converts a slice to an `ArrayVec<[u8; n]>` if possible.
 pub fn slice_to_arrayvec<A, T>(s: &[T]) -> Option<ArrayVec<A>>
 where